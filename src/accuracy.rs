@@ -0,0 +1,573 @@
+use error_chain::ChainedError;
+use serde_json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use client;
+use errors::*;
+use geo::{self, LatLon};
+use location::{self, VehicleTime};
+use prediction::{self, PredictionsList};
+use stops::{self, FlatRoute, Stop};
+use time;
+
+pub const DEFAULT_PAUSE_SECONDS: u64 = 30;
+pub const DEFAULT_INTERVAL_SECONDS: u64 = 10;
+// Vehicle locations are never polled more often than this, however often they are asked for
+const MIN_INTERVAL_SECONDS: u64 = 5;
+pub const DEFAULT_ARRIVAL_RADIUS_METERS: u64 = 30;
+
+// Predictions are reported in these ranges of minutes between when the prediction was made
+// and the arrival it predicted
+const HORIZONS: &[(u64, u64)] = &[(0, 5), (5, 10), (10, 20)];
+
+// An arrival only counts for a prediction if it comes within this long of the prediction
+// being made, so that it isn't matched to the vehicle's next trip past the stop
+const MAX_ARRIVAL_DELAY_MS: u64 = 60 * 60 * 1000;
+
+// A line of a collector's output
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    Prediction(PredictionRecord),
+    Arrival(ArrivalRecord),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PredictionRecord {
+    pub route_tag: String,
+    pub stop_tag: String,
+    pub vehicle: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub trip_tag: Option<String>,
+    // milliseconds since the Unix epoch of the predicted arrival
+    pub predicted_epoch: u64,
+    // milliseconds since the Unix epoch when the prediction was made
+    pub prediction_epoch: u64,
+}
+
+// A vehicle passing a stop, inferred from its reported positions
+#[derive(Serialize, Deserialize, Debug)]
+struct ArrivalRecord {
+    pub route_tag: String,
+    pub stop_tag: String,
+    pub vehicle: String,
+    pub epoch: u64,
+}
+
+#[derive(Debug)]
+pub struct CollectOptions {
+    // stops to record predictions for (default: every stop of the route)
+    pub stops: Vec<String>,
+    pub pause_seconds: u64,
+    // time between vehicle location requests, raised to MIN_INTERVAL_SECONDS if below it
+    pub interval_seconds: u64,
+    // how close a vehicle has to pass a stop to have arrived at it
+    pub arrival_radius_meters: f64,
+    // how many times to poll vehicle locations before stopping, or None to run forever
+    pub polls: Option<u64>,
+}
+
+// Prediction errors in seconds, positive when the vehicle arrived later than predicted
+#[derive(Serialize, Debug)]
+struct ErrorDistribution {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route_tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_tag: Option<String>,
+    // e.g. "0-5" for predictions made up to 5 minutes before the predicted arrival
+    pub horizon_minutes: String,
+    pub count: usize,
+    pub mean_seconds: f64,
+    pub mean_absolute_seconds: f64,
+    pub p10_seconds: f64,
+    pub median_seconds: f64,
+    pub p90_seconds: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct AccuracyReport {
+    pub predictions: usize,
+    // predictions that could be matched to an arrival of the vehicle at the stop
+    pub matched: usize,
+    pub by_horizon: Vec<ErrorDistribution>,
+    pub by_route: Vec<ErrorDistribution>,
+    pub by_stop: Vec<ErrorDistribution>,
+}
+
+// Print a line of JSON for each prediction for the route's stops, every `pause_seconds`, and
+// for each arrival of a vehicle at a stop inferred from the vehicle locations in between
+pub fn collect(
+    client: &client::Client,
+    agency: String,
+    route: String,
+    options: CollectOptions,
+) -> Result<()> {
+    let config = match stops::_get_stops(client, &agency, &route)?.pop() {
+        Some(config) => config,
+        None => return Err(format!("No route config for route {}", route).into()),
+    };
+    let stop_tags = match options.stops.len() {
+        0 => all_stops(&config).map(|s| s.tag.clone()).collect(),
+        _ => options.stops.clone(),
+    };
+
+    let mut tracker = ArrivalTracker::new(&config, options.arrival_radius_meters);
+    let pause = Duration::from_secs(options.pause_seconds);
+    let interval = Duration::from_secs(options.interval_seconds.max(MIN_INTERVAL_SECONDS));
+    let mut next_predictions = Instant::now();
+    let mut epoch = 0;
+    let mut polls = 0;
+
+    loop {
+        if Instant::now() >= next_predictions {
+            next_predictions = Instant::now() + pause;
+            match client.block_on(prediction::get_predictions_async(
+                client, &agency, &route, &stop_tags,
+            )) {
+                Ok(Some(predictions)) => {
+                    for record in prediction_records(predictions, time::now_epoch()) {
+                        print_record(&Record::Prediction(record));
+                    }
+                }
+                Ok(None) => (),
+                Err(e) => warn!(
+                    "Download error: {} for route={}",
+                    e.display_chain().to_string(),
+                    route
+                ),
+            }
+        }
+
+        match client.block_on(location::get_locations_async(
+            client, &agency, &route, epoch,
+        )) {
+            Ok(Some((vehicles, updated_time))) => {
+                epoch = updated_time;
+                for vehicle in vehicles {
+                    for record in tracker.update(vehicle) {
+                        print_record(&Record::Arrival(record));
+                    }
+                }
+            }
+            Ok(None) => (),
+            Err(e) => warn!(
+                "Download error: {} for route={}",
+                e.display_chain().to_string(),
+                route
+            ),
+        }
+
+        client.report_drift();
+        polls += 1;
+        if options.polls.is_some_and(|p| polls >= p) {
+            return Ok(());
+        }
+        thread::sleep(interval);
+    }
+}
+
+fn print_record(record: &Record) {
+    println!("{}", serde_json::to_string(record).unwrap());
+}
+
+fn all_stops(config: &FlatRoute) -> impl Iterator<Item = &Stop> {
+    let mut seen = HashSet::new();
+    config
+        .directions
+        .iter()
+        .flat_map(|d| d.stops.iter())
+        .filter(move |s| seen.insert(s.tag.clone()))
+}
+
+fn prediction_records(predictions: PredictionsList, now: u64) -> Vec<PredictionRecord> {
+    let mut records = Vec::new();
+    for p in predictions.predictions {
+        for d in p.directions {
+            for arrival in d.prediction {
+                records.push(PredictionRecord {
+                    route_tag: p.route_tag.clone(),
+                    stop_tag: p.stop_tag.clone(),
+                    vehicle: arrival.vehicle,
+                    trip_tag: arrival.trip_tag,
+                    predicted_epoch: arrival.epoch,
+                    prediction_epoch: now,
+                });
+            }
+        }
+    }
+    records
+}
+
+// Infers when vehicles pass stops, from the path between each vehicle's successive positions
+struct ArrivalTracker {
+    route_tag: String,
+    // stops of each direction, by direction tag
+    stops: HashMap<String, Vec<(String, LatLon)>>,
+    all_stops: Vec<(String, LatLon)>,
+    radius_meters: f64,
+    last_positions: HashMap<String, VehicleTime>,
+    // vehicles that are still within the radius of a stop they've arrived at, by vehicle and
+    // stop tag, so that a vehicle waiting at a stop only arrives once
+    at_stops: HashSet<(String, String)>,
+}
+
+impl ArrivalTracker {
+    fn new(config: &FlatRoute, radius_meters: f64) -> ArrivalTracker {
+        let located = |s: &Stop| (s.tag.clone(), LatLon::new(s.lat, s.lon));
+        ArrivalTracker {
+            route_tag: config.tag.clone(),
+            stops: config
+                .directions
+                .iter()
+                .map(|d| (d.tag.clone(), d.stops.iter().map(located).collect()))
+                .collect(),
+            all_stops: all_stops(config).map(located).collect(),
+            radius_meters,
+            last_positions: HashMap::new(),
+            at_stops: HashSet::new(),
+        }
+    }
+
+    fn update(&mut self, vehicle: VehicleTime) -> Vec<ArrivalRecord> {
+        let previous = match self.last_positions.get(&vehicle.id) {
+            // the feed repeats a vehicle's last report until it sends a new one
+            Some(p) if p.epoch >= vehicle.epoch => return Vec::new(),
+            Some(p) => p.clone(),
+            None => {
+                // a vehicle first seen at a stop may have arrived there long before
+                let position = LatLon::new(vehicle.lat, vehicle.lon);
+                let stops = self.stops.get(&vehicle.dir_tag).unwrap_or(&self.all_stops);
+                for (stop_tag, stop_position) in stops {
+                    if position.distance_to(stop_position) <= self.radius_meters {
+                        self.at_stops.insert((vehicle.id.clone(), stop_tag.clone()));
+                    }
+                }
+                self.last_positions.insert(vehicle.id.clone(), vehicle);
+                return Vec::new();
+            }
+        };
+        let from = LatLon::new(previous.lat, previous.lon);
+        let to = LatLon::new(vehicle.lat, vehicle.lon);
+        let stops = self.stops.get(&vehicle.dir_tag).unwrap_or(&self.all_stops);
+
+        let mut arrivals = Vec::new();
+        for (stop_tag, position) in stops {
+            let key = (vehicle.id.clone(), stop_tag.clone());
+            let passed = match geo::locate_on_path(&[from, to], position) {
+                Some(p) if p.offset <= self.radius_meters => p,
+                _ => {
+                    self.at_stops.remove(&key);
+                    continue;
+                }
+            };
+            if self.at_stops.contains(&key) {
+                // still waiting at the stop, unless it has since moved away from it
+                if to.distance_to(position) > self.radius_meters {
+                    self.at_stops.remove(&key);
+                }
+                continue;
+            }
+
+            // interpolate the time of closest approach between the two reports
+            let length = from.distance_to(&to);
+            let fraction = match length > 0.0 {
+                true => passed.distance_along / length,
+                false => 0.0,
+            };
+            let elapsed = (vehicle.epoch - previous.epoch) as f64;
+            arrivals.push(ArrivalRecord {
+                route_tag: self.route_tag.clone(),
+                stop_tag: stop_tag.clone(),
+                vehicle: vehicle.id.clone(),
+                epoch: previous.epoch + (fraction * elapsed) as u64,
+            });
+            if to.distance_to(position) <= self.radius_meters {
+                self.at_stops.insert(key);
+            }
+        }
+
+        self.last_positions.insert(vehicle.id.clone(), vehicle);
+        arrivals
+    }
+}
+
+// Match the predictions recorded by `collect` to the arrivals that followed them, and print
+// the distribution of prediction errors by horizon, route and stop
+pub fn report(path: Option<String>) -> Result<()> {
+    let reader: Box<dyn BufRead> = match path {
+        Some(ref p) if p != "-" => Box::new(BufReader::new(File::open(p)?)),
+        _ => Box::new(BufReader::new(io::stdin())),
+    };
+
+    let mut predictions = Vec::new();
+    let mut arrivals: HashMap<(String, String, String), Vec<u64>> = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line)? {
+            Record::Prediction(p) => predictions.push(p),
+            Record::Arrival(a) => arrivals
+                .entry((a.route_tag, a.stop_tag, a.vehicle))
+                .or_default()
+                .push(a.epoch),
+        }
+    }
+    for epochs in arrivals.values_mut() {
+        epochs.sort_unstable();
+    }
+    let errors = prediction_errors(&predictions, &arrivals);
+
+    let report = AccuracyReport {
+        predictions: predictions.len(),
+        matched: errors.len(),
+        by_horizon: distributions(&errors, |_| (None, None)),
+        by_route: distributions(&errors, |e| (Some(e.0.clone()), None)),
+        by_stop: distributions(&errors, |e| (Some(e.0.clone()), Some(e.1.clone()))),
+    };
+    println!("{}", serde_json::to_string(&report).unwrap());
+    Ok(())
+}
+
+// The (route tag, stop tag, horizon, error in seconds) of each prediction that can be matched
+// to the vehicle's first arrival at the stop after it was made, from the sorted arrival epochs
+// by route tag, stop tag and vehicle
+fn prediction_errors(
+    predictions: &[PredictionRecord],
+    arrivals: &HashMap<(String, String, String), Vec<u64>>,
+) -> Vec<(String, String, usize, f64)> {
+    let mut errors = Vec::new();
+    for p in predictions {
+        let horizon = match horizon(p) {
+            Some(h) => h,
+            None => continue,
+        };
+        let key = (p.route_tag.clone(), p.stop_tag.clone(), p.vehicle.clone());
+        let arrival = arrivals.get(&key).and_then(|epochs| {
+            epochs.iter().find(|&&e| {
+                e >= p.prediction_epoch && e - p.prediction_epoch <= MAX_ARRIVAL_DELAY_MS
+            })
+        });
+        if let Some(&arrival) = arrival {
+            let error = (arrival as f64 - p.predicted_epoch as f64) / 1000.0;
+            errors.push((p.route_tag.clone(), p.stop_tag.clone(), horizon, error));
+        }
+    }
+    errors
+}
+
+// The index in `HORIZONS` of how far ahead a prediction was made, or None if it was made
+// further ahead than any of them
+fn horizon(p: &PredictionRecord) -> Option<usize> {
+    let minutes = p.predicted_epoch.saturating_sub(p.prediction_epoch) as f64 / 60_000.0;
+    HORIZONS
+        .iter()
+        .position(|&(from, to)| minutes >= from as f64 && minutes < to as f64)
+}
+
+type Group = (Option<String>, Option<String>);
+
+fn distributions<F>(errors: &[(String, String, usize, f64)], group: F) -> Vec<ErrorDistribution>
+where
+    F: Fn(&(String, String, usize, f64)) -> Group,
+{
+    let mut groups: BTreeMap<(Group, usize), Vec<f64>> = BTreeMap::new();
+    for e in errors {
+        groups.entry((group(e), e.2)).or_default().push(e.3);
+    }
+    groups
+        .into_iter()
+        .map(|(((route_tag, stop_tag), horizon), mut values)| {
+            values.sort_by(|a, b| a.total_cmp(b));
+            let count = values.len() as f64;
+            let (from, to) = HORIZONS[horizon];
+            ErrorDistribution {
+                route_tag,
+                stop_tag,
+                horizon_minutes: format!("{}-{}", from, to),
+                count: values.len(),
+                mean_seconds: values.iter().sum::<f64>() / count,
+                mean_absolute_seconds: values.iter().map(|v| v.abs()).sum::<f64>() / count,
+                p10_seconds: percentile(&values, 10),
+                median_seconds: percentile(&values, 50),
+                p90_seconds: percentile(&values, 90),
+            }
+        })
+        .collect()
+}
+
+// Nearest-rank percentile of sorted, non-empty values
+fn percentile(sorted: &[f64], percent: usize) -> f64 {
+    let rank = (percent * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::Coordinate;
+    use stops::FlatDirection;
+
+    const START: u64 = 1792414800000;
+
+    // Stops 111m apart along a street running north
+    fn config() -> FlatRoute {
+        let stop = |tag: &str, lat: f64| Stop {
+            tag: String::from(tag),
+            title: String::new(),
+            lat: Coordinate(lat),
+            lon: Coordinate(-122.48),
+            stop_id: None,
+        };
+        FlatRoute {
+            tag: String::from("N"),
+            title: String::new(),
+            lat_min: Coordinate(37.76),
+            lat_max: Coordinate(37.762),
+            lon_min: Coordinate(-122.48),
+            lon_max: Coordinate(-122.48),
+            directions: vec![FlatDirection {
+                tag: String::from("N_in"),
+                title: String::new(),
+                name: String::new(),
+                use_for_ui: true,
+                stops: vec![stop("1", 37.76), stop("2", 37.761), stop("3", 37.762)],
+            }],
+            paths: Vec::new(),
+        }
+    }
+
+    fn vehicle(lat: f64, epoch: u64) -> VehicleTime {
+        VehicleTime {
+            id: String::from("1501"),
+            route_tag: String::from("N"),
+            dir_tag: String::from("N_in"),
+            lat: Coordinate(lat),
+            lon: Coordinate(-122.48),
+            epoch,
+            predictable: true,
+            heading: 0,
+            speed_km_hr: 0,
+            leading_vehicle_id: String::new(),
+            removed: false,
+            progress: None,
+            heading_source: None,
+            speed_source: None,
+        }
+    }
+
+    fn prediction(stop_tag: &str, predicted_epoch: u64, prediction_epoch: u64) -> PredictionRecord {
+        PredictionRecord {
+            route_tag: String::from("N"),
+            stop_tag: String::from(stop_tag),
+            vehicle: String::from("1501"),
+            trip_tag: None,
+            predicted_epoch,
+            prediction_epoch,
+        }
+    }
+
+    fn arrivals(records: &[ArrivalRecord]) -> Vec<(&str, u64)> {
+        records.iter().map(|a| (&a.stop_tag[..], a.epoch)).collect()
+    }
+
+    #[test]
+    fn infers_arrivals_between_positions() {
+        let mut tracker = ArrivalTracker::new(&config(), 30.0);
+
+        // first seen at stop 1, which it may have reached long ago
+        assert!(tracker.update(vehicle(37.76, START)).is_empty());
+        // the same report again
+        assert!(tracker.update(vehicle(37.76, START)).is_empty());
+        // passing stops 2 and 3 a third and two thirds of the way between reports 30s apart
+        let passed = tracker.update(vehicle(37.763, START + 30_000));
+        assert_eq!(
+            arrivals(&passed),
+            vec![("2", START + 10_000), ("3", START + 20_000)]
+        );
+        assert_eq!(passed[0].route_tag, "N");
+        assert_eq!(passed[0].vehicle, "1501");
+    }
+
+    #[test]
+    fn arrives_once_while_waiting_at_a_stop() {
+        let mut tracker = ArrivalTracker::new(&config(), 30.0);
+        tracker.update(vehicle(37.7605, START));
+
+        let arrived = tracker.update(vehicle(37.761, START + 20_000));
+        assert_eq!(arrivals(&arrived), vec![("2", START + 20_000)]);
+        // still there, then leaving
+        assert!(tracker.update(vehicle(37.76101, START + 40_000)).is_empty());
+        assert!(tracker.update(vehicle(37.7613, START + 60_000)).is_empty());
+        // and passing it again, e.g. on its next trip
+        tracker.update(vehicle(37.7605, START + 600_000));
+        let again = tracker.update(vehicle(37.7615, START + 620_000));
+        assert_eq!(arrivals(&again), vec![("2", START + 610_000)]);
+    }
+
+    #[test]
+    fn groups_predictions_by_how_far_ahead_they_were_made() {
+        let at = |minutes: f64| prediction("1", START + (minutes * 60_000.0) as u64, START);
+        assert_eq!(horizon(&at(0.0)), Some(0));
+        assert_eq!(horizon(&at(4.9)), Some(0));
+        assert_eq!(horizon(&at(5.0)), Some(1));
+        assert_eq!(horizon(&at(19.9)), Some(2));
+        assert_eq!(horizon(&at(20.0)), None);
+        // predicted for before it was made
+        assert_eq!(horizon(&prediction("1", START, START + 1000)), Some(0));
+    }
+
+    #[test]
+    fn takes_nearest_rank_percentiles() {
+        let values: Vec<f64> = (1..=10).map(|v| v as f64).collect();
+        assert_eq!(percentile(&values, 10), 1.0);
+        assert_eq!(percentile(&values, 50), 5.0);
+        assert_eq!(percentile(&values, 90), 9.0);
+        assert_eq!(percentile(&values, 100), 10.0);
+        assert_eq!(percentile(&values, 0), 1.0);
+        assert_eq!(percentile(&[3.0], 90), 3.0);
+    }
+
+    #[test]
+    fn matches_predictions_to_the_next_arrival() {
+        let mut arrivals = HashMap::new();
+        let key = |stop_tag: &str| {
+            (
+                String::from("N"),
+                String::from(stop_tag),
+                String::from("1501"),
+            )
+        };
+        arrivals.insert(
+            key("1"),
+            vec![START - 60_000, START + 300_000, START + 900_000],
+        );
+        arrivals.insert(key("2"), vec![START + 2 * MAX_ARRIVAL_DELAY_MS]);
+
+        let predictions = vec![
+            // made before the second arrival, which was 60s later than predicted
+            prediction("1", START + 240_000, START),
+            // made after it, so for the third arrival, 30s early
+            prediction("1", START + 930_000, START + 600_000),
+            // the only arrival is too long after the prediction to be for it
+            prediction("2", START + 60_000, START),
+            // no arrivals at the stop
+            prediction("3", START + 60_000, START),
+            // too far ahead for any horizon
+            prediction("1", START + 1_800_000, START),
+        ];
+        let errors = prediction_errors(&predictions, &arrivals);
+        assert_eq!(
+            errors,
+            vec![
+                (String::from("N"), String::from("1"), 0, 60.0),
+                (String::from("N"), String::from("1"), 1, -30.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,517 @@
+use error_chain::ChainedError;
+use serde_json;
+use std::collections::HashMap;
+use std::slice;
+use std::thread;
+use std::time::Duration;
+
+use client;
+use errors::*;
+use geo::LatLon;
+use location::{self, VehicleTime};
+use mapmatch::{self, RouteMatcher};
+use prediction::{self, PredictionsList};
+use schedule::{self, Schedule, Timezone, VehicleBlock};
+use stops::{self, FlatDirection, FlatRoute};
+use time;
+
+// A vehicle is taken to be running a trip from this long before the trip's first scheduled
+// time until this long after its last
+const TRIP_SLACK_MS: i64 = 30 * 60 * 1000;
+
+#[derive(Serialize, Debug)]
+struct VehicleAdherence {
+    pub vehicle: String,
+    pub dir_tag: String,
+    // milliseconds since the Unix epoch when the vehicle reported its position
+    pub epoch: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trip_tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_class: Option<String>,
+    // the last timepoint of the scheduled trip that the vehicle has passed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_passed_stop_tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_passed_stop_title: Option<String>,
+    // milliseconds since the Unix epoch when the vehicle was scheduled to pass that stop, and
+    // when it's estimated to have passed it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduled_epoch: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passed_epoch: Option<i64>,
+    // how late the vehicle passed that stop, negative when it was early
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minutes_late: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct AdherenceOptions {
+    pub timezone: Timezone,
+    pub pause_seconds: Option<u64>,
+}
+
+// (distance along the direction, milliseconds since the Unix epoch when it's scheduled, stop
+// tag) of a stop that a trip is scheduled to pass
+type Timepoint = (f64, i64, String);
+
+// The scheduled trip a vehicle is running
+#[derive(Debug)]
+struct TripMatch {
+    service_class: String,
+    // in order along the direction
+    timepoints: Vec<Timepoint>,
+}
+
+impl TripMatch {
+    fn start(&self) -> i64 {
+        self.timepoints.iter().map(|t| t.1).min().unwrap_or(0)
+    }
+
+    fn end(&self) -> i64 {
+        self.timepoints.iter().map(|t| t.1).max().unwrap_or(0)
+    }
+}
+
+// A vehicle's distance along its direction when it reported, and the report's time
+type Report = (f64, i64);
+
+pub fn get_adherence(
+    client: &client::Client,
+    agency: String,
+    route: String,
+    options: AdherenceOptions,
+) -> Result<()> {
+    let config = match stops::_get_stops(client, &agency, &route)?.pop() {
+        Some(config) => config,
+        None => return Err(format!("No route config for route {}", route).into()),
+    };
+    let schedule = schedule::_get_schedule(client, &agency, &route)?;
+    let matcher = RouteMatcher::new(
+        slice::from_ref(&config),
+        mapmatch::DEFAULT_OFF_ROUTE_METERS as f64,
+    );
+    let mut stop_tags: Vec<String> = Vec::new();
+    for stop in config.directions.iter().flat_map(|d| d.stops.iter()) {
+        if !stop_tags.contains(&stop.tag) {
+            stop_tags.push(stop.tag.clone());
+        }
+    }
+    // each vehicle's last report, to tell when it passed a stop between two polls
+    let mut reports: HashMap<String, (String, Report)> = HashMap::new();
+
+    loop {
+        let vehicles = client
+            .block_on(location::get_locations_async(client, &agency, &route, 0))
+            .map(|l| l.map(|(vehicles, _)| vehicles).unwrap_or_default());
+        // only predictions say which block and trip each vehicle is running
+        let predictions = client.block_on(prediction::get_predictions_async(
+            client, &agency, &route, &stop_tags,
+        ));
+
+        match (vehicles, predictions) {
+            (Ok(vehicles), Ok(predictions)) => {
+                let blocks = vehicle_blocks(&predictions);
+                let adherence: Vec<VehicleAdherence> = vehicles
+                    .into_iter()
+                    .map(|v| {
+                        let report = matcher
+                            .locate(&config.tag, &v.dir_tag, &LatLon::new(v.lat, v.lon))
+                            .and_then(|p| p.distance_along_meters)
+                            .map(|d| (d, v.epoch as i64));
+                        let previous = reports
+                            .get(&v.id)
+                            .filter(|(dir_tag, _)| *dir_tag == v.dir_tag)
+                            .map(|(_, r)| *r);
+                        if let Some(report) = report {
+                            reports.insert(v.id.clone(), (v.dir_tag.clone(), report));
+                        }
+                        let block = blocks.get(&v.id);
+                        let position = report.map(|r| (r, previous));
+                        vehicle_adherence(
+                            v,
+                            block,
+                            position,
+                            &config,
+                            &matcher,
+                            &schedule,
+                            options.timezone,
+                        )
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string(&adherence).unwrap());
+            }
+            (Err(e), _) | (_, Err(e)) => warn!(
+                "Download error: {} for route={}",
+                e.display_chain().to_string(),
+                route
+            ),
+        }
+
+        client.report_drift();
+        match options.pause_seconds {
+            None => return Ok(()),
+            Some(s) => thread::sleep(Duration::from_millis(s * 1000)),
+        }
+    }
+}
+
+// The block and trip tag of each vehicle with predictions that name its block, by vehicle id
+fn vehicle_blocks(
+    predictions: &Option<PredictionsList>,
+) -> HashMap<String, (String, Option<String>)> {
+    predictions
+        .iter()
+        .flat_map(|p| p.predictions.iter())
+        .flat_map(|p| p.directions.iter())
+        .flat_map(|d| d.prediction.iter())
+        .filter_map(|p| Some((p.vehicle.clone(), (p.block.clone()?, p.trip_tag.clone()))))
+        .collect()
+}
+
+// A vehicle's report, and its report before that, as it was along its direction
+type Position = (Report, Option<Report>);
+
+fn vehicle_adherence(
+    vehicle: VehicleTime,
+    block: Option<&(String, Option<String>)>,
+    position: Option<Position>,
+    config: &FlatRoute,
+    matcher: &RouteMatcher,
+    schedule: &Schedule,
+    timezone: Timezone,
+) -> VehicleAdherence {
+    let direction = config.directions.iter().find(|d| d.tag == vehicle.dir_tag);
+    let distances = matcher.stop_distances(&config.tag, &vehicle.dir_tag);
+    let trip = match (block, direction, distances) {
+        (Some((block_id, _)), Some(direction), Some(distances)) => match_trip(
+            vehicle.epoch as i64,
+            block_id,
+            direction,
+            distances,
+            schedule,
+            timezone,
+        ),
+        _ => None,
+    };
+    let passed = match (&trip, position) {
+        (Some(trip), Some((report, previous))) => {
+            passed_timepoint(&trip.timepoints, report, previous, vehicle.speed_km_hr)
+        }
+        _ => None,
+    };
+    let last_passed_stop_title = passed.and_then(|(last, _)| {
+        direction
+            .and_then(|d| d.stops.iter().find(|s| s.tag == last.2))
+            .map(|s| s.title.clone())
+    });
+
+    VehicleAdherence {
+        block_id: block.map(|b| b.0.clone()),
+        trip_tag: block.and_then(|b| b.1.clone()),
+        service_class: trip.as_ref().map(|t| t.service_class.clone()),
+        last_passed_stop_tag: passed.map(|(last, _)| last.2.clone()),
+        last_passed_stop_title,
+        scheduled_epoch: passed.map(|(last, _)| last.1),
+        passed_epoch: passed.map(|(_, passed)| passed),
+        minutes_late: passed.map(|(last, passed)| (passed - last.1) as f64 / 60_000.0),
+        vehicle: vehicle.id,
+        dir_tag: vehicle.dir_tag,
+        epoch: vehicle.epoch,
+    }
+}
+
+// Of the trips of a block in the vehicle's direction scheduled today, or yesterday for
+// service past midnight, the one running at the vehicle's report time: the latest in
+// scheduled order to have started, give or take TRIP_SLACK_MS, that hasn't long ended
+fn match_trip(
+    epoch: i64,
+    block_id: &str,
+    direction: &FlatDirection,
+    distances: &[f64],
+    schedule: &Schedule,
+    timezone: Timezone,
+) -> Option<TripMatch> {
+    // schedule tables are named by the directions' names, e.g. Inbound
+    let same_direction = schedule
+        .routes
+        .iter()
+        .any(|t| t.direction == direction.name);
+    let tables = schedule
+        .routes
+        .iter()
+        .filter(|t| !same_direction || t.direction == direction.name);
+    let service_dates = [
+        timezone.date(epoch - time::MS_PER_DAY),
+        timezone.date(epoch),
+    ];
+
+    let mut trips: Vec<TripMatch> = Vec::new();
+    for table in tables {
+        for &date in &service_dates {
+            if !schedule::runs_on(schedule, table, date) {
+                continue;
+            }
+            for trip in table.blocks.iter().filter(|b| b.block_id == block_id) {
+                let timepoints: Option<Vec<Timepoint>> =
+                    trip_timepoints(trip, direction, distances)
+                        .into_iter()
+                        .map(|(distance, time, tag)| {
+                            Some((distance, timezone.scheduled_epoch(date, time)?, tag))
+                        })
+                        .collect();
+                match timepoints {
+                    Some(timepoints) if !timepoints.is_empty() => trips.push(TripMatch {
+                        service_class: table.service_class.clone(),
+                        timepoints,
+                    }),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    trips.sort_by_key(|t| t.start());
+    let running: Vec<TripMatch> = trips
+        .into_iter()
+        .filter(|t| t.start() - TRIP_SLACK_MS <= epoch && epoch <= t.end() + TRIP_SLACK_MS)
+        .collect();
+    let started = running
+        .iter()
+        .rposition(|t| t.start() <= epoch)
+        .unwrap_or(0);
+    running.into_iter().nth(started)
+}
+
+// (distance along the direction, scheduled time, stop tag) of each stop a trip is scheduled
+// to pass, in order along the direction, where `distances` are those of the direction's stops
+fn trip_timepoints(
+    trip: &VehicleBlock,
+    direction: &FlatDirection,
+    distances: &[f64],
+) -> Vec<Timepoint> {
+    let mut timepoints: Vec<Timepoint> = trip
+        .stops
+        .iter()
+        .filter_map(|s| {
+            // skipped stops have no time
+            let time = s.epoch_time?;
+            let index = direction.stops.iter().position(|d| d.tag == s.tag)?;
+            Some((*distances.get(index)?, time, s.tag.clone()))
+        })
+        .collect();
+    timepoints.sort_by(|a, b| a.0.total_cmp(&b.0));
+    timepoints
+}
+
+// The last timepoint at or before a distance along the direction, and the scheduled time at
+// that distance, interpolated between it and the next timepoint
+fn scheduled_at(timepoints: &[Timepoint], distance: f64) -> Option<(&Timepoint, i64)> {
+    let passed = timepoints.iter().rposition(|t| t.0 <= distance)?;
+    let last = &timepoints[passed];
+    let scheduled = match timepoints.get(passed + 1) {
+        Some(next) if next.0 > last.0 => {
+            let fraction = (distance - last.0) / (next.0 - last.0);
+            last.1 + (fraction * (next.1 - last.1) as f64) as i64
+        }
+        _ => last.1,
+    };
+    Some((last, scheduled))
+}
+
+// The last timepoint the vehicle has passed, and when it passed it: between its previous
+// report and this one if it passed it in between, or else going back from this report at
+// its reported speed, or at the scheduled pace if it isn't moving
+fn passed_timepoint(
+    timepoints: &[Timepoint],
+    report: Report,
+    previous: Option<Report>,
+    speed_km_hr: u32,
+) -> Option<(&Timepoint, i64)> {
+    let (distance, epoch) = report;
+    let (last, scheduled_here) = scheduled_at(timepoints, distance)?;
+    let passed = match previous {
+        Some((from, from_epoch)) if from <= last.0 && from < distance && from_epoch < epoch => {
+            let fraction = (last.0 - from) / (distance - from);
+            from_epoch + (fraction * (epoch - from_epoch) as f64) as i64
+        }
+        _ if speed_km_hr > 0 => {
+            let meters_per_ms = speed_km_hr as f64 / 3_600.0;
+            epoch - ((distance - last.0) / meters_per_ms) as i64
+        }
+        _ => epoch - (scheduled_here - last.1),
+    };
+    Some((last, passed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use chrono_tz;
+    use geo::Coordinate;
+    use serde_xml_rs::deserialize;
+    use stops::Stop;
+
+    const UTC: Timezone = Timezone::Named(chrono_tz::UTC);
+    const MINUTE: i64 = 60_000;
+
+    // Milliseconds since the Unix epoch at a time of a day in October 2026, when the 19th
+    // and 20th are weekdays
+    fn at(day: u32, hours: i64, minutes: i64) -> i64 {
+        let date = NaiveDate::from_ymd_opt(2026, 10, day).unwrap();
+        UTC.scheduled_epoch(date, (hours * 60 + minutes) * MINUTE)
+            .unwrap()
+    }
+
+    // A direction through the given stops, which are DISTANCES along it
+    fn direction(tag: &str, name: &str, tags: &[&str]) -> FlatDirection {
+        FlatDirection {
+            tag: String::from(tag),
+            title: String::new(),
+            name: String::from(name),
+            use_for_ui: true,
+            stops: tags
+                .iter()
+                .map(|tag| Stop {
+                    tag: String::from(*tag),
+                    title: tag.to_uppercase(),
+                    lat: Coordinate(0.0),
+                    lon: Coordinate(0.0),
+                    stop_id: None,
+                })
+                .collect(),
+        }
+    }
+
+    const DISTANCES: [f64; 3] = [0.0, 1000.0, 3000.0];
+
+    // Block 1 runs inbound at 06:00 and 07:00, outbound at 06:40, and inbound again past
+    // midnight, skipping b
+    fn schedule() -> Schedule {
+        deserialize(
+            &br#"<body>
+            <route tag="1" title="1" scheduleClass="x" serviceClass="wkd" direction="Inbound">
+              <header><stop tag="a">A</stop><stop tag="b">B</stop><stop tag="c">C</stop></header>
+              <tr blockID="1">
+                <stop tag="a" epochTime="21600000">06:00:00</stop>
+                <stop tag="b" epochTime="22200000">06:10:00</stop>
+                <stop tag="c" epochTime="23400000">06:30:00</stop>
+              </tr>
+              <tr blockID="1">
+                <stop tag="a" epochTime="25200000">07:00:00</stop>
+                <stop tag="b" epochTime="25800000">07:10:00</stop>
+                <stop tag="c" epochTime="27000000">07:30:00</stop>
+              </tr>
+              <tr blockID="1">
+                <stop tag="a" epochTime="89400000">24:50:00</stop>
+                <stop tag="b" epochTime="-1">--</stop>
+                <stop tag="c" epochTime="91200000">25:20:00</stop>
+              </tr>
+            </route>
+            <route tag="1" title="1" scheduleClass="x" serviceClass="wkd" direction="Outbound">
+              <header><stop tag="c">C</stop><stop tag="a">A</stop></header>
+              <tr blockID="1">
+                <stop tag="c" epochTime="24000000">06:40:00</stop>
+                <stop tag="a" epochTime="25800000">07:10:00</stop>
+              </tr>
+            </route>
+            </body>"#[..],
+        )
+        .unwrap()
+    }
+
+    fn matched(epoch: i64, direction: &FlatDirection) -> Option<i64> {
+        match_trip(epoch, "1", direction, &DISTANCES, &schedule(), UTC).map(|t| t.start())
+    }
+
+    #[test]
+    fn matches_the_trip_running_in_scheduled_order() {
+        let inbound = direction("1_in", "Inbound", &["a", "b", "c"]);
+        assert_eq!(matched(at(19, 6, 20), &inbound), Some(at(19, 6, 0)));
+        // both trips could be running, and the first has started
+        assert_eq!(matched(at(19, 6, 45), &inbound), Some(at(19, 6, 0)));
+        // late on the first trip, since the next hasn't started
+        assert_eq!(matched(at(19, 6, 59), &inbound), Some(at(19, 6, 0)));
+        // the first trip has long ended
+        assert_eq!(matched(at(19, 7, 5), &inbound), Some(at(19, 7, 0)));
+        assert_eq!(matched(at(19, 9, 0), &inbound), None);
+        // service past midnight is on the day before's schedule
+        assert_eq!(matched(at(20, 1, 0), &inbound), Some(at(19, 24, 50)));
+
+        let outbound = direction("1_out", "Outbound", &["c", "b", "a"]);
+        assert_eq!(matched(at(19, 6, 45), &outbound), Some(at(19, 6, 40)));
+        assert!(match_trip(at(19, 6, 20), "2", &inbound, &DISTANCES, &schedule(), UTC).is_none());
+    }
+
+    #[test]
+    fn orders_a_trips_timepoints_along_the_direction() {
+        let inbound = direction("1_in", "Inbound", &["a", "b", "c"]);
+        let schedule = schedule();
+        let timepoints = trip_timepoints(&schedule.routes[0].blocks[2], &inbound, &DISTANCES);
+        // b is skipped
+        assert_eq!(
+            timepoints,
+            vec![
+                (0.0, 89400000, String::from("a")),
+                (3000.0, 91200000, String::from("c")),
+            ]
+        );
+
+        // outbound stops, listed against the outbound direction
+        let outbound = direction("1_out", "Outbound", &["c", "b", "a"]);
+        let timepoints = trip_timepoints(&schedule.routes[1].blocks[0], &outbound, &DISTANCES);
+        let tags: Vec<&str> = timepoints.iter().map(|t| &t.2[..]).collect();
+        assert_eq!(tags, vec!["c", "a"]);
+        assert_eq!(timepoints[1].0, 3000.0);
+
+        // stops the direction doesn't have are left out
+        let short = direction("1_short", "Inbound", &["a", "b"]);
+        let timepoints = trip_timepoints(&schedule.routes[0].blocks[0], &short, &DISTANCES);
+        assert_eq!(timepoints.len(), 2);
+    }
+
+    fn timepoints() -> Vec<Timepoint> {
+        vec![
+            (0.0, 0, String::from("a")),
+            (1000.0, 10 * MINUTE, String::from("b")),
+            (3000.0, 30 * MINUTE, String::from("c")),
+        ]
+    }
+
+    #[test]
+    fn interpolates_the_scheduled_time_between_timepoints() {
+        let timepoints = timepoints();
+        let (last, scheduled) = scheduled_at(&timepoints, 2000.0).unwrap();
+        assert_eq!(last.2, "b");
+        assert_eq!(scheduled, 20 * MINUTE);
+        assert_eq!(scheduled_at(&timepoints, 1000.0).unwrap().1, 10 * MINUTE);
+        // past the last timepoint
+        let (last, scheduled) = scheduled_at(&timepoints, 3500.0).unwrap();
+        assert_eq!((&last.2[..], scheduled), ("c", 30 * MINUTE));
+        assert!(scheduled_at(&timepoints, -10.0).is_none());
+    }
+
+    #[test]
+    fn estimates_when_the_last_timepoint_was_passed() {
+        let timepoints = timepoints();
+        let report = (1500.0, 20 * MINUTE);
+
+        // passed b halfway between the reports
+        let (last, passed) =
+            passed_timepoint(&timepoints, report, Some((500.0, 16 * MINUTE)), 0).unwrap();
+        assert_eq!(last.2, "b");
+        assert_eq!(passed, 18 * MINUTE);
+
+        // 500m back at 30 km/h, a minute ago, since the last report was already past b
+        let (_, passed) =
+            passed_timepoint(&timepoints, report, Some((1200.0, 19 * MINUTE)), 30).unwrap();
+        assert_eq!(passed, 19 * MINUTE);
+
+        // stationary, at the scheduled pace of 5 minutes for 500m
+        let (_, passed) = passed_timepoint(&timepoints, report, None, 0).unwrap();
+        assert_eq!(passed, 15 * MINUTE);
+    }
+}
@@ -0,0 +1,220 @@
+use serde_json::{self, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::Path;
+use xml::reader::{EventReader, XmlEvent};
+
+use http;
+
+// The responses to serve, from a directory of responses recorded with nextbus's
+// `--record-dir`. Vehicles are simulated on every route whose config was recorded, and route
+// lists, schedules and messages are served as they were recorded. The first response recorded
+// for a request is served, and a request that wasn't recorded gets one made up from the route
+// configs, or an error.
+#[derive(Debug)]
+pub struct Fixtures {
+    // response bodies by command, agency and route, which are empty when not given
+    responses: HashMap<(String, String, String), String>,
+    pub agencies: BTreeMap<String, Agency>,
+}
+
+#[derive(Debug)]
+pub struct Agency {
+    pub tag: String,
+    // by route tag
+    pub routes: BTreeMap<String, Route>,
+}
+
+#[derive(Debug)]
+pub struct Route {
+    pub tag: String,
+    pub title: String,
+    // the <route> element of the route config, as it's served
+    pub config: String,
+    pub stops: HashMap<String, Stop>,
+    pub directions: Vec<Direction>,
+    // the pieces of the route's path, in no particular order
+    pub paths: Vec<Vec<Point>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stop {
+    pub tag: String,
+    pub title: String,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Stop {
+    pub fn point(&self) -> Point {
+        Point {
+            lat: self.lat,
+            lon: self.lon,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Direction {
+    pub tag: String,
+    pub title: String,
+    pub stop_tags: Vec<String>,
+}
+
+impl Fixtures {
+    pub fn load(dir: &Path) -> io::Result<Fixtures> {
+        let mut recorded: Vec<_> = fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<_>>()?;
+        // responses to the same request are numbered in the order they were recorded
+        recorded.sort();
+
+        let mut responses = HashMap::new();
+        let mut agencies = BTreeMap::new();
+        for path in recorded {
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            // the URL and status of the response, whose body is saved next to it
+            let meta: Value =
+                serde_json::from_str(&fs::read_to_string(&path)?).map_err(|e| invalid(&path, e))?;
+            if meta["status"].as_u64() != Some(200) {
+                continue;
+            }
+            let request = http::parse_target(meta["url"].as_str().unwrap_or(""));
+            let param = |name: &str| String::from(request.param(name).unwrap_or(""));
+            let (command, agency, route) = (param("command"), param("a"), param("r"));
+            let body = fs::read_to_string(path.with_extension("xml"))?;
+
+            if !agency.is_empty() {
+                agencies.entry(agency.clone()).or_insert_with(|| Agency {
+                    tag: agency.clone(),
+                    routes: BTreeMap::new(),
+                });
+            }
+            match (&command[..], agencies.get_mut(&agency)) {
+                // an error for an unknown route has no route in it
+                ("routeConfig", Some(a)) if !route.is_empty() => {
+                    if let Some(route) = load_route(&path, &body)? {
+                        a.routes.entry(route.tag.clone()).or_insert(route);
+                    }
+                }
+                _ => {
+                    responses.entry((command, agency, route)).or_insert(body);
+                }
+            }
+        }
+        Ok(Fixtures {
+            responses,
+            agencies,
+        })
+    }
+
+    // The recorded response to a command, if there is one
+    pub fn response(&self, command: &str, agency: &str, route: Option<&str>) -> Option<&str> {
+        let key = (
+            String::from(command),
+            String::from(agency),
+            String::from(route.unwrap_or("")),
+        );
+        self.responses.get(&key).map(|body| &body[..])
+    }
+}
+
+// The route in a recorded route config, or None if it has none
+fn load_route(path: &Path, xml: &str) -> io::Result<Option<Route>> {
+    let mut route = Route {
+        tag: String::new(),
+        title: String::new(),
+        config: String::from(inner_body(xml)),
+        stops: HashMap::new(),
+        directions: Vec::new(),
+        paths: Vec::new(),
+    };
+
+    // stops directly inside a route have coordinates, and stops inside a direction only
+    // name them by tag. Points are only found inside paths.
+    let mut direction: Option<Direction> = None;
+    for event in EventReader::new(xml.as_bytes()).into_iter() {
+        match event.map_err(|e| invalid(path, e))? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                let attribute = |key: &str| {
+                    attributes
+                        .iter()
+                        .find(|a| a.name.local_name == key)
+                        .map(|a| a.value.clone())
+                        .unwrap_or_default()
+                };
+                match (&name.local_name[..], direction.as_mut()) {
+                    ("route", _) => {
+                        route.tag = attribute("tag");
+                        route.title = attribute("title");
+                    }
+                    ("direction", _) => {
+                        direction = Some(Direction {
+                            tag: attribute("tag"),
+                            title: attribute("title"),
+                            stop_tags: Vec::new(),
+                        })
+                    }
+                    ("stop", Some(d)) => d.stop_tags.push(attribute("tag")),
+                    ("stop", None) => {
+                        let stop = Stop {
+                            tag: attribute("tag"),
+                            title: attribute("title"),
+                            lat: attribute("lat").parse().map_err(|e| invalid(path, e))?,
+                            lon: attribute("lon").parse().map_err(|e| invalid(path, e))?,
+                        };
+                        route.stops.insert(stop.tag.clone(), stop);
+                    }
+                    ("path", _) => route.paths.push(Vec::new()),
+                    ("point", _) => {
+                        let point = Point {
+                            lat: attribute("lat").parse().map_err(|e| invalid(path, e))?,
+                            lon: attribute("lon").parse().map_err(|e| invalid(path, e))?,
+                        };
+                        if let Some(points) = route.paths.last_mut() {
+                            points.push(point);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            XmlEvent::EndElement { name } if name.local_name == "direction" => {
+                route.directions.extend(direction.take());
+            }
+            _ => (),
+        }
+    }
+    Ok(match route.tag.is_empty() {
+        true => None,
+        false => Some(route),
+    })
+}
+
+fn invalid<E: Display>(path: &Path, e: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}
+
+// The elements inside a response's <body>, so that several can be served in one body
+pub fn inner_body(xml: &str) -> &str {
+    let start = xml
+        .find("<body")
+        .and_then(|i| xml[i..].find('>').map(|j| i + j + 1))
+        .unwrap_or(0);
+    let end = xml.rfind("</body>").unwrap_or(xml.len());
+    xml[start..end.max(start)].trim()
+}
@@ -0,0 +1,160 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str;
+use std::sync::Arc;
+use std::thread;
+
+// Just enough HTTP/1.1 to answer the GET requests that nextbus makes, one connection per
+// request
+#[derive(Debug)]
+pub struct Request {
+    pub path: String,
+    // every name and value in the query string, decoded, in order
+    pub query: Vec<(String, String)>,
+}
+
+impl Request {
+    // The first value of a query parameter
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| &v[..])
+    }
+
+    // Every value of a query parameter, e.g. each stop of predictionsForMultiStops
+    pub fn params(&self, name: &str) -> Vec<&str> {
+        self.query
+            .iter()
+            .filter(|(k, _)| k == name)
+            .map(|(_, v)| &v[..])
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    pub fn xml(body: String) -> Response {
+        Response { status: 200, body }
+    }
+}
+
+pub type Handler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+pub fn serve(listener: TcpListener, handler: Handler) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let handler = handler.clone();
+        thread::spawn(move || {
+            if let Err(e) = answer(stream, &handler) {
+                warn!("Connection error: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn answer(stream: TcpStream, handler: &Handler) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers don't matter, and GET requests have no body
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let response = match parse_request_line(&request_line) {
+        Some(request) => {
+            info!("{} {:?}", request.path, request.query);
+            handler(&request)
+        }
+        None => Response {
+            status: 400,
+            body: String::new(),
+        },
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/xml; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.body.len()
+    )?;
+    stream.write_all(response.body.as_bytes())?;
+    stream.flush()
+}
+
+// "GET /service/publicXMLFeed?command=routeList&a=sf-muni HTTP/1.1"
+fn parse_request_line(line: &str) -> Option<Request> {
+    let mut parts = line.split_whitespace();
+    if parts.next()? != "GET" {
+        return None;
+    }
+    Some(parse_target(parts.next()?))
+}
+
+// "/service/publicXMLFeed?command=routeList&a=sf-muni", or a whole URL
+pub fn parse_target(target: &str) -> Request {
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, ""),
+    };
+    Request {
+        path: String::from(path),
+        query: query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| match p.find('=') {
+                Some(i) => (decode(&p[..i]), decode(&p[i + 1..])),
+                None => (decode(p), String::new()),
+            })
+            .collect(),
+    }
+}
+
+// Undo percent-encoding, and '+' for a space
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
@@ -0,0 +1,467 @@
+// A fake publicXMLFeed for testing nextbus without the real feed: it serves route configs,
+// schedules and messages recorded with nextbus's --record-dir, simulates vehicles driving along the routes for
+// vehicle locations and predictions, and can be made to fail like the real feed does.
+extern crate clap;
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+extern crate serde_json;
+extern crate xml;
+
+mod fixtures;
+mod http;
+mod simulation;
+
+use clap::{App, Arg, ArgMatches};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fixtures::Fixtures;
+use http::{Request, Response};
+use simulation::{Simulation, SimulationOptions};
+
+const DEFAULT_PORT: u64 = 8080;
+const DEFAULT_VEHICLES_PER_DIRECTION: u64 = 2;
+const DEFAULT_SPEED_KM_HR: u64 = 20;
+const DEFAULT_REPORT_INTERVAL_SECONDS: u64 = 10;
+const DEFAULT_SLOW_SECONDS: u64 = 5;
+// how many predictions to give for each direction of a stop, like the real feed
+const PREDICTIONS_PER_DIRECTION: usize = 5;
+
+// How often to fail, in percent of requests, and how
+#[derive(Debug, Default)]
+struct Faults {
+    // answer with an <Error shouldRetry="true"> body, as the feed does when it's overloaded
+    feed_error_percent: u64,
+    // answer with a 503
+    server_error_percent: u64,
+    // answer with an empty body
+    empty_percent: u64,
+    // wait slow_seconds before answering
+    slow_percent: u64,
+    slow_seconds: u64,
+}
+
+// A small xorshift generator, so faults can be repeated with --seed without another dependency
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    // Whether an event that happens `percent` of the time happens this time
+    fn chance(&mut self, percent: u64) -> bool {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        percent > 0 && self.0 % 100 < percent
+    }
+}
+
+struct Feed {
+    fixtures: Fixtures,
+    simulation: Simulation,
+    faults: Faults,
+    rng: Mutex<Rng>,
+}
+
+fn main() {
+    env_logger::init();
+    let cli = App::new("fake-feed")
+        .about("Serve a fake publicXMLFeed from fixture files, with simulated vehicles")
+        .args(&[
+            Arg::with_name("fixtures")
+                .help("Directory of responses recorded with nextbus --record-dir, e.g. fixtures/recorded/responses")
+                .index(1)
+                .required(true),
+            Arg::with_name("port")
+                .long("port")
+                .value_name("PORT")
+                .help("Port to listen on at 127.0.0.1 (default: 8080)"),
+            Arg::with_name("vehicles")
+                .long("vehicles")
+                .value_name("COUNT")
+                .help("Vehicles to simulate in each direction of each route (default: 2)"),
+            Arg::with_name("speed")
+                .long("speed")
+                .value_name("KM_HR")
+                .help("Speed of the simulated vehicles (default: 20)"),
+            Arg::with_name("report_interval")
+                .long("report-interval")
+                .value_name("SECONDS")
+                .help("Time between the reports of each simulated vehicle (default: 10)"),
+            Arg::with_name("feed_error_percent")
+                .long("feed-error-percent")
+                .value_name("PERCENT")
+                .help("Answer PERCENT of requests with an <Error shouldRetry=\"true\"> body"),
+            Arg::with_name("server_error_percent")
+                .long("server-error-percent")
+                .value_name("PERCENT")
+                .help("Answer PERCENT of requests with a 503"),
+            Arg::with_name("empty_percent")
+                .long("empty-percent")
+                .value_name("PERCENT")
+                .help("Answer PERCENT of requests with an empty body"),
+            Arg::with_name("slow_percent")
+                .long("slow-percent")
+                .value_name("PERCENT")
+                .help("Answer PERCENT of requests after --slow-seconds"),
+            Arg::with_name("slow_seconds")
+                .long("slow-seconds")
+                .value_name("SECONDS")
+                .help("Time to wait before answering a slow request (default: 5)"),
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seed for choosing which requests fail (default: the current time)"),
+        ])
+        .get_matches();
+
+    let dir = cli.value_of("fixtures").unwrap();
+    let fixtures = Fixtures::load(Path::new(dir))
+        .unwrap_or_else(|e| panic!("Unable to load fixtures from {}: {}", dir, e));
+    let simulation_options = SimulationOptions {
+        vehicles_per_direction: parse_u64(&cli, "vehicles")
+            .unwrap_or(DEFAULT_VEHICLES_PER_DIRECTION) as usize,
+        speed_km_hr: parse_u64(&cli, "speed").unwrap_or(DEFAULT_SPEED_KM_HR) as f64,
+        report_interval_ms: parse_u64(&cli, "report_interval")
+            .unwrap_or(DEFAULT_REPORT_INTERVAL_SECONDS)
+            * 1000,
+    };
+    let faults = Faults {
+        feed_error_percent: parse_u64(&cli, "feed_error_percent").unwrap_or(0),
+        server_error_percent: parse_u64(&cli, "server_error_percent").unwrap_or(0),
+        empty_percent: parse_u64(&cli, "empty_percent").unwrap_or(0),
+        slow_percent: parse_u64(&cli, "slow_percent").unwrap_or(0),
+        slow_seconds: parse_u64(&cli, "slow_seconds").unwrap_or(DEFAULT_SLOW_SECONDS),
+    };
+    // xorshift never leaves zero
+    let seed = parse_u64(&cli, "seed").unwrap_or_else(now_epoch).max(1);
+
+    let feed = Arc::new(Feed {
+        simulation: Simulation::new(&fixtures, &simulation_options, now_epoch()),
+        fixtures,
+        faults,
+        rng: Mutex::new(Rng(seed)),
+    });
+    let port = parse_u64(&cli, "port").unwrap_or(DEFAULT_PORT);
+    let listener = TcpListener::bind(("127.0.0.1", port as u16))
+        .unwrap_or_else(|e| panic!("Unable to listen on port {}: {}", port, e));
+    eprintln!(
+        "Serving {} at http://127.0.0.1:{}/service/publicXMLFeed",
+        dir, port
+    );
+    http::serve(listener, Arc::new(move |request| feed.answer(request)))
+        .unwrap_or_else(|e| panic!("Server failed: {}", e));
+}
+
+fn parse_u64(cli: &ArgMatches, name: &str) -> Option<u64> {
+    cli.value_of(name).map(|s| {
+        s.parse::<u64>()
+            .unwrap_or_else(|_| panic!("Must provide a positive integer, got '{}'", s))
+    })
+}
+
+fn now_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+impl Feed {
+    fn answer(&self, request: &Request) -> Response {
+        if !request.path.ends_with("/publicXMLFeed") {
+            return Response {
+                status: 404,
+                body: String::new(),
+            };
+        }
+
+        let (slow, server_error, empty, feed_error) = {
+            let mut rng = self.rng.lock().unwrap();
+            (
+                rng.chance(self.faults.slow_percent),
+                rng.chance(self.faults.server_error_percent),
+                rng.chance(self.faults.empty_percent),
+                rng.chance(self.faults.feed_error_percent),
+            )
+        };
+        if slow {
+            thread::sleep(Duration::from_secs(self.faults.slow_seconds));
+        }
+        if server_error {
+            return Response {
+                status: 503,
+                body: String::new(),
+            };
+        }
+        if empty {
+            return Response::xml(String::new());
+        }
+        if feed_error {
+            return error(
+                true,
+                "Agency server cannot accept client while status is: agency name = fake, \
+                 status = UNINITIALIZED. Could be that the agency server is still initializing.",
+            );
+        }
+
+        let command = request.param("command").unwrap_or("");
+        if command == "agencyList" {
+            return self.agency_list();
+        }
+        let agency = match request.param("a") {
+            Some(a) if self.fixtures.agencies.contains_key(a) => a,
+            Some(a) => {
+                return error(
+                    false,
+                    &format!("Agency parameter \"a={}\" is not valid.", a),
+                )
+            }
+            None => {
+                return error(
+                    false,
+                    "agency parameter \"a\" must be specified in query string",
+                )
+            }
+        };
+        let route = request.param("r").filter(|r| !r.is_empty());
+        match command {
+            "routeList" => self.route_list(agency),
+            "routeConfig" => self.route_config(agency, route),
+            "schedule" => self.fixture_for_route(agency, "schedule", route),
+            "messages" => Response::xml(
+                self.fixtures
+                    .response("messages", agency, None)
+                    .map(String::from)
+                    .unwrap_or_else(|| body("")),
+            ),
+            "vehicleLocations" => {
+                let since = request.param("t").and_then(|t| t.parse().ok()).unwrap_or(0);
+                self.vehicle_locations(agency, route, since)
+            }
+            "predictions" => match (route, request.param("s")) {
+                (Some(r), Some(s)) => self.predictions(agency, &[(r, s)]),
+                _ => error(false, "route \"r\" and stop \"s\" must be specified"),
+            },
+            "predictionsForMultiStops" => {
+                let stops: Vec<(&str, &str)> = request
+                    .params("stops")
+                    .into_iter()
+                    .filter_map(|s| {
+                        let mut parts = s.splitn(2, '|');
+                        Some((parts.next()?, parts.next()?))
+                    })
+                    .collect();
+                self.predictions(agency, &stops)
+            }
+            _ => error(false, &format!("Command \"{}\" is not valid.", command)),
+        }
+    }
+
+    fn agency_list(&self) -> Response {
+        if let Some(xml) = self.fixtures.response("agencyList", "", None) {
+            return Response::xml(String::from(xml));
+        }
+        let agencies: Vec<String> = self
+            .fixtures
+            .agencies
+            .keys()
+            .map(|a| {
+                format!(
+                    "<agency tag=\"{0}\" title=\"{0}\" regionTitle=\"\"/>",
+                    escape(a)
+                )
+            })
+            .collect();
+        Response::xml(body(&agencies.join("\n")))
+    }
+
+    fn route_list(&self, agency: &str) -> Response {
+        if let Some(xml) = self.fixtures.response("routeList", agency, None) {
+            return Response::xml(String::from(xml));
+        }
+        let routes: Vec<String> = self.fixtures.agencies[agency]
+            .routes
+            .values()
+            .map(|r| {
+                format!(
+                    "<route tag=\"{}\" title=\"{}\"/>",
+                    escape(&r.tag),
+                    escape(&r.title)
+                )
+            })
+            .collect();
+        Response::xml(body(&routes.join("\n")))
+    }
+
+    // One route's config, or every route's without a route
+    fn route_config(&self, agency: &str, route: Option<&str>) -> Response {
+        let routes = &self.fixtures.agencies[agency].routes;
+        let configs: Vec<&str> = match route {
+            Some(r) => match routes.get(r) {
+                Some(route) => vec![&route.config[..]],
+                None => return no_route(agency, r),
+            },
+            None => routes.values().map(|r| &r.config[..]).collect(),
+        };
+        Response::xml(body(&configs.join("\n")))
+    }
+
+    fn fixture_for_route(&self, agency: &str, command: &str, route: Option<&str>) -> Response {
+        let route = match route {
+            Some(r) => r,
+            None => return error(false, "route \"r\" must be specified"),
+        };
+        match self.fixtures.response(command, agency, Some(route)) {
+            Some(xml) => Response::xml(String::from(xml)),
+            None => no_route(agency, route),
+        }
+    }
+
+    // Vehicles that have reported since `since`, or all of them for 0, with the time to ask
+    // for updates from next
+    fn vehicle_locations(&self, agency: &str, route: Option<&str>, since: u64) -> Response {
+        if let Some(r) = route {
+            if !self.fixtures.agencies[agency].routes.contains_key(r) {
+                return no_route(agency, r);
+            }
+        }
+        let now = now_epoch();
+        let vehicles: Vec<String> = self
+            .simulation
+            .reports(agency, route, now)
+            .into_iter()
+            .filter(|v| v.epoch > since)
+            .map(|v| {
+                format!(
+                    "<vehicle id=\"{}\" routeTag=\"{}\" dirTag=\"{}\" lat=\"{:.6}\" lon=\"{:.6}\" \
+                     secsSinceReport=\"{}\" predictable=\"true\" heading=\"{}\" speedKmHr=\"{}\"/>",
+                    escape(v.id),
+                    escape(v.route_tag),
+                    escape(v.dir_tag),
+                    v.lat,
+                    v.lon,
+                    (now - v.epoch) / 1000,
+                    v.heading.round() as u64 % 360,
+                    v.speed_km_hr.round() as u64,
+                )
+            })
+            .collect();
+        Response::xml(body(&format!(
+            "{}\n<lastTime time=\"{}\"/>",
+            vehicles.join("\n"),
+            now
+        )))
+    }
+
+    fn predictions(&self, agency: &str, stops: &[(&str, &str)]) -> Response {
+        let now = now_epoch();
+        let mut elements = Vec::new();
+        for &(route_tag, stop_tag) in stops {
+            let route = match self.fixtures.agencies[agency].routes.get(route_tag) {
+                Some(r) => r,
+                None => return no_route(agency, route_tag),
+            };
+            let stop = match route.stops.get(stop_tag) {
+                Some(s) => s,
+                None => {
+                    return error(
+                        false,
+                        &format!("Stop s={} on route r={} is not valid.", stop_tag, route_tag),
+                    )
+                }
+            };
+
+            let arrivals = self.simulation.arrivals(agency, route_tag, stop_tag, now);
+            let directions: Vec<String> = route
+                .directions
+                .iter()
+                .filter_map(|d| {
+                    let predictions: Vec<String> = arrivals
+                        .iter()
+                        .filter(|a| a.dir_tag == d.tag)
+                        .take(PREDICTIONS_PER_DIRECTION)
+                        .map(|a| {
+                            format!(
+                                "<prediction epochTime=\"{}\" seconds=\"{}\" minutes=\"{}\" \
+                                 isDeparture=\"false\" dirTag=\"{}\" vehicle=\"{}\" block=\"{}\" \
+                                 tripTag=\"{}\"/>",
+                                now + a.seconds * 1000,
+                                a.seconds,
+                                a.seconds / 60,
+                                escape(a.dir_tag),
+                                escape(a.vehicle),
+                                escape(a.block),
+                                escape(a.block),
+                            )
+                        })
+                        .collect();
+                    match predictions.is_empty() {
+                        true => None,
+                        false => Some(format!(
+                            "<direction title=\"{}\">\n{}\n</direction>",
+                            escape(&d.title),
+                            predictions.join("\n")
+                        )),
+                    }
+                })
+                .collect();
+
+            let attributes = format!(
+                "agencyTitle=\"{}\" routeTitle=\"{}\" routeTag=\"{}\" stopTitle=\"{}\" stopTag=\"{}\"",
+                escape(agency),
+                escape(&route.title),
+                escape(route_tag),
+                escape(&stop.title),
+                escape(stop_tag),
+            );
+            elements.push(match directions.is_empty() {
+                true => format!(
+                    "<predictions {} dirTitleBecauseNoPredictions=\"\"/>",
+                    attributes
+                ),
+                false => format!(
+                    "<predictions {}>\n{}\n</predictions>",
+                    attributes,
+                    directions.join("\n")
+                ),
+            });
+        }
+        Response::xml(body(&elements.join("\n")))
+    }
+}
+
+fn body(contents: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<body copyright=\"Fake data for testing.\">\n{}\n</body>\n",
+        contents
+    )
+}
+
+fn error(should_retry: bool, message: &str) -> Response {
+    Response::xml(body(&format!(
+        "<Error shouldRetry=\"{}\">\n{}\n</Error>",
+        should_retry,
+        escape(message)
+    )))
+}
+
+fn no_route(agency: &str, route: &str) -> Response {
+    error(
+        false,
+        &format!(
+            "Could not get route \"{}\" for agency tag \"{}\".",
+            route, agency
+        ),
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,348 @@
+use fixtures::{Fixtures, Point, Route, Stop};
+
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+// A stop further than this from a path isn't taken to be on it
+const STOP_ON_PATH_METERS: f64 = 50.0;
+
+// Vehicles driving round and round each direction of every route, along the route's path
+// through the direction's stops. Every vehicle's position is a function of the time, so any request at any
+// time sees the vehicles where they would be.
+#[derive(Debug)]
+pub struct Simulation {
+    vehicles: Vec<Vehicle>,
+    // milliseconds since the Unix epoch when the vehicles left their starting points
+    started: u64,
+    meters_per_second: f64,
+    report_interval_ms: u64,
+}
+
+#[derive(Debug)]
+pub struct SimulationOptions {
+    pub vehicles_per_direction: usize,
+    pub speed_km_hr: f64,
+    pub report_interval_ms: u64,
+}
+
+#[derive(Debug)]
+struct Vehicle {
+    id: String,
+    block: String,
+    agency: String,
+    route_tag: String,
+    dir_tag: String,
+    line: Line,
+    // how far along its line the vehicle started
+    start_meters: f64,
+    // when in each report interval the vehicle reports, so reports are spread out
+    report_offset_ms: u64,
+}
+
+// The line a direction runs along, through its stops, with the distance along it to each of
+// its points and to each stop
+#[derive(Debug, Clone)]
+struct Line {
+    points: Vec<Point>,
+    distances: Vec<f64>,
+    stops: Vec<Stop>,
+    stop_distances: Vec<f64>,
+}
+
+impl Line {
+    fn length(&self) -> f64 {
+        *self.distances.last().unwrap_or(&0.0)
+    }
+
+    // (lat, lon, heading) at a distance along the line
+    fn position(&self, meters: f64) -> (f64, f64, f64) {
+        let segment = self
+            .distances
+            .windows(2)
+            .position(|d| meters < d[1])
+            .unwrap_or(self.points.len().saturating_sub(2));
+        let (a, b) = (&self.points[segment], &self.points[segment + 1]);
+        let length = self.distances[segment + 1] - self.distances[segment];
+        let fraction = match length > 0.0 {
+            true => ((meters - self.distances[segment]) / length).clamp(0.0, 1.0),
+            false => 0.0,
+        };
+        (
+            a.lat + fraction * (b.lat - a.lat),
+            a.lon + fraction * (b.lon - a.lon),
+            bearing(a, b),
+        )
+    }
+}
+
+// Where a vehicle was when it last reported
+#[derive(Debug)]
+pub struct Report<'a> {
+    pub id: &'a str,
+    pub route_tag: &'a str,
+    pub dir_tag: &'a str,
+    pub lat: f64,
+    pub lon: f64,
+    pub heading: f64,
+    pub speed_km_hr: f64,
+    pub epoch: u64,
+}
+
+// When a vehicle will next arrive at a stop
+#[derive(Debug)]
+pub struct Arrival<'a> {
+    pub vehicle: &'a str,
+    pub block: &'a str,
+    pub dir_tag: &'a str,
+    pub seconds: u64,
+}
+
+impl Simulation {
+    pub fn new(fixtures: &Fixtures, options: &SimulationOptions, started: u64) -> Simulation {
+        let mut vehicles = Vec::new();
+        for agency in fixtures.agencies.values() {
+            for route in agency.routes.values() {
+                for direction in &route.directions {
+                    let line = match line(route, &direction.stop_tags) {
+                        Some(line) => line,
+                        None => continue,
+                    };
+                    for i in 0..options.vehicles_per_direction {
+                        let n = vehicles.len();
+                        vehicles.push(Vehicle {
+                            id: format!("{}", 1000 + n),
+                            block: format!("{}", 9700 + n),
+                            agency: agency.tag.clone(),
+                            route_tag: route.tag.clone(),
+                            dir_tag: direction.tag.clone(),
+                            start_meters: line.length() * i as f64
+                                / options.vehicles_per_direction as f64,
+                            report_offset_ms: (n as u64 * 3_700)
+                                % options.report_interval_ms.max(1),
+                            line: line.clone(),
+                        });
+                    }
+                }
+            }
+        }
+        Simulation {
+            vehicles,
+            started,
+            meters_per_second: options.speed_km_hr / 3.6,
+            report_interval_ms: options.report_interval_ms.max(1),
+        }
+    }
+
+    // The latest report of each vehicle on an agency's route, or on all its routes, as of a
+    // time
+    pub fn reports(&self, agency: &str, route: Option<&str>, now: u64) -> Vec<Report<'_>> {
+        self.on_route(agency, route)
+            .into_iter()
+            .map(|v| {
+                let since_offset = now.saturating_sub(v.report_offset_ms);
+                let epoch =
+                    since_offset - since_offset % self.report_interval_ms + v.report_offset_ms;
+                let epoch = epoch.min(now);
+                let (lat, lon, heading) = v.line.position(self.distance_along(v, epoch));
+                Report {
+                    id: &v.id,
+                    route_tag: &v.route_tag,
+                    dir_tag: &v.dir_tag,
+                    lat,
+                    lon,
+                    heading,
+                    speed_km_hr: self.meters_per_second * 3.6,
+                    epoch,
+                }
+            })
+            .collect()
+    }
+
+    // The next arrival of each vehicle at a stop on a route, soonest first
+    pub fn arrivals(
+        &self,
+        agency: &str,
+        route: &str,
+        stop_tag: &str,
+        now: u64,
+    ) -> Vec<Arrival<'_>> {
+        let mut arrivals: Vec<Arrival> = self
+            .on_route(agency, Some(route))
+            .into_iter()
+            .filter_map(|v| {
+                let index = v.line.stops.iter().position(|s| s.tag == stop_tag)?;
+                let stop_meters = v.line.stop_distances[index];
+                let along = self.distance_along(v, now);
+                // vehicles past the stop loop round to it again
+                let remaining = match stop_meters >= along {
+                    true => stop_meters - along,
+                    false => stop_meters + v.line.length() - along,
+                };
+                Some(Arrival {
+                    vehicle: &v.id,
+                    block: &v.block,
+                    dir_tag: &v.dir_tag,
+                    seconds: (remaining / self.meters_per_second) as u64,
+                })
+            })
+            .collect();
+        arrivals.sort_by_key(|a| a.seconds);
+        arrivals
+    }
+
+    fn on_route(&self, agency: &str, route: Option<&str>) -> Vec<&Vehicle> {
+        self.vehicles
+            .iter()
+            .filter(|v| v.agency == agency && route.is_none_or(|r| v.route_tag == r))
+            .collect()
+    }
+
+    fn distance_along(&self, vehicle: &Vehicle, epoch: u64) -> f64 {
+        let length = vehicle.line.length();
+        if length <= 0.0 {
+            return 0.0;
+        }
+        let travelled = epoch.saturating_sub(self.started) as f64 / 1000.0 * self.meters_per_second;
+        (vehicle.start_meters + travelled) % length
+    }
+}
+
+// The line through a direction's stops, if it has at least two that the route config lists.
+// It follows the route's path from each stop to the next where one path passes both, either
+// way, and goes straight between them otherwise.
+fn line(route: &Route, stop_tags: &[String]) -> Option<Line> {
+    let stops: Vec<Stop> = stop_tags
+        .iter()
+        .filter_map(|t| route.stops.get(t).cloned())
+        .collect();
+    if stops.len() < 2 {
+        return None;
+    }
+
+    let mut points = vec![stops[0].point()];
+    let mut stop_indexes = vec![0];
+    for pair in stops.windows(2) {
+        let (a, b) = (pair[0].point(), pair[1].point());
+        let piece = route
+            .paths
+            .iter()
+            .filter_map(|path| {
+                let (from, from_offset) = locate(path, &a)?;
+                let (to, to_offset) = locate(path, &b)?;
+                match from_offset <= STOP_ON_PATH_METERS
+                    && to_offset <= STOP_ON_PATH_METERS
+                    && (to - from).abs() > 0.0
+                {
+                    true => Some((from_offset + to_offset, path, from, to)),
+                    false => None,
+                }
+            })
+            .min_by(|x, y| x.0.total_cmp(&y.0))
+            .map(|(_, path, from, to)| {
+                // a path may run either way
+                match from < to {
+                    true => subpath(path, from, to),
+                    false => {
+                        let mut piece = subpath(path, to, from);
+                        piece.reverse();
+                        piece
+                    }
+                }
+            })
+            .unwrap_or_else(|| vec![a, b]);
+        // the piece starts where the last one ended, near the same stop
+        points.extend(piece.into_iter().skip(1));
+        stop_indexes.push(points.len() - 1);
+    }
+
+    let distances = cumulative_distances(&points);
+    let stop_distances = stop_indexes.into_iter().map(|i| distances[i]).collect();
+    Some(Line {
+        points,
+        distances,
+        stops,
+        stop_distances,
+    })
+}
+
+// Distance in meters from the start of a path to each of its points
+fn cumulative_distances(path: &[Point]) -> Vec<f64> {
+    let mut distances = vec![0.0];
+    for pair in path.windows(2) {
+        let travelled = distances[distances.len() - 1] + distance(&pair[0], &pair[1]);
+        distances.push(travelled);
+    }
+    distances
+}
+
+// How far along a path the point on it nearest to a point is, and how far away it is, in
+// meters, or None for an empty path
+fn locate(path: &[Point], point: &Point) -> Option<(f64, f64)> {
+    let mut nearest: Option<(f64, f64)> = path.first().map(|p| (0.0, distance(p, point)));
+    let mut travelled = 0.0;
+    for segment in path.windows(2) {
+        let (a, b) = (&segment[0], &segment[1]);
+        // project as if the earth were flat around the segment
+        let scale = a.lat.to_radians().cos();
+        let (bx, by) = ((b.lon - a.lon) * scale, b.lat - a.lat);
+        let (px, py) = ((point.lon - a.lon) * scale, point.lat - a.lat);
+        let squared_length = bx * bx + by * by;
+        let t = match squared_length > 0.0 {
+            true => ((px * bx + py * by) / squared_length).clamp(0.0, 1.0),
+            false => 0.0,
+        };
+        let length = distance(a, b);
+        let offset = distance(&interpolate(a, b, t), point);
+        if nearest.is_none_or(|(_, o)| offset < o) {
+            nearest = Some((travelled + t * length, offset));
+        }
+        travelled += length;
+    }
+    nearest
+}
+
+// The part of a path between two distances along it
+fn subpath(path: &[Point], from: f64, to: f64) -> Vec<Point> {
+    let distances = cumulative_distances(path);
+    let along = |meters: f64| match distances.iter().position(|&d| d >= meters) {
+        None => path[path.len() - 1],
+        Some(0) => path[0],
+        Some(i) => interpolate(
+            &path[i - 1],
+            &path[i],
+            (meters - distances[i - 1]) / (distances[i] - distances[i - 1]),
+        ),
+    };
+    let mut points = vec![along(from)];
+    points.extend(
+        path.iter()
+            .zip(&distances)
+            .filter(|&(_, &d)| d > from && d < to)
+            .map(|(p, _)| *p),
+    );
+    points.push(along(to));
+    points
+}
+
+fn interpolate(a: &Point, b: &Point, fraction: f64) -> Point {
+    Point {
+        lat: a.lat + fraction * (b.lat - a.lat),
+        lon: a.lon + fraction * (b.lon - a.lon),
+    }
+}
+
+// Great-circle distance in meters
+fn distance(a: &Point, b: &Point) -> f64 {
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (b.lon - a.lon).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * h.sqrt().asin()
+}
+
+// Initial bearing in degrees clockwise from north
+fn bearing(a: &Point, b: &Point) -> f64 {
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let d_lon = (b.lon - a.lon).to_radians();
+    let y = d_lon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+    (y.atan2(x).to_degrees() + 360.0) % 360.0
+}
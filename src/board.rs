@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+
+use output::{Color, Styler};
+use prediction::{Prediction, PredictionsList};
+
+const DELAYED_MARKER: &str = "!";
+const LAYOVER_MARKER: &str = "*";
+
+// One line of the board: the arrivals of a route in one direction at a stop
+struct Row {
+    route_tag: String,
+    direction: String,
+    predictions: Vec<Prediction>,
+}
+
+struct StopBoard {
+    title: String,
+    rows: Vec<Row>,
+}
+
+// Render predictions as an arrivals board, grouped by stop and then by route and direction:
+//
+//   Mission St & 16th St (5552)
+//     22  Outbound to UCSF Mission Bay  3 min, 12 min!, 24 min*
+pub fn render(predictions: PredictionsList, styler: Styler) -> String {
+    let mut boards: BTreeMap<String, StopBoard> = BTreeMap::new();
+    for p in predictions.predictions {
+        let board = boards
+            .entry(p.stop_tag.clone())
+            .or_insert_with(|| StopBoard {
+                title: p.stop_title.clone(),
+                rows: Vec::new(),
+            });
+        for d in p.directions {
+            let mut arrivals = d.prediction;
+            arrivals.sort_by_key(|a| a.epoch);
+            board.rows.push(Row {
+                route_tag: p.route_tag.clone(),
+                direction: d.title,
+                predictions: arrivals,
+            });
+        }
+    }
+
+    let route_width = boards
+        .values()
+        .flat_map(|b| b.rows.iter())
+        .map(|r| r.route_tag.chars().count())
+        .max()
+        .unwrap_or(0);
+    let direction_width = boards
+        .values()
+        .flat_map(|b| b.rows.iter())
+        .map(|r| r.direction.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut any_delayed = false;
+    let mut any_layover = false;
+    for (stop_tag, mut board) in boards {
+        lines.push(styler.paint(Color::Bold, &format!("{} ({})", board.title, stop_tag)));
+        if board.rows.is_empty() {
+            lines.push(format!("  {}", styler.paint(Color::Dim, "no predictions")));
+        }
+        board.rows.sort_by(|a, b| {
+            a.route_tag
+                .cmp(&b.route_tag)
+                .then_with(|| a.direction.cmp(&b.direction))
+        });
+        for row in board.rows {
+            let arrivals: Vec<String> = row
+                .predictions
+                .iter()
+                .map(|p| {
+                    any_delayed |= p.delayed;
+                    any_layover |= p.affected_by_layover;
+                    arrival(p, styler)
+                })
+                .collect();
+            lines.push(format!(
+                "  {:route$}  {:direction$}  {}",
+                row.route_tag,
+                row.direction,
+                arrivals.join(", "),
+                route = route_width,
+                direction = direction_width,
+            ));
+        }
+        lines.push(String::new());
+    }
+
+    let mut legend: Vec<String> = Vec::new();
+    if any_delayed {
+        legend.push(format!("{} delayed", DELAYED_MARKER));
+    }
+    if any_layover {
+        legend.push(format!("{} affected by layover", LAYOVER_MARKER));
+    }
+    if !legend.is_empty() {
+        lines.push(styler.paint(Color::Dim, &legend.join("  ")));
+    }
+
+    lines.join("\n")
+}
+
+fn arrival(p: &Prediction, styler: Styler) -> String {
+    let mut text = match p.minutes {
+        0 => String::from("now"),
+        m => format!("{} min", m),
+    };
+    if p.delayed {
+        text.push_str(DELAYED_MARKER);
+    }
+    if p.affected_by_layover {
+        text.push_str(LAYOVER_MARKER);
+    }
+    match (p.delayed, p.affected_by_layover) {
+        (true, _) => styler.paint(Color::Red, &text),
+        (false, true) => styler.paint(Color::Yellow, &text),
+        (false, false) => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_xml_rs::deserialize;
+
+    // Two stops, out of order, with routes, directions and arrivals out of order too
+    const PREDICTIONS: &str = r#"<body>
+<predictions agencyTitle="San Francisco Muni" routeTitle="N-Judah" routeTag="N" stopTitle="Judah St &amp; 28th Ave" stopTag="5186">
+<direction title="Outbound to Ocean Beach">
+<prediction epochTime="1792416420000" seconds="900" minutes="15" isDeparture="false" dirTag="N____O_F00" vehicle="1504" affectedByLayover="true"/>
+<prediction epochTime="1792415580000" seconds="60" minutes="1" isDeparture="false" dirTag="N____O_F00" vehicle="1501"/>
+</direction>
+<direction title="Inbound to Caltrain via Downtown">
+<prediction epochTime="1792415520000" seconds="0" minutes="0" isDeparture="false" dirTag="N____I_F00" vehicle="1502" delayed="true"/>
+</direction>
+</predictions>
+<predictions agencyTitle="San Francisco Muni" routeTitle="J-Church" routeTag="J" stopTitle="Judah St &amp; 28th Ave" stopTag="5186">
+<direction title="Inbound to Embarcadero">
+<prediction epochTime="1792415940000" seconds="420" minutes="7" isDeparture="false" dirTag="J____I_F00" vehicle="1401" delayed="true" affectedByLayover="true"/>
+</direction>
+</predictions>
+<predictions agencyTitle="San Francisco Muni" routeTitle="N-Judah" routeTag="N" stopTitle="Judah St &amp; 19th Ave" stopTag="5178">
+</predictions>
+</body>"#;
+
+    fn predictions() -> PredictionsList {
+        deserialize(PREDICTIONS.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn renders_a_board_by_stop_route_and_direction() {
+        let board = render(predictions(), Styler { enabled: false });
+        assert_eq!(
+            board.lines().collect::<Vec<&str>>(),
+            vec![
+                "Judah St & 19th Ave (5178)",
+                "  no predictions",
+                "",
+                "Judah St & 28th Ave (5186)",
+                "  J  Inbound to Embarcadero            7 min!*",
+                "  N  Inbound to Caltrain via Downtown  now!",
+                "  N  Outbound to Ocean Beach           1 min, 15 min*",
+                "",
+                "! delayed  * affected by layover",
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_out_the_legend_without_markers() {
+        let mut predictions = predictions();
+        for p in &mut predictions.predictions {
+            for d in &mut p.directions {
+                for arrival in &mut d.prediction {
+                    arrival.delayed = false;
+                    arrival.affected_by_layover = false;
+                }
+            }
+        }
+        let board = render(predictions, Styler { enabled: false });
+        assert!(board.ends_with("1 min, 15 min\n"), "{}", board);
+        assert!(!board.contains('!') && !board.contains('*'));
+    }
+
+    #[test]
+    fn colors_delayed_and_layover_arrivals() {
+        let board = render(predictions(), Styler { enabled: true });
+        // delayed wins over layover
+        assert!(board.contains("\x1b[31m7 min!*\x1b[0m"));
+        assert!(board.contains("\x1b[31mnow!\x1b[0m"));
+        assert!(board.contains("\x1b[33m15 min*\x1b[0m"));
+        assert!(board.contains("1 min, "));
+        assert!(board.contains("\x1b[1mJudah St & 28th Ave (5186)\x1b[0m"));
+    }
+}
@@ -1,12 +1,94 @@
+use flate2::read::GzDecoder;
+use futures::future::{self, Either, Loop};
+use futures::{stream, Future, Stream};
 use reqwest;
+use tokio;
 
-use serde::de::{self, Deserialize, Deserializer};
+use serde::de::{self, Deserialize, DeserializeOwned, Deserializer};
 use serde_xml_rs::deserialize;
 use std::fmt::{Debug, Display};
+use std::io::{self, Cursor, Read};
 use std::result::Result as StdResult;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use error_chain::ChainedError;
 use errors::*;
+use lenient::{self, SchemaDrift};
+use ratelimit::{RateLimiter, RateLimits};
+use recording::{RecordedResponse, Recorder, Recording};
+use xmlstream;
+
+// Every fetcher builds its URLs on this, and the client sends them to `ClientConfig::feed_url`
+pub const DEFAULT_FEED_URL: &str = "https://retro.umoiq.com/service/publicXMLFeed";
+pub const DEFAULT_CONNECT_TIMEOUT_SECONDS: u64 = 10;
+pub const DEFAULT_REQUEST_TIMEOUT_SECONDS: u64 = 30;
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub connect_timeout: Duration,
+    // for the whole request, from connecting to reading the last of the response body
+    pub request_timeout: Duration,
+    pub proxy: Option<String>,
+    pub rate_limits: RateLimits,
+    // maximum number of requests in flight at once for async fetchers
+    pub concurrency: usize,
+    // save every response to a directory, or answer requests from one instead of the network
+    pub recording: Option<Recording>,
+    // the feed to send requests to, e.g. a local fake-feed server
+    pub feed_url: String,
+    // skip records that fail to parse, instead of failing the whole response
+    pub lenient: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            connect_timeout: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECONDS),
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECONDS),
+            proxy: None,
+            rate_limits: RateLimits::default(),
+            concurrency: DEFAULT_CONCURRENCY,
+            recording: None,
+            feed_url: String::from(DEFAULT_FEED_URL),
+            lenient: false,
+        }
+    }
+}
+
+// A long-lived HTTP client shared by every fetcher, so that polling loops reuse pooled
+// keep-alive connections, and all requests go through the same rate limiter
+pub struct Client {
+    http: reqwest::Client,
+    async_http: reqwest::async::Client,
+    limiter: Arc<RateLimiter>,
+    // async requests all run on one runtime so that pooled connections outlive a request
+    runtime: Mutex<tokio::runtime::Runtime>,
+    recorder: Option<Arc<Recorder>>,
+    feed_url: String,
+    // what lenient parsing has skipped and ignored, when parsing leniently
+    drift: Option<Arc<SchemaDrift>>,
+    pub concurrency: usize,
+}
+
+impl Debug for Client {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Client")
+            .field("limiter", &self.limiter)
+            .field("concurrency", &self.concurrency)
+            .finish()
+    }
+}
+
+pub fn user_agent() -> String {
+    format!(
+        "nextbus/{} ({}; +https://github.com/shahin/nextbus)",
+        env!("VERGEN_SEMVER"),
+        env!("VERGEN_SHA_SHORT"),
+    )
+}
 
 pub trait Contents {
     fn is_empty(&self) -> bool;
@@ -24,35 +106,504 @@ where
     T::from_str(&s).map_err(de::Error::custom)
 }
 
-pub fn download<'de, T>(url: &String) -> Result<Option<T>>
+impl Client {
+    pub fn new(config: ClientConfig) -> Result<Client> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::USER_AGENT,
+            reqwest::header::HeaderValue::from_str(&user_agent())
+                .chain_err(|| "Invalid User-Agent header.")?,
+        );
+        // bodies are decompressed by `decode_body` and `download_reader` rather than by
+        // reqwest, so that the rate limiter counts the bytes that came over the wire
+        headers.insert(
+            reqwest::header::ACCEPT_ENCODING,
+            reqwest::header::HeaderValue::from_static("gzip"),
+        );
+
+        let mut builder = reqwest::Client::builder()
+            .default_headers(headers.clone())
+            .gzip(false)
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout);
+        let mut async_builder = reqwest::async::Client::builder()
+            .default_headers(headers)
+            .gzip(false)
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout);
+        match config.proxy {
+            Some(ref proxy) => {
+                builder = builder.proxy(reqwest::Proxy::all(&proxy[..])?);
+                async_builder = async_builder.proxy(reqwest::Proxy::all(&proxy[..])?);
+            }
+            None => {
+                builder = builder.use_sys_proxy();
+                async_builder = async_builder.use_sys_proxy();
+            }
+        };
+
+        Ok(Client {
+            http: builder.build()?,
+            async_http: async_builder.build()?,
+            limiter: Arc::new(RateLimiter::new(config.rate_limits)),
+            runtime: Mutex::new(tokio::runtime::Runtime::new()?),
+            recorder: match config.recording {
+                Some(recording) => Some(Arc::new(Recorder::new(recording)?)),
+                None => None,
+            },
+            feed_url: config.feed_url,
+            drift: match config.lenient {
+                true => Some(Arc::new(SchemaDrift::default())),
+                false => None,
+            },
+            concurrency: config.concurrency.max(1),
+        })
+    }
+
+    // Run a future returned by one of the async fetchers to completion
+    pub fn block_on<F>(&self, f: F) -> Result<F::Item>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        self.runtime.lock().unwrap().block_on(f)
+    }
+
+    // Print what lenient parsing has skipped or ignored since this was last called to
+    // stderr, once a run or after each poll, whatever the log level
+    pub fn report_drift(&self) {
+        if let Some(ref drift) = self.drift {
+            for line in drift.summary() {
+                eprintln!("{}", line);
+            }
+        }
+    }
+
+    // The URL to request for a URL built on DEFAULT_FEED_URL. Recordings keep the URL as it
+    // was built, so they can be replayed against any feed.
+    fn feed_request_url(&self, url: &str) -> String {
+        match url.starts_with(DEFAULT_FEED_URL) {
+            true => self.feed_url.clone() + &url[DEFAULT_FEED_URL.len()..],
+            false => String::from(url),
+        }
+    }
+
+    // The recorded response to a URL when replaying a recording
+    fn replayed(&self, url: &str) -> Option<Result<RecordedResponse>> {
+        self.recorder.as_ref().and_then(|r| r.replayed(url))
+    }
+
+    // Save a response when recording
+    fn record(&self, url: &str, response: &RecordedResponse) -> Result<()> {
+        match self.recorder {
+            Some(ref recorder) => recorder.record(url, response),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn download<T>(client: &Client, url: &str) -> Result<Option<T>>
+where
+    T: DeserializeOwned + Debug + Contents,
+{
+    let response = match client.replayed(url) {
+        Some(replayed) => replayed?,
+        None => fetch(client, url)?,
+    };
+    parse_response(
+        url,
+        response.status,
+        &response.headers,
+        &response.body,
+        client.drift.as_deref(),
+    )
+}
+
+// Download a whole response body, recording the response when recording
+fn fetch(client: &Client, url: &str) -> Result<RecordedResponse> {
+    client.limiter.acquire(url);
+    let mut response = client.http.get(&client.feed_request_url(url)).send()?;
+    let mut wire = Vec::new();
+    response.copy_to(&mut wire)?;
+    client.limiter.record_bytes(wire.len());
+    let mut headers = response.headers().clone();
+    let body = decode_body(&mut headers, wire)?;
+    let response = RecordedResponse {
+        status: response.status(),
+        headers,
+        body,
+    };
+    client.record(url, &response)?;
+    Ok(response)
+}
+
+// Whether a response body is gzipped, as asked for
+fn is_gzipped(headers: &reqwest::header::HeaderMap) -> bool {
+    headers
+        .get(reqwest::header::CONTENT_ENCODING)
+        .is_some_and(|e| e == "gzip")
+}
+
+// The text of a response body as it came over the wire, decompressed if it was gzipped, in
+// which case the headers that describe the compressed body are dropped
+fn decode_body(headers: &mut reqwest::header::HeaderMap, wire: Vec<u8>) -> Result<String> {
+    let bytes = match is_gzipped(headers) {
+        true => {
+            let mut decoded = Vec::new();
+            GzDecoder::new(&wire[..])
+                .read_to_end(&mut decoded)
+                .chain_err(|| "Failed to decompress response.")?;
+            headers.remove(reqwest::header::CONTENT_ENCODING);
+            headers.remove(reqwest::header::CONTENT_LENGTH);
+            decoded
+        }
+        false => wire,
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// A response body that is read as it arrives, rather than all at once, decompressed if it was
+// gzipped
+pub struct ResponseReader {
+    body: Box<dyn Read + Send>,
+}
+
+impl ResponseReader {
+    fn new(wire: WireReader, gzipped: bool) -> ResponseReader {
+        ResponseReader {
+            body: match gzipped {
+                true => Box::new(GzDecoder::new(wire)),
+                false => Box::new(wire),
+            },
+        }
+    }
+}
+
+impl Read for ResponseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+// A response body as it comes over the wire. The bytes read are counted against the rate
+// limiter, if given, when the reader is dropped; it's left out for bodies that were counted
+// when they were fetched, or that were replayed from disk.
+struct WireReader {
+    response: Box<dyn Read + Send>,
+    limiter: Option<Arc<RateLimiter>>,
+    bytes: usize,
+}
+
+impl Read for WireReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.response.read(buf)?;
+        self.bytes += n;
+        Ok(n)
+    }
+}
+
+impl Drop for WireReader {
+    fn drop(&mut self) {
+        if let Some(ref limiter) = self.limiter {
+            limiter.record_bytes(self.bytes);
+        }
+    }
+}
+
+// Like `download`, but leaves parsing the body to the caller, e.g. with `xmlstream::Elements`
+pub fn download_reader(client: &Client, url: &str) -> Result<ResponseReader> {
+    // a recorded body is read from memory, and a body being recorded is downloaded in full
+    // before it's read, so that it can be saved whole
+    let (response, limiter, gzipped): (Box<dyn Read + Send>, _, _) =
+        match (client.replayed(url), &client.recorder) {
+            (Some(replayed), _) => {
+                let replayed = replayed?;
+                check_status(url, replayed.status, &replayed.headers)?;
+                (
+                    Box::new(Cursor::new(replayed.body.into_bytes())),
+                    None,
+                    false,
+                )
+            }
+            (None, Some(_)) => {
+                // `fetch` has already counted and decompressed the body
+                let fetched = fetch(client, url)?;
+                check_status(url, fetched.status, &fetched.headers)?;
+                (
+                    Box::new(Cursor::new(fetched.body.into_bytes())),
+                    None,
+                    false,
+                )
+            }
+            (None, None) => {
+                client.limiter.acquire(url);
+                let response = client.http.get(&client.feed_request_url(url)).send()?;
+                check_status(url, response.status(), response.headers())?;
+                let gzipped = is_gzipped(response.headers());
+                (Box::new(response), Some(client.limiter.clone()), gzipped)
+            }
+        };
+    let wire = WireReader {
+        response,
+        limiter,
+        bytes: 0,
+    };
+    Ok(ResponseReader::new(wire, gzipped))
+}
+
+// Async equivalent of `download`, waiting on the rate limiter without blocking a thread
+pub fn download_async<T>(
+    client: &Client,
+    url: String,
+) -> impl Future<Item = Option<T>, Error = Error> + Send
+where
+    T: DeserializeOwned + Debug + Contents + Send + 'static,
+{
+    if let Some(replayed) = client.replayed(&url) {
+        return Either::A(future::result(replayed.and_then(|response| {
+            parse_response(
+                &url,
+                response.status,
+                &response.headers,
+                &response.body,
+                client.drift.as_deref(),
+            )
+        })));
+    }
+
+    let http = client.async_http.clone();
+    let limiter = client.limiter.clone();
+    let recorder = client.recorder.clone();
+    let drift = client.drift.clone();
+    let request_url = client.feed_request_url(&url);
+
+    Either::B(
+        acquire_async(limiter.clone(), url.clone()).and_then(move |_| {
+            http.get(&request_url[..])
+                .send()
+                .and_then(|response| {
+                    let status = response.status();
+                    let headers = response.headers().clone();
+                    response
+                        .into_body()
+                        .concat2()
+                        .map(move |wire| (status, headers, wire.to_vec()))
+                })
+                .map_err(Error::from)
+                .and_then(move |(status, mut headers, wire)| {
+                    limiter.record_bytes(wire.len());
+                    let response = RecordedResponse {
+                        status,
+                        body: decode_body(&mut headers, wire)?,
+                        headers,
+                    };
+                    if let Some(recorder) = recorder {
+                        recorder.record(&url, &response)?;
+                    }
+                    parse_response(
+                        &url,
+                        response.status,
+                        &response.headers,
+                        &response.body,
+                        drift.as_deref(),
+                    )
+                })
+        }),
+    )
+}
+
+// Download every URL with at most `client.concurrency` requests in flight, yielding the
+// results in the same order as the URLs. A URL that fails to download is logged and yields
+// None, so one bad response doesn't lose the others.
+pub fn download_all<T>(
+    client: &Client,
+    urls: Vec<String>,
+) -> impl Future<Item = Vec<Option<T>>, Error = Error> + Send
 where
-    T: Deserialize<'de> + Debug + Contents,
+    T: DeserializeOwned + Debug + Contents + Send + 'static,
 {
-    let mut response = reqwest::get(&url[..])?;
-    let body = response.text()?;
-    let date = response
-        .headers()
+    let downloads: Vec<_> = urls
+        .into_iter()
+        .map(|url| {
+            download_async(client, url.clone()).then(move |result| {
+                Ok::<_, Error>(result.unwrap_or_else(|e| {
+                    warn!(
+                        "Download error: {} from URL={}",
+                        e.display_chain().to_string(),
+                        url
+                    );
+                    None
+                }))
+            })
+        })
+        .collect();
+    stream::iter_ok(downloads)
+        .buffered(client.concurrency)
+        .collect()
+}
+
+fn acquire_async(
+    limiter: Arc<RateLimiter>,
+    url: String,
+) -> impl Future<Item = (), Error = Error> + Send {
+    future::loop_fn((), move |_| match limiter.try_acquire(&url) {
+        None => Either::A(future::ok(Loop::Break(()))),
+        Some(wait) => Either::B(
+            tokio::timer::Delay::new(Instant::now() + wait)
+                .map(|_| Loop::Continue(()))
+                .map_err(|e| Error::from(format!("Rate limiter timer failed: {}", e))),
+        ),
+    })
+}
+
+fn check_status(
+    url: &str,
+    status: reqwest::StatusCode,
+    headers: &reqwest::header::HeaderMap,
+) -> Result<()> {
+    let date = headers
         .get(reqwest::header::DATE)
-        .unwrap()
-        .to_str()
-        .unwrap();
-    let status = response.status();
+        .and_then(|d| d.to_str().ok())
+        .unwrap_or("");
     match status {
         reqwest::StatusCode::OK => {
             debug!(
                 r#"request="{}" response="{}" response_date="{}""#,
                 url, status, date
             );
-            deserialize(body.as_bytes())
-                .and_then(|d: T| if d.is_empty() { Ok(None) } else { Ok(Some(d)) })
-                .chain_err(|| "Deserialization failed.")
+            Ok(())
         }
         _ => {
             warn!(
                 r#"request="{}" response="{}" response_date="{}""#,
                 url, status, date
             );
-            return Err(format!("Bad response: {}", status).into());
+            Err(format!("Bad response: {}", status).into())
         }
     }
 }
+
+fn parse_response<T>(
+    url: &str,
+    status: reqwest::StatusCode,
+    headers: &reqwest::header::HeaderMap,
+    body: &str,
+    drift: Option<&SchemaDrift>,
+) -> Result<Option<T>>
+where
+    T: DeserializeOwned + Debug + Contents,
+{
+    check_status(url, status, headers)?;
+    let parsed: Result<T> = match drift {
+        Some(drift) => lenient::deserialize(body, drift),
+        None => deserialize(body.as_bytes()).map_err(Error::from),
+    };
+    parsed
+        .map(|d| if d.is_empty() { None } else { Some(d) })
+        .chain_err(|| "Deserialization failed.")
+}
+
+// Deserialize an element of a streamed response. When parsing leniently, an element that
+// fails to parse is skipped and None is returned.
+pub fn deserialize_element<T: DeserializeOwned>(
+    client: &Client,
+    element: xmlstream::Element,
+) -> Result<Option<T>> {
+    match client.drift {
+        Some(ref drift) => Ok(lenient::deserialize_element(element, drift)),
+        None => element.deserialize().map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn counts_only_bodies_read_from_the_network() {
+        let url = "https://example.com/feed?command=routeList&a=sf-muni";
+        let read = |limiter: Option<Arc<RateLimiter>>| {
+            let mut reader = WireReader {
+                response: Box::new(Cursor::new(vec![b'x'; 100])),
+                limiter,
+                bytes: 0,
+            };
+            io::copy(&mut reader, &mut io::sink()).unwrap();
+        };
+        let limiter = Arc::new(RateLimiter::new(RateLimits {
+            max_bytes: 100,
+            ..RateLimits::default()
+        }));
+
+        // a whole bucket's worth, and then bodies that were already counted, or replayed
+        read(Some(limiter.clone()));
+        read(None);
+        read(None);
+        assert_eq!(limiter.try_acquire(url), None);
+        read(Some(limiter.clone()));
+        assert!(limiter.try_acquire(url).is_some());
+    }
+
+    fn gzip(text: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn gzip_headers() -> reqwest::header::HeaderMap {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::CONTENT_ENCODING,
+            reqwest::header::HeaderValue::from_static("gzip"),
+        );
+        headers.insert(
+            reqwest::header::CONTENT_LENGTH,
+            reqwest::header::HeaderValue::from_static("40"),
+        );
+        headers
+    }
+
+    #[test]
+    fn decompresses_gzipped_bodies() {
+        let text = "<body>".to_owned() + &"<route tag=\"N\"/>".repeat(100) + "</body>";
+        let mut headers = gzip_headers();
+        assert_eq!(decode_body(&mut headers, gzip(&text)).unwrap(), text);
+        // the recorded headers describe the body as it's saved
+        assert!(headers.is_empty());
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert_eq!(
+            decode_body(&mut headers, text.clone().into_bytes()).unwrap(),
+            text
+        );
+        assert!(decode_body(&mut gzip_headers(), text.into_bytes()).is_err());
+    }
+
+    #[test]
+    fn counts_the_compressed_bytes_of_a_streamed_body() {
+        let url = "https://example.com/feed?command=routeList&a=sf-muni";
+        let text = "<body>".to_owned() + &"<route tag=\"N\"/>".repeat(100) + "</body>";
+        let wire = gzip(&text);
+        assert!(wire.len() < 100);
+        let limiter = Arc::new(RateLimiter::new(RateLimits {
+            max_bytes: 100,
+            ..RateLimits::default()
+        }));
+
+        let mut decoded = String::new();
+        let wire = WireReader {
+            response: Box::new(Cursor::new(wire)),
+            limiter: Some(limiter.clone()),
+            bytes: 0,
+        };
+        ResponseReader::new(wire, true)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+        // over a kilobyte of text, but less than the bucket's 100 bytes compressed
+        assert_eq!(limiter.try_acquire(url), None);
+    }
+}
@@ -0,0 +1,306 @@
+use std::num::ParseFloatError;
+use std::str::FromStr;
+
+use errors::*;
+
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+
+// A latitude or longitude in decimal degrees. The feed sends these as strings, so fields of
+// this type are parsed with `client::from_string`, and are serialized as JSON numbers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct Coordinate(pub f64);
+
+impl FromStr for Coordinate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Coordinate> {
+        let value = s
+            .trim()
+            .parse::<f64>()
+            .map_err(|e: ParseFloatError| Error::from(e.to_string()))?;
+        // NaN and infinities parse as floats, but aren't anywhere
+        match value.is_finite() {
+            true => Ok(Coordinate(value)),
+            false => Err(format!("'{}' is not a finite coordinate", s).into()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LatLon {
+    pub lat: Coordinate,
+    pub lon: Coordinate,
+}
+
+impl LatLon {
+    pub fn new(lat: Coordinate, lon: Coordinate) -> LatLon {
+        LatLon { lat, lon }
+    }
+
+    // Great-circle distance in meters, using the haversine formula
+    pub fn distance_to(&self, other: &LatLon) -> f64 {
+        let (lat1, lat2) = (self.lat.0.to_radians(), other.lat.0.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.lon.0 - self.lon.0).to_radians();
+
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+    }
+
+    // Initial bearing in degrees clockwise from north, in [0, 360)
+    pub fn bearing_to(&self, other: &LatLon) -> f64 {
+        let (lat1, lat2) = (self.lat.0.to_radians(), other.lat.0.to_radians());
+        let d_lon = (other.lon.0 - self.lon.0).to_radians();
+
+        let y = d_lon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+        (y.atan2(x).to_degrees() + 360.0) % 360.0
+    }
+}
+
+// Parse "lat,lon", e.g. from the command line
+impl FromStr for LatLon {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LatLon> {
+        let parts: Vec<&str> = s.split(',').collect();
+        match parts.len() {
+            2 => Ok(LatLon::new(
+                parts[0]
+                    .parse()
+                    .chain_err(|| format!("Invalid latitude in '{}'", s))?,
+                parts[1]
+                    .parse()
+                    .chain_err(|| format!("Invalid longitude in '{}'", s))?,
+            )),
+            _ => Err(format!("Expected 'lat,lon', got '{}'", s).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min: LatLon,
+    pub max: LatLon,
+}
+
+impl BoundingBox {
+    pub fn contains(&self, point: &LatLon) -> bool {
+        self.min.lat <= point.lat
+            && point.lat <= self.max.lat
+            && self.min.lon <= point.lon
+            && point.lon <= self.max.lon
+    }
+}
+
+// Parse "minLat,minLon,maxLat,maxLon", e.g. from the command line
+impl FromStr for BoundingBox {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<BoundingBox> {
+        let parts: Vec<&str> = s.split(',').collect();
+        match parts.len() {
+            4 => Ok(BoundingBox {
+                min: format!("{},{}", parts[0], parts[1]).parse()?,
+                max: format!("{},{}", parts[2], parts[3]).parse()?,
+            }),
+            _ => Err(format!("Expected 'minLat,minLon,maxLat,maxLon', got '{}'", s).into()),
+        }
+    }
+}
+
+// An area of interest that points can be filtered by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Area {
+    Bounds(BoundingBox),
+    Circle { center: LatLon, radius_meters: f64 },
+}
+
+impl Area {
+    pub fn contains(&self, point: &LatLon) -> bool {
+        match *self {
+            Area::Bounds(ref bbox) => bbox.contains(point),
+            Area::Circle {
+                ref center,
+                radius_meters,
+            } => center.distance_to(point) <= radius_meters,
+        }
+    }
+}
+
+// Where a point lies relative to a path: how far along the path the nearest point on it is,
+// and how far the point is from that nearest point, both in meters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathPosition {
+    pub distance_along: f64,
+    pub offset: f64,
+}
+
+// Locate a point on a path through the given points, or None for an empty path
+pub fn locate_on_path(path: &[LatLon], point: &LatLon) -> Option<PathPosition> {
+    if path.len() == 1 {
+        return Some(PathPosition {
+            distance_along: 0.0,
+            offset: point.distance_to(&path[0]),
+        });
+    }
+
+    let mut nearest: Option<PathPosition> = None;
+    let mut travelled = 0.0;
+    for segment in path.windows(2) {
+        let (a, b) = (&segment[0], &segment[1]);
+        let length = a.distance_to(b);
+
+        // project onto the segment as if the earth were flat around it, which is close enough
+        // over the distance between stops
+        let scale = a.lat.0.to_radians().cos();
+        let (bx, by) = ((b.lon.0 - a.lon.0) * scale, b.lat.0 - a.lat.0);
+        let (px, py) = ((point.lon.0 - a.lon.0) * scale, point.lat.0 - a.lat.0);
+        let squared_length = bx * bx + by * by;
+        let t = match squared_length > 0.0 {
+            true => ((px * bx + py * by) / squared_length).clamp(0.0, 1.0),
+            false => 0.0,
+        };
+        let projected = LatLon::new(
+            Coordinate(a.lat.0 + t * (b.lat.0 - a.lat.0)),
+            Coordinate(a.lon.0 + t * (b.lon.0 - a.lon.0)),
+        );
+
+        let offset = point.distance_to(&projected);
+        if nearest.is_none_or(|n| offset < n.offset) {
+            nearest = Some(PathPosition {
+                distance_along: travelled + t * length,
+                offset,
+            });
+        }
+        travelled += length;
+    }
+    nearest
+}
+
+// Distance in meters from the start of a path to each of its points
+pub fn cumulative_distances(path: &[LatLon]) -> Vec<f64> {
+    let mut travelled = 0.0;
+    let mut distances = vec![0.0];
+    for segment in path.windows(2) {
+        travelled += segment[0].distance_to(&segment[1]);
+        distances.push(travelled);
+    }
+    distances
+}
+
+// The part of a path between two distances along it, starting and ending where the path is
+// at those distances
+pub fn subpath(path: &[LatLon], from: f64, to: f64) -> Vec<LatLon> {
+    let distances = cumulative_distances(path);
+    let mut points = vec![point_along(path, &distances, from)];
+    points.extend(
+        path.iter()
+            .zip(&distances)
+            .filter(|&(_, &d)| d > from && d < to)
+            .map(|(p, _)| *p),
+    );
+    points.push(point_along(path, &distances, to));
+    points
+}
+
+// The point a distance along a path, given the distance to each of its points
+fn point_along(path: &[LatLon], distances: &[f64], along: f64) -> LatLon {
+    match distances.iter().position(|&d| d >= along) {
+        None => path[path.len() - 1],
+        Some(0) => path[0],
+        Some(i) => {
+            let (a, b) = (&path[i - 1], &path[i]);
+            let t = (along - distances[i - 1]) / (distances[i] - distances[i - 1]);
+            LatLon::new(
+                Coordinate(a.lat.0 + t * (b.lat.0 - a.lat.0)),
+                Coordinate(a.lon.0 + t * (b.lon.0 - a.lon.0)),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_coordinates() {
+        assert_eq!(
+            " 37.7612".parse::<Coordinate>().unwrap(),
+            Coordinate(37.7612)
+        );
+        let position: LatLon = "37.7612,-122.4856".parse().unwrap();
+        assert_eq!(position.lon, Coordinate(-122.4856));
+    }
+
+    #[test]
+    fn rejects_coordinates_that_are_not_finite() {
+        for s in &["nan", "NaN", "inf", "-infinity", "", "north"] {
+            assert!(s.parse::<Coordinate>().is_err(), "parsed '{}'", s);
+        }
+        assert!("nan,-122.4856".parse::<LatLon>().is_err());
+        assert!("37.7,-122.5,inf,-122.4".parse::<BoundingBox>().is_err());
+    }
+
+    fn point(lat: f64, lon: f64) -> LatLon {
+        LatLon::new(Coordinate(lat), Coordinate(lon))
+    }
+
+    #[test]
+    fn measures_great_circle_distances() {
+        let san_francisco = point(37.7749, -122.4194);
+        let los_angeles = point(34.0522, -118.2437);
+        let distance = san_francisco.distance_to(&los_angeles);
+        assert!((distance - 559_121.0).abs() < 1.0, "{}", distance);
+        assert_eq!(distance, los_angeles.distance_to(&san_francisco));
+
+        // a degree of latitude, and a degree of longitude across the antimeridian
+        let degree = EARTH_RADIUS_METERS * 1.0_f64.to_radians();
+        assert!((point(0.0, 0.0).distance_to(&point(1.0, 0.0)) - degree).abs() < 1e-6);
+        let across = point(0.0, 179.5).distance_to(&point(0.0, -179.5));
+        assert!((across - degree).abs() < 1e-6, "{}", across);
+
+        assert_eq!(san_francisco.distance_to(&san_francisco), 0.0);
+    }
+
+    #[test]
+    fn measures_bearings_clockwise_from_north() {
+        let origin = point(37.7749, -122.4194);
+        let bearing = |lat: f64, lon: f64| origin.bearing_to(&point(lat, lon));
+        assert_eq!(bearing(37.8, -122.4194), 0.0);
+        assert_eq!(bearing(37.7, -122.4194), 180.0);
+        // a parallel isn't a great circle, so points along it are a little off east and west
+        assert!((bearing(37.7749, -122.4) - 90.0).abs() < 0.05);
+        assert!((bearing(37.7749, -122.5) - 270.0).abs() < 0.05);
+
+        // southeast to Los Angeles, and northwest back
+        let los_angeles = point(34.0522, -118.2437);
+        assert!((origin.bearing_to(&los_angeles) - 136.50).abs() < 0.01);
+        assert!((los_angeles.bearing_to(&origin) - 318.95).abs() < 0.01);
+
+        // the short way across the antimeridian
+        assert_eq!(point(0.0, 179.5).bearing_to(&point(0.0, -179.5)), 90.0);
+        assert_eq!(point(0.0, -179.5).bearing_to(&point(0.0, 179.5)), 270.0);
+
+        // always in [0, 360), even to the same point
+        assert_eq!(origin.bearing_to(&origin), 0.0);
+    }
+
+    #[test]
+    fn cuts_a_path_between_two_distances() {
+        let path = [
+            point(37.76, -122.48),
+            point(37.77, -122.48),
+            point(37.77, -122.47),
+        ];
+        let distances = cumulative_distances(&path);
+        let corner = distances[1];
+
+        let part = subpath(&path, corner / 2.0, corner + 100.0);
+        assert_eq!(part.len(), 3);
+        assert!((part[0].lat.0 - 37.765).abs() < 1e-9);
+        assert_eq!(part[1], path[1]);
+        assert!((cumulative_distances(&part)[2] - (corner / 2.0 + 100.0)).abs() < 0.01);
+    }
+}
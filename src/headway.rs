@@ -0,0 +1,850 @@
+use error_chain::ChainedError;
+use serde_json;
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+use client;
+use errors::*;
+use geo::LatLon;
+use location::{self, VehicleTime};
+use mapmatch::{self, RouteMatcher};
+use prediction::{self, Prediction, PredictionsList};
+use schedule::{self, Schedule, Timezone};
+use stops::{self, FlatDirection};
+use time;
+
+// A gap below this percentage of the expected headway is reported as bunching
+pub const DEFAULT_BUNCHING_PERCENT: u64 = 50;
+// A gap above this percentage of the expected headway is reported as a gap in service
+pub const DEFAULT_GAP_PERCENT: u64 = 150;
+// The scheduled headway is the median time between the trips scheduled this close to now
+const SCHEDULE_WINDOW_MS: i64 = 60 * 60 * 1000;
+// Vehicles are taken to average this speed, stops included, when the schedule doesn't say
+const DEFAULT_SPEED_KM_HR: f64 = 15.0;
+
+#[derive(Serialize, Debug)]
+struct RouteHeadways {
+    pub route_tag: String,
+    // milliseconds since the Unix epoch when the headways were computed
+    pub epoch: u64,
+    pub directions: Vec<DirectionHeadways>,
+}
+
+#[derive(Serialize, Debug)]
+struct DirectionHeadways {
+    pub direction_tag: String,
+    pub direction_title: String,
+    // the stop whose predicted arrivals the time gaps are measured at
+    pub reference_stop_tag: String,
+    pub reference_stop_title: String,
+    // the headway that time gaps are compared against, and where it came from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_headway_seconds: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_headway_source: Option<HeadwaySource>,
+    // the spacing that distance gaps are compared against: how far a vehicle goes in the
+    // expected headway, at the direction's scheduled speed or else DEFAULT_SPEED_KM_HR
+    pub speed_km_hr: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_spacing_meters: Option<f64>,
+    // gaps between consecutive arrivals at the reference stop, in order of arrival
+    pub time_gaps: Vec<Gap>,
+    // gaps between consecutive vehicles along the route's path, leading vehicle first
+    pub distance_gaps: Vec<Gap>,
+    pub events: Vec<Event>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Gap {
+    pub leading_vehicle: String,
+    pub following_vehicle: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seconds: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meters: Option<f64>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum EventKind {
+    // vehicles far closer together than expected
+    Bunching,
+    // vehicles far further apart than expected
+    Gap,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum GapSource {
+    Predictions,
+    Positions,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum HeadwaySource {
+    // given with --scheduled-headway
+    Given,
+    // the trips scheduled around now at the reference stop, or the timepoint nearest it
+    Schedule,
+    // without a schedule for now, the median of the time gaps
+    ObservedMedian,
+}
+
+#[derive(Serialize, Debug)]
+struct Event {
+    pub kind: EventKind,
+    pub source: GapSource,
+    // the gap as a fraction of the expected headway or spacing
+    pub ratio: f64,
+    #[serde(flatten)]
+    pub gap: Gap,
+}
+
+// What a direction's schedule says about it
+#[derive(Debug, Default, Clone, Copy)]
+struct Scheduled {
+    headway_seconds: Option<f64>,
+    speed_km_hr: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct HeadwayOptions {
+    // stops to measure time gaps at, for the directions that serve them (default: the
+    // middle stop of each direction)
+    pub stops: Vec<String>,
+    // the headway to expect, instead of the one in the route's schedule
+    pub scheduled_headway: Option<Duration>,
+    // the timezone of the route's schedule
+    pub timezone: Timezone,
+    pub bunching_ratio: f64,
+    pub gap_ratio: f64,
+    pub pause_seconds: Option<u64>,
+}
+
+pub fn get_headways(
+    client: &client::Client,
+    agency: String,
+    route: String,
+    options: HeadwayOptions,
+) -> Result<()> {
+    let config = match stops::_get_stops(client, &agency, &route)?.pop() {
+        Some(config) => config,
+        None => return Err(format!("No route config for route {}", route).into()),
+    };
+    let directions: Vec<&FlatDirection> = config
+        .directions
+        .iter()
+        .filter(|d| !d.stops.is_empty())
+        .collect();
+    let reference_stops: Vec<String> = directions
+        .iter()
+        .map(|d| reference_stop(d, &options.stops).tag.clone())
+        .collect();
+    let matcher = RouteMatcher::new(
+        ::std::slice::from_ref(&config),
+        mapmatch::DEFAULT_OFF_ROUTE_METERS as f64,
+    );
+    // for the speed of each direction, even when the headway is given
+    let schedule = client
+        .block_on(schedule::get_schedules_async(
+            client,
+            &agency,
+            ::std::slice::from_ref(&route),
+        ))?
+        .pop()
+        .flatten();
+    let speeds: Vec<Option<f64>> = directions
+        .iter()
+        .map(|d| {
+            let distances = matcher.stop_distances(&config.tag, &d.tag)?;
+            scheduled_speed(schedule.as_ref()?, d, distances)
+        })
+        .collect();
+
+    loop {
+        let vehicles = client
+            .block_on(location::get_locations_async(client, &agency, &route, 0))
+            .map(|l| l.map(|(vehicles, _)| vehicles).unwrap_or_default());
+        let predictions = client.block_on(prediction::get_predictions_async(
+            client,
+            &agency,
+            &route,
+            &reference_stops,
+        ));
+
+        match (vehicles, predictions) {
+            (Ok(vehicles), Ok(predictions)) => {
+                let now = time::now_epoch();
+                let headways = RouteHeadways {
+                    route_tag: config.tag.clone(),
+                    epoch: now,
+                    directions: directions
+                        .iter()
+                        .zip(&speeds)
+                        .map(|(d, &speed_km_hr)| {
+                            let scheduled = Scheduled {
+                                headway_seconds: schedule
+                                    .as_ref()
+                                    .and_then(|s| scheduled_headway(s, d, &options, now as i64)),
+                                speed_km_hr,
+                            };
+                            direction_headways(
+                                d,
+                                &options,
+                                scheduled,
+                                &matcher,
+                                &config.tag,
+                                &vehicles,
+                                &predictions,
+                            )
+                        })
+                        .collect(),
+                };
+                println!("{}", serde_json::to_string(&headways).unwrap());
+            }
+            (Err(e), _) | (_, Err(e)) => warn!(
+                "Download error: {} for route={}",
+                e.display_chain().to_string(),
+                route
+            ),
+        }
+
+        client.report_drift();
+        match options.pause_seconds {
+            None => return Ok(()),
+            Some(s) => thread::sleep(Duration::from_millis(s * 1000)),
+        }
+    }
+}
+
+// The first of the given stops on a direction, or else its middle stop, where vehicles are
+// well spread out after leaving the terminal
+fn reference_stop<'a>(direction: &'a FlatDirection, stops: &[String]) -> &'a stops::Stop {
+    direction
+        .stops
+        .iter()
+        .find(|s| stops.contains(&s.tag))
+        .unwrap_or(&direction.stops[direction.stops.len() / 2])
+}
+
+// The median time between the trips scheduled within an hour of now, at a direction's
+// reference stop, or at the stop nearest it that the schedule lists times for
+fn scheduled_headway(
+    schedule: &Schedule,
+    direction: &FlatDirection,
+    options: &HeadwayOptions,
+    now: i64,
+) -> Option<f64> {
+    let stop_tag = &reference_stop(direction, &options.stops).tag;
+    let timepoint = nearest_timepoint(schedule, direction, stop_tag)?;
+    let timezone = options.timezone;
+    let today = timezone.date(now);
+
+    // service past midnight belongs to the service day before
+    let mut times: Vec<i64> = Vec::new();
+    for &date in &[today.pred_opt()?, today] {
+        let tables = schedule
+            .routes
+            .iter()
+            .filter(|t| serves(t, direction) && schedule::runs_on(schedule, t, date));
+        for table in tables {
+            times.extend(
+                table
+                    .blocks
+                    .iter()
+                    .filter_map(|b| b.stops.iter().find(|s| s.tag == timepoint)?.epoch_time)
+                    .filter_map(|t| timezone.scheduled_epoch(date, t))
+                    .filter(|&t| (t - now).abs() <= SCHEDULE_WINDOW_MS),
+            );
+        }
+    }
+    times.sort_unstable();
+    times.dedup();
+    median(
+        times
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]) as f64 / 1000.0)
+            .collect(),
+    )
+}
+
+// The median speed of a direction's scheduled trips, from their first timepoint to their
+// last, where `distances` are those of the direction's stops along its path
+fn scheduled_speed(
+    schedule: &Schedule,
+    direction: &FlatDirection,
+    distances: &[f64],
+) -> Option<f64> {
+    let speeds = schedule
+        .routes
+        .iter()
+        .filter(|t| serves(t, direction))
+        .flat_map(|t| t.blocks.iter())
+        .filter_map(|b| {
+            let timepoints: Vec<(f64, i64)> = b
+                .stops
+                .iter()
+                .filter_map(|s| {
+                    let index = direction.stops.iter().position(|d| d.tag == s.tag)?;
+                    Some((*distances.get(index)?, s.epoch_time?))
+                })
+                .collect();
+            let (first, last) = (timepoints.first()?, timepoints.last()?);
+            match last.1 > first.1 && last.0 > first.0 {
+                true => {
+                    Some((last.0 - first.0) / 1000.0 / ((last.1 - first.1) as f64 / 3_600_000.0))
+                }
+                false => None,
+            }
+        })
+        .collect();
+    median(speeds)
+}
+
+// Whether a schedule table is for a direction: its stops are all on the direction, in the
+// same order. Terminals are on the tables of both directions, so one stop doesn't tell.
+fn serves(table: &schedule::Route, direction: &FlatDirection) -> bool {
+    let mut stops = direction.stops.iter();
+    table
+        .header
+        .stops
+        .iter()
+        .all(|h| stops.any(|s| s.tag == h.tag))
+}
+
+// The stop of a direction nearest the given one that the schedule lists times for, since
+// schedules only list a few timepoints of each direction
+fn nearest_timepoint<'a>(
+    schedule: &Schedule,
+    direction: &'a FlatDirection,
+    stop_tag: &str,
+) -> Option<&'a str> {
+    let index = direction.stops.iter().position(|s| s.tag == stop_tag)?;
+    let timepoints: HashSet<&str> = schedule
+        .routes
+        .iter()
+        .filter(|t| serves(t, direction))
+        .flat_map(|t| t.header.stops.iter())
+        .map(|s| &s.tag[..])
+        .collect();
+    direction
+        .stops
+        .iter()
+        .enumerate()
+        .filter(|&(_, s)| timepoints.contains(&s.tag[..]))
+        .min_by_key(|&(i, _)| (i as i64 - index as i64).abs())
+        .map(|(_, s)| &s.tag[..])
+}
+
+fn direction_headways(
+    direction: &FlatDirection,
+    options: &HeadwayOptions,
+    scheduled: Scheduled,
+    matcher: &RouteMatcher,
+    route_tag: &str,
+    vehicles: &[VehicleTime],
+    predictions: &Option<PredictionsList>,
+) -> DirectionHeadways {
+    let stop = reference_stop(direction, &options.stops);
+    let time_gaps = time_gaps(direction, &stop.tag, predictions);
+    let distance_gaps = distance_gaps(direction, matcher, route_tag, vehicles);
+
+    let expected_headway = options
+        .scheduled_headway
+        .map(|h| (h.as_secs() as f64, HeadwaySource::Given))
+        .or_else(|| {
+            scheduled
+                .headway_seconds
+                .map(|h| (h, HeadwaySource::Schedule))
+        })
+        .or_else(|| {
+            median(time_gaps.iter().filter_map(|g| g.seconds).collect())
+                .map(|h| (h, HeadwaySource::ObservedMedian))
+        });
+    let expected_headway_seconds = expected_headway.map(|(h, _)| h);
+    let speed_km_hr = scheduled.speed_km_hr.unwrap_or(DEFAULT_SPEED_KM_HR);
+    let expected_spacing_meters = expected_headway_seconds.map(|h| h * speed_km_hr / 3.6);
+
+    let mut events = Vec::new();
+    events.extend(find_events(
+        &time_gaps,
+        GapSource::Predictions,
+        expected_headway_seconds,
+        options,
+    ));
+    events.extend(find_events(
+        &distance_gaps,
+        GapSource::Positions,
+        expected_spacing_meters,
+        options,
+    ));
+
+    DirectionHeadways {
+        direction_tag: direction.tag.clone(),
+        direction_title: direction.title.clone(),
+        reference_stop_tag: stop.tag.clone(),
+        reference_stop_title: stop.title.clone(),
+        expected_headway_seconds,
+        expected_headway_source: expected_headway.map(|(_, source)| source),
+        speed_km_hr,
+        expected_spacing_meters,
+        time_gaps,
+        distance_gaps,
+        events,
+    }
+}
+
+// Gaps between the predicted arrivals of consecutive vehicles at a stop
+fn time_gaps(
+    direction: &FlatDirection,
+    stop_tag: &str,
+    predictions: &Option<PredictionsList>,
+) -> Vec<Gap> {
+    let mut arrivals: Vec<&Prediction> = predictions
+        .iter()
+        .flat_map(|p| p.predictions.iter())
+        .filter(|p| p.stop_tag == stop_tag)
+        .flat_map(|p| p.directions.iter())
+        .flat_map(|d| d.prediction.iter())
+        .filter(|p| p.dir_tag == direction.tag)
+        .collect();
+    arrivals.sort_by_key(|p| p.epoch);
+    // a vehicle on a short loop may be predicted to arrive twice, but only its next arrival
+    // separates it from the vehicles around it
+    let mut seen: Vec<&str> = Vec::new();
+    arrivals.retain(|p| {
+        let first = !seen.contains(&&p.vehicle[..]);
+        seen.push(&p.vehicle);
+        first
+    });
+
+    arrivals
+        .windows(2)
+        .map(|pair| Gap {
+            leading_vehicle: pair[0].vehicle.clone(),
+            following_vehicle: pair[1].vehicle.clone(),
+            seconds: Some((pair[1].epoch - pair[0].epoch) as f64 / 1000.0),
+            meters: None,
+        })
+        .collect()
+}
+
+// Gaps between consecutive vehicles along a direction's path, from their reported positions
+fn distance_gaps(
+    direction: &FlatDirection,
+    matcher: &RouteMatcher,
+    route_tag: &str,
+    vehicles: &[VehicleTime],
+) -> Vec<Gap> {
+    let mut located: Vec<(f64, &VehicleTime)> = vehicles
+        .iter()
+        .filter(|v| v.dir_tag == direction.tag)
+        .filter_map(|v| {
+            let progress = matcher.locate(route_tag, &direction.tag, &LatLon::new(v.lat, v.lon))?;
+            Some((progress.distance_along_meters?, v))
+        })
+        .collect();
+    located.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    located
+        .windows(2)
+        .map(|pair| Gap {
+            leading_vehicle: pair[0].1.id.clone(),
+            following_vehicle: pair[1].1.id.clone(),
+            seconds: None,
+            meters: Some(pair[0].0 - pair[1].0),
+        })
+        .collect()
+}
+
+fn find_events(
+    gaps: &[Gap],
+    source: GapSource,
+    expected: Option<f64>,
+    options: &HeadwayOptions,
+) -> Vec<Event> {
+    let expected = match expected {
+        Some(e) if e > 0.0 => e,
+        _ => return Vec::new(),
+    };
+    gaps.iter()
+        .filter_map(|gap| {
+            let ratio = gap.seconds.or(gap.meters)? / expected;
+            let kind = if ratio < options.bunching_ratio {
+                EventKind::Bunching
+            } else if ratio > options.gap_ratio {
+                EventKind::Gap
+            } else {
+                return None;
+            };
+            Some(Event {
+                kind,
+                source,
+                ratio,
+                gap: gap.clone(),
+            })
+        })
+        .collect()
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    match values.len() % 2 {
+        0 => Some((values[mid - 1] + values[mid]) / 2.0),
+        _ => Some(values[mid]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use geo::Coordinate;
+    use serde_xml_rs::deserialize;
+
+    const SCHEDULE: &str = r#"<body>
+<route tag="N" title="N-Judah" scheduleClass="2026" serviceClass="wkd" direction="Inbound">
+<header><stop tag="5205">Judah St &amp; La Playa St</stop><stop tag="4006">Carl St &amp; Cole St</stop></header>
+<tr blockID="1"><stop tag="5205" epochTime="21600000">06:00:00</stop><stop tag="4006" epochTime="23100000">06:25:00</stop></tr>
+<tr blockID="2"><stop tag="5205" epochTime="22200000">06:10:00</stop><stop tag="4006" epochTime="23700000">06:35:00</stop></tr>
+<tr blockID="3"><stop tag="5205" epochTime="22800000">06:20:00</stop><stop tag="4006" epochTime="24300000">06:45:00</stop></tr>
+<tr blockID="4"><stop tag="5205" epochTime="23520000">06:32:00</stop><stop tag="4006" epochTime="25020000">06:57:00</stop></tr>
+<tr blockID="5"><stop tag="5205" epochTime="28800000">08:00:00</stop><stop tag="4006" epochTime="30300000">08:25:00</stop></tr>
+</route>
+<route tag="N" title="N-Judah" scheduleClass="2026" serviceClass="wkd" direction="Outbound">
+<header><stop tag="4006">Carl St &amp; Cole St</stop><stop tag="5205">Judah St &amp; La Playa St</stop></header>
+<tr blockID="6"><stop tag="4006" epochTime="20700000">05:45:00</stop><stop tag="5205" epochTime="22500000">06:15:00</stop></tr>
+</route>
+</body>"#;
+
+    const PREDICTIONS: &str = r#"<body>
+<predictions agencyTitle="San Francisco Muni" routeTitle="N-Judah" routeTag="N" stopTitle="Judah St &amp; 19th Ave" stopTag="5186">
+<direction title="Inbound to Caltrain via Downtown">
+<prediction epochTime="1792415400000" seconds="600" minutes="10" isDeparture="false" dirTag="N____I_F00" vehicle="1503"/>
+<prediction epochTime="1792414860000" seconds="60" minutes="1" isDeparture="false" dirTag="N____I_F00" vehicle="1501"/>
+<prediction epochTime="1792415040000" seconds="240" minutes="4" isDeparture="false" dirTag="N____I_F00" vehicle="1502"/>
+<prediction epochTime="1792416600000" seconds="1800" minutes="30" isDeparture="false" dirTag="N____I_F00" vehicle="1501"/>
+<prediction epochTime="1792414920000" seconds="120" minutes="2" isDeparture="false" dirTag="N____O_F00" vehicle="1504"/>
+</direction>
+</predictions>
+<predictions agencyTitle="San Francisco Muni" routeTitle="N-Judah" routeTag="N" stopTitle="Carl St &amp; Cole St" stopTag="4006">
+<direction title="Inbound to Caltrain via Downtown">
+<prediction epochTime="1792414980000" seconds="180" minutes="3" isDeparture="false" dirTag="N____I_F00" vehicle="1505"/>
+</direction>
+</predictions>
+</body>"#;
+
+    fn stop(tag: &str, lat: f64) -> stops::Stop {
+        stops::Stop {
+            tag: String::from(tag),
+            title: String::new(),
+            lat: Coordinate(lat),
+            lon: Coordinate(-122.48),
+            stop_id: None,
+        }
+    }
+
+    // Three stops a little over a kilometre apart, running north
+    fn inbound() -> FlatDirection {
+        FlatDirection {
+            tag: String::from("N____I_F00"),
+            title: String::from("Inbound to Caltrain via Downtown"),
+            name: String::from("Inbound"),
+            use_for_ui: true,
+            stops: vec![
+                stop("5205", 37.76),
+                stop("5186", 37.77),
+                stop("4006", 37.78),
+            ],
+        }
+    }
+
+    // The inbound stops, and a path that detours a kilometre east between the last two
+    fn matcher() -> RouteMatcher {
+        let point = |lat: f64, lon: f64| LatLon::new(Coordinate(lat), Coordinate(lon));
+        let outbound = FlatDirection {
+            tag: String::from("N____O_F00"),
+            title: String::from("Outbound to Ocean Beach"),
+            name: String::from("Outbound"),
+            use_for_ui: true,
+            stops: vec![stop("4006", 37.78), stop("5205", 37.76)],
+        };
+        let route = stops::FlatRoute {
+            tag: String::from("N"),
+            title: String::from("N-Judah"),
+            lat_min: Coordinate(37.76),
+            lat_max: Coordinate(37.78),
+            lon_min: Coordinate(-122.48),
+            lon_max: Coordinate(-122.46),
+            directions: vec![inbound(), outbound],
+            paths: vec![vec![
+                point(37.76, -122.48),
+                point(37.77, -122.48),
+                point(37.77, -122.4686),
+                point(37.78, -122.4686),
+                point(37.78, -122.48),
+            ]],
+        };
+        RouteMatcher::new(&[route], 100.0)
+    }
+
+    fn vehicle(id: &str, dir_tag: &str, lat: f64, lon: f64) -> VehicleTime {
+        VehicleTime {
+            id: String::from(id),
+            route_tag: String::from("N"),
+            dir_tag: String::from(dir_tag),
+            lat: Coordinate(lat),
+            lon: Coordinate(lon),
+            epoch: 1792414800000,
+            predictable: true,
+            heading: 0,
+            speed_km_hr: 0,
+            leading_vehicle_id: String::new(),
+            removed: false,
+            progress: None,
+            heading_source: None,
+            speed_source: None,
+        }
+    }
+
+    fn gap(seconds: Option<f64>, meters: Option<f64>) -> Gap {
+        Gap {
+            leading_vehicle: String::from("1501"),
+            following_vehicle: String::from("1502"),
+            seconds,
+            meters,
+        }
+    }
+
+    fn options() -> HeadwayOptions {
+        HeadwayOptions {
+            stops: vec![String::from("5186")],
+            scheduled_headway: None,
+            timezone: "America/Los_Angeles".parse().unwrap(),
+            bunching_ratio: 0.5,
+            gap_ratio: 1.5,
+            pause_seconds: None,
+        }
+    }
+
+    fn at(date: NaiveDate, time: &str) -> i64 {
+        options()
+            .timezone
+            .scheduled_epoch(date, schedule::parse_time(time).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn takes_headways_from_the_schedule() {
+        let schedule: Schedule = deserialize(SCHEDULE.as_bytes()).unwrap();
+        let monday = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        let direction = inbound();
+
+        // at the timepoint nearest the reference stop, only on the inbound table
+        assert_eq!(
+            nearest_timepoint(&schedule, &direction, "5186"),
+            Some("5205")
+        );
+        assert!(serves(&schedule.routes[0], &direction));
+        assert!(!serves(&schedule.routes[1], &direction));
+
+        // 06:00, 06:10, 06:20 and 06:32 are within an hour of 06:15, but 08:00 isn't
+        let headway = scheduled_headway(&schedule, &direction, &options(), at(monday, "06:15"));
+        assert_eq!(headway, Some(600.0));
+        // only the 08:00 trip is left
+        let headway = scheduled_headway(&schedule, &direction, &options(), at(monday, "08:30"));
+        assert_eq!(headway, None);
+        // and none run on Saturdays
+        let saturday = NaiveDate::from_ymd_opt(2026, 10, 24).unwrap();
+        let headway = scheduled_headway(&schedule, &direction, &options(), at(saturday, "06:15"));
+        assert_eq!(headway, None);
+    }
+
+    #[test]
+    fn measures_the_scheduled_speed_along_the_path() {
+        let schedule: Schedule = deserialize(SCHEDULE.as_bytes()).unwrap();
+        let matcher = matcher();
+        let distances = matcher.stop_distances("N", "N____I_F00").unwrap();
+        // 5205 to 4006 is 4.2km along the path, in 25 minutes
+        let speed = scheduled_speed(&schedule, &inbound(), distances).unwrap();
+        assert!((speed - 10.1).abs() < 0.1, "{}", speed);
+
+        // the outbound table isn't for the inbound direction, and has no timepoints on it
+        let outbound = FlatDirection {
+            tag: String::from("N____I_F01"),
+            name: String::from("Elsewhere"),
+            ..inbound()
+        };
+        assert_eq!(scheduled_speed(&schedule, &outbound, &[]), None);
+    }
+
+    #[test]
+    fn measures_time_gaps_between_arrivals() {
+        let predictions: PredictionsList = deserialize(PREDICTIONS.as_bytes()).unwrap();
+        let gaps = time_gaps(&inbound(), "5186", &Some(predictions));
+
+        // in order of arrival, only at the reference stop and in the direction, and only
+        // the next arrival of 1501
+        let pairs: Vec<(&str, &str, Option<f64>)> = gaps
+            .iter()
+            .map(|g| (&g.leading_vehicle[..], &g.following_vehicle[..], g.seconds))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![("1501", "1502", Some(180.0)), ("1502", "1503", Some(360.0))]
+        );
+        assert!(gaps.iter().all(|g| g.meters.is_none()));
+
+        assert!(time_gaps(&inbound(), "5186", &None).is_empty());
+    }
+
+    #[test]
+    fn measures_distance_gaps_along_the_path() {
+        let vehicles = vec![
+            // on the path's detour east, 1.1km + 500m along
+            vehicle("1502", "N____I_F00", 37.77, -122.4743),
+            // 1.1km + 1km + 1.1km + 500m along
+            vehicle("1501", "N____I_F00", 37.78, -122.4743),
+            // at the first stop
+            vehicle("1503", "N____I_F00", 37.76, -122.48),
+            vehicle("1504", "N____O_F00", 37.775, -122.48),
+        ];
+        let gaps = distance_gaps(&inbound(), &matcher(), "N", &vehicles);
+
+        // leading vehicle first, measured around the detour rather than between the stops
+        let pairs: Vec<(&str, &str)> = gaps
+            .iter()
+            .map(|g| (&g.leading_vehicle[..], &g.following_vehicle[..]))
+            .collect();
+        assert_eq!(pairs, vec![("1501", "1502"), ("1502", "1503")]);
+        let meters: Vec<f64> = gaps.iter().filter_map(|g| g.meters).collect();
+        assert!((meters[0] - 2112.0).abs() < 20.0, "{:?}", meters);
+        assert!((meters[1] - 1612.0).abs() < 20.0, "{:?}", meters);
+        assert!(gaps.iter().all(|g| g.seconds.is_none()));
+
+        // a route the matcher doesn't know has no positions along it
+        assert!(distance_gaps(&inbound(), &matcher(), "J", &vehicles).is_empty());
+    }
+
+    #[test]
+    fn finds_gaps_far_from_the_expected_headway() {
+        let gaps = vec![
+            gap(Some(200.0), None),
+            gap(Some(300.0), None),
+            gap(Some(600.0), None),
+            gap(Some(900.0), None),
+            gap(Some(1000.0), None),
+        ];
+        let events = find_events(&gaps, GapSource::Predictions, Some(600.0), &options());
+        // below half and above one and a half times the headway, but not at either
+        let kinds: Vec<(EventKind, f64)> = events
+            .iter()
+            .map(|e| (e.kind, e.gap.seconds.unwrap()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![(EventKind::Bunching, 200.0), (EventKind::Gap, 1000.0)]
+        );
+        assert!(events.iter().all(|e| e.source == GapSource::Predictions));
+        assert!((events[0].ratio - 1.0 / 3.0).abs() < 1e-9);
+
+        // distance gaps are compared by their meters
+        let gaps = vec![gap(None, Some(500.0))];
+        let events = find_events(&gaps, GapSource::Positions, Some(2500.0), &options());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Bunching);
+
+        // without an expected headway, nothing is far from it
+        assert!(find_events(&gaps, GapSource::Positions, None, &options()).is_empty());
+        assert!(find_events(&gaps, GapSource::Positions, Some(0.0), &options()).is_empty());
+    }
+
+    #[test]
+    fn expects_the_spacing_covered_in_a_headway() {
+        let matcher = matcher();
+        let vehicles = vec![
+            vehicle("1501", "N____I_F00", 37.78, -122.4743),
+            vehicle("1502", "N____I_F00", 37.779, -122.4686),
+        ];
+        let scheduled = Scheduled {
+            headway_seconds: Some(600.0),
+            speed_km_hr: Some(12.0),
+        };
+        let headways = direction_headways(
+            &inbound(),
+            &options(),
+            scheduled,
+            &matcher,
+            "N",
+            &vehicles,
+            &None,
+        );
+        // ten minutes at 12km/h
+        assert_eq!(headways.speed_km_hr, 12.0);
+        assert!((headways.expected_spacing_meters.unwrap() - 2000.0).abs() < 1e-6);
+        // the two vehicles are about 600m apart along the path
+        assert_eq!(headways.events.len(), 1);
+        assert_eq!(headways.events[0].kind, EventKind::Bunching);
+        assert_eq!(headways.events[0].source, GapSource::Positions);
+
+        // at the default speed without one from the schedule
+        let scheduled = Scheduled {
+            speed_km_hr: None,
+            ..scheduled
+        };
+        let headways = direction_headways(
+            &inbound(),
+            &options(),
+            scheduled,
+            &matcher,
+            "N",
+            &vehicles,
+            &None,
+        );
+        assert_eq!(headways.speed_km_hr, DEFAULT_SPEED_KM_HR);
+        assert!((headways.expected_spacing_meters.unwrap() - 2500.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn labels_where_the_expected_headway_came_from() {
+        let direction = inbound();
+        let matcher = matcher();
+        let scheduled = Scheduled {
+            headway_seconds: Some(600.0),
+            speed_km_hr: None,
+        };
+        let headways =
+            direction_headways(&direction, &options(), scheduled, &matcher, "N", &[], &None);
+        assert_eq!(headways.expected_headway_seconds, Some(600.0));
+        assert_eq!(
+            headways.expected_headway_source,
+            Some(HeadwaySource::Schedule)
+        );
+
+        let given = HeadwayOptions {
+            scheduled_headway: Some(Duration::from_secs(300)),
+            ..options()
+        };
+        let headways = direction_headways(&direction, &given, scheduled, &matcher, "N", &[], &None);
+        assert_eq!(headways.expected_headway_seconds, Some(300.0));
+        assert_eq!(headways.expected_headway_source, Some(HeadwaySource::Given));
+
+        // without a schedule or any arrivals, there is nothing to expect
+        let headways = direction_headways(
+            &direction,
+            &options(),
+            Scheduled::default(),
+            &matcher,
+            "N",
+            &[],
+            &None,
+        );
+        assert_eq!(headways.expected_headway_source, None);
+    }
+}
@@ -0,0 +1,612 @@
+use serde::de::value::{Error as ValueError, StringDeserializer};
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, Deserializer, IntoDeserializer, MapAccess, SeqAccess,
+    Visitor,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::mem;
+use std::result::Result as StdResult;
+use std::sync::Mutex;
+use xml::reader::{EventReader, XmlEvent};
+
+use errors::*;
+use xmlstream::{AttributeValue, Element};
+
+// What lenient parsing has had to work around so far in a run: records that were skipped
+// because they failed to parse, and attributes the structs don't have fields for. Each
+// unknown attribute is only reported the first time it's seen.
+#[derive(Debug, Default)]
+pub struct SchemaDrift {
+    // number of records skipped, by element name
+    skipped: Mutex<HashMap<String, usize>>,
+    // (element, attribute) pairs already reported
+    unknown: Mutex<HashSet<(String, String)>>,
+    unsummarized: Mutex<Unsummarized>,
+}
+
+// What hasn't been summarized yet
+#[derive(Debug, Default)]
+struct Unsummarized {
+    // records skipped, and the last error, by element name
+    skipped: BTreeMap<String, (usize, String)>,
+    // (element, attribute) pairs first seen
+    unknown: Vec<(String, String)>,
+}
+
+impl SchemaDrift {
+    fn skipped(&self, element: &str, error: &str) {
+        let mut skipped = self.skipped.lock().unwrap();
+        let count = skipped.entry(String::from(element)).or_insert(0);
+        *count += 1;
+        warn!(
+            "Skipped a <{}> that failed to parse ({} skipped so far): {}",
+            element, count, error
+        );
+
+        let mut unsummarized = self.unsummarized.lock().unwrap();
+        let entry = unsummarized
+            .skipped
+            .entry(String::from(element))
+            .or_insert((0, String::new()));
+        entry.0 += 1;
+        entry.1 = String::from(error);
+    }
+
+    fn unknown_attribute(&self, element: &str, attribute: &str) {
+        let key = (String::from(element), String::from(attribute));
+        if self.unknown.lock().unwrap().insert(key.clone()) {
+            warn!(
+                "Unknown attribute '{}' on <{}> is ignored",
+                attribute, element
+            );
+            self.unsummarized.lock().unwrap().unknown.push(key);
+        }
+    }
+
+    // The lines to print about what has been skipped and ignored since the last summary, so
+    // that drift is seen whatever the log level. Empty when there's been none.
+    pub fn summary(&self) -> Vec<String> {
+        let Unsummarized { skipped, unknown } = mem::take(&mut *self.unsummarized.lock().unwrap());
+        let skipped = skipped.into_iter().map(|(element, (count, error))| {
+            format!(
+                "Schema drift: skipped {} <{}> that failed to parse, the last with {}",
+                count, element, error
+            )
+        });
+        let unknown = unknown.into_iter().map(|(element, attribute)| {
+            format!(
+                "Schema drift: ignored unknown attribute '{}' on <{}>",
+                attribute, element
+            )
+        });
+        skipped.chain(unknown).collect()
+    }
+}
+
+// Deserialize a response, skipping any record that fails to parse rather than failing the
+// whole response. A record is any element that's one of a list, e.g. a <vehicle>,
+// <prediction> or <stop>, and the innermost failing one is removed from the response
+// before trying again, so a bad <prediction> only loses that prediction.
+pub fn deserialize<T: DeserializeOwned>(body: &str, drift: &SchemaDrift) -> Result<T> {
+    let mut root = parse_tree(body)?;
+    loop {
+        let context = Context::default();
+        let result = T::deserialize(NodeDeserializer {
+            node: &root,
+            path: Vec::new(),
+            root: true,
+            drift,
+            context: &context,
+        });
+        match (result, context.failed.into_inner()) {
+            (Ok(parsed), _) => return Ok(parsed),
+            (Err(_), Some(failure)) => {
+                drift.skipped(&failure.element, &failure.message);
+                remove(&mut root, &failure.path);
+            }
+            (Err(e), None) => return Err(e.to_string().into()),
+        }
+    }
+}
+
+// Deserialize a single streamed element, or None if it failed to parse
+pub fn deserialize_element<T: DeserializeOwned>(
+    element: Element,
+    drift: &SchemaDrift,
+) -> Option<T> {
+    let node = Node {
+        name: element.name,
+        attributes: element.attributes,
+        children: Vec::new(),
+        text: String::new(),
+    };
+    let context = Context::default();
+    let result = T::deserialize(NodeDeserializer {
+        node: &node,
+        path: Vec::new(),
+        root: false,
+        drift,
+        context: &context,
+    });
+    match result {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            drift.skipped(&node.name, &e.to_string());
+            None
+        }
+    }
+}
+
+// An element of a response, held in memory so that it can be deserialized again once a
+// record that failed has been removed
+#[derive(Debug, Default)]
+struct Node {
+    name: String,
+    attributes: Vec<(String, String)>,
+    children: Vec<Node>,
+    text: String,
+}
+
+fn parse_tree(body: &str) -> Result<Node> {
+    // the bottom of the stack is the document, holding the root element
+    let mut stack = vec![Node::default()];
+    for event in EventReader::new(body.as_bytes()).into_iter() {
+        match event? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => stack.push(Node {
+                name: name.local_name,
+                attributes: attributes
+                    .into_iter()
+                    .map(|a| (a.name.local_name, a.value))
+                    .collect(),
+                ..Node::default()
+            }),
+            XmlEvent::EndElement { .. } if stack.len() > 1 => {
+                let node = stack.pop().unwrap();
+                stack.last_mut().unwrap().children.push(node);
+            }
+            XmlEvent::Characters(s) | XmlEvent::CData(s) => {
+                stack.last_mut().unwrap().text.push_str(&s)
+            }
+            _ => (),
+        }
+    }
+    stack
+        .pop()
+        .and_then(|document| document.children.into_iter().next())
+        .ok_or_else(|| "Response has no root element.".into())
+}
+
+// Remove the element at a path of child indexes from the root
+fn remove(root: &mut Node, path: &[usize]) {
+    let (last, parents) = match path.split_last() {
+        Some(split) => split,
+        None => return,
+    };
+    let parent = parents.iter().fold(root, |node, &i| &mut node.children[i]);
+    parent.children.remove(*last);
+}
+
+// The innermost record that failed to parse in one attempt
+#[derive(Debug, Default)]
+struct Context {
+    failed: RefCell<Option<Failure>>,
+}
+
+#[derive(Debug)]
+struct Failure {
+    path: Vec<usize>,
+    element: String,
+    message: String,
+}
+
+impl Context {
+    // Records fail from the inside out, so the first failure is the innermost
+    fn fail(&self, path: Vec<usize>, element: &str, error: &ValueError) {
+        let mut failed = self.failed.borrow_mut();
+        if failed.is_none() {
+            *failed = Some(Failure {
+                path,
+                element: String::from(element),
+                message: error.to_string(),
+            });
+        }
+    }
+}
+
+// Deserializes an element as serde_xml_rs does: a struct's fields are the element's
+// attributes and child elements, with lists of children for Vec fields, and its text as a
+// "$value" field
+struct NodeDeserializer<'a> {
+    node: &'a Node,
+    // child indexes from the root to the node
+    path: Vec<usize>,
+    // the root element is the response's <body>, whose attributes aren't worth reporting
+    root: bool,
+    drift: &'a SchemaDrift,
+    context: &'a Context,
+}
+
+impl<'a> NodeDeserializer<'a> {
+    fn map(self, fields: &[&str]) -> NodeMap<'a> {
+        let node = self.node;
+        let mut entries: VecDeque<(String, Entry<'a>)> = node
+            .attributes
+            .iter()
+            .map(|(k, v)| (k.clone(), Entry::Text(v.clone())))
+            .collect();
+        if fields.contains(&"$value") {
+            entries.push_back((String::from("$value"), Entry::Text(node.text.clone())));
+        }
+        // children with the same name are grouped into one entry, in order of appearance
+        let mut groups: Vec<(String, Vec<(usize, &'a Node)>)> = Vec::new();
+        for (i, child) in node.children.iter().enumerate() {
+            match groups.iter_mut().find(|(name, _)| *name == child.name) {
+                Some((_, group)) => group.push((i, child)),
+                None => groups.push((child.name.clone(), vec![(i, child)])),
+            }
+        }
+        entries.extend(
+            groups
+                .into_iter()
+                .map(|(name, children)| (name, Entry::Children(children))),
+        );
+        NodeMap {
+            entries,
+            value: None,
+            path: self.path,
+            drift: self.drift,
+            context: self.context,
+        }
+    }
+}
+
+impl<'de, 'a> Deserializer<'de> for NodeDeserializer<'a> {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> StdResult<V::Value, ValueError> {
+        match self.node.attributes.is_empty() && self.node.children.is_empty() {
+            true => visitor.visit_string(self.node.text.clone()),
+            false => visitor.visit_map(self.map(&[])),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> StdResult<V::Value, ValueError> {
+        if !self.root {
+            for (attribute, _) in &self.node.attributes {
+                if !fields.contains(&&attribute[..]) {
+                    self.drift.unknown_attribute(&self.node.name, attribute);
+                }
+            }
+        }
+        visitor.visit_map(self.map(fields))
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> StdResult<V::Value, ValueError> {
+        visitor.visit_map(self.map(&[]))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> StdResult<V::Value, ValueError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> StdResult<V::Value, ValueError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> StdResult<V::Value, ValueError> {
+        let text: StringDeserializer<ValueError> = self.node.text.clone().into_deserializer();
+        text.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> StdResult<V::Value, ValueError> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit unit_struct seq tuple tuple_struct identifier
+    }
+}
+
+enum Entry<'a> {
+    Text(String),
+    // child elements with the same name, with their indexes in the parent
+    Children(Vec<(usize, &'a Node)>),
+}
+
+struct NodeMap<'a> {
+    entries: VecDeque<(String, Entry<'a>)>,
+    value: Option<(String, Entry<'a>)>,
+    path: Vec<usize>,
+    drift: &'a SchemaDrift,
+    context: &'a Context,
+}
+
+impl<'de, 'a> MapAccess<'de> for NodeMap<'a> {
+    type Error = ValueError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> StdResult<Option<K::Value>, ValueError> {
+        match self.entries.pop_front() {
+            Some((key, entry)) => {
+                let key_deserializer: StringDeserializer<ValueError> =
+                    key.clone().into_deserializer();
+                self.value = Some((key, entry));
+                seed.deserialize(key_deserializer).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> StdResult<V::Value, ValueError> {
+        let (key, entry) = self
+            .value
+            .take()
+            .ok_or_else(|| de::Error::custom("value requested before its key"))?;
+        let value = match entry {
+            Entry::Text(text) => seed.deserialize(AttributeValue(text)),
+            Entry::Children(nodes) => seed.deserialize(ChildrenDeserializer {
+                nodes,
+                path: self.path.clone(),
+                drift: self.drift,
+                context: self.context,
+            }),
+        };
+        // name the field that failed, e.g. "lat: invalid float literal"
+        value.map_err(|e| de::Error::custom(format!("{}: {}", key, e)))
+    }
+}
+
+// Child elements of the same name, which are a list of records for a Vec field, or one
+// element for any other field
+struct ChildrenDeserializer<'a> {
+    nodes: Vec<(usize, &'a Node)>,
+    // of the parent
+    path: Vec<usize>,
+    drift: &'a SchemaDrift,
+    context: &'a Context,
+}
+
+impl<'a> ChildrenDeserializer<'a> {
+    fn first(self) -> NodeDeserializer<'a> {
+        let (i, node) = self.nodes[0];
+        let mut path = self.path;
+        path.push(i);
+        NodeDeserializer {
+            node,
+            path,
+            root: false,
+            drift: self.drift,
+            context: self.context,
+        }
+    }
+}
+
+impl<'de, 'a> Deserializer<'de> for ChildrenDeserializer<'a> {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> StdResult<V::Value, ValueError> {
+        self.first().deserialize_any(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> StdResult<V::Value, ValueError> {
+        visitor.visit_seq(Records {
+            nodes: self.nodes.into_iter(),
+            path: self.path,
+            drift: self.drift,
+            context: self.context,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> StdResult<V::Value, ValueError> {
+        self.first().deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> StdResult<V::Value, ValueError> {
+        self.first().deserialize_map(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> StdResult<V::Value, ValueError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> StdResult<V::Value, ValueError> {
+        self.first().deserialize_newtype_struct(name, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> StdResult<V::Value, ValueError> {
+        self.first().deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> StdResult<V::Value, ValueError> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit unit_struct tuple tuple_struct identifier
+    }
+}
+
+// The records of a list, which note where they are when they fail to parse so that they
+// can be removed
+struct Records<'a> {
+    nodes: ::std::vec::IntoIter<(usize, &'a Node)>,
+    path: Vec<usize>,
+    drift: &'a SchemaDrift,
+    context: &'a Context,
+}
+
+impl<'de, 'a> SeqAccess<'de> for Records<'a> {
+    type Error = ValueError;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> StdResult<Option<S::Value>, ValueError> {
+        let (i, node) = match self.nodes.next() {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+        let mut path = self.path.clone();
+        path.push(i);
+        let record = NodeDeserializer {
+            node,
+            path: path.clone(),
+            root: false,
+            drift: self.drift,
+            context: self.context,
+        };
+        seed.deserialize(record)
+            .map(Some)
+            .inspect_err(|e| self.context.fail(path, &node.name, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::from_string;
+
+    #[derive(Deserialize, Debug)]
+    struct Body {
+        #[serde(rename = "route")]
+        routes: Vec<Route>,
+        #[serde(rename = "lastTime")]
+        last_time: LastTime,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Route {
+        tag: String,
+        #[serde(rename = "stop")]
+        stops: Vec<Stop>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Stop {
+        tag: String,
+        #[serde(deserialize_with = "from_string")]
+        lat: f64,
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct LastTime {
+        #[serde(deserialize_with = "from_string")]
+        time: u64,
+    }
+
+    #[test]
+    fn skips_the_innermost_record_that_fails() {
+        let drift = SchemaDrift::default();
+        let body: Body = deserialize(
+            r#"<body copyright="x">
+                <route tag="1"><stop tag="a" lat="1.5"/><stop tag="b" lat=""/></route>
+                <route tag="2"><stop tag="c" lat="x"/></route>
+                <route tag="3"><stop tag="d" lat="2.5" stopId="4"/></route>
+                <lastTime time="10"/>
+            </body>"#,
+            &drift,
+        )
+        .unwrap();
+
+        let tags: Vec<&str> = body.routes.iter().map(|r| &r.tag[..]).collect();
+        // route 2 has no stops left once its only stop is skipped, so it's skipped too
+        assert_eq!(tags, vec!["1", "3"]);
+        assert_eq!(body.routes[0].stops.len(), 1);
+        assert_eq!(body.routes[0].stops[0].tag, "a");
+        assert_eq!(body.routes[1].stops[0].lat, 2.5);
+        assert_eq!(body.last_time.time, 10);
+
+        let skipped = drift.skipped.lock().unwrap();
+        assert_eq!(skipped["stop"], 2);
+        assert_eq!(skipped["route"], 1);
+        let unknown = drift.unknown.lock().unwrap();
+        assert!(unknown.contains(&(String::from("stop"), String::from("stopId"))));
+        // the <body> envelope isn't reported
+        assert_eq!(unknown.len(), 1);
+
+        assert_eq!(
+            drift.summary(),
+            vec![
+                "Schema drift: skipped 1 <route> that failed to parse, the last with missing \
+                 field `stop`",
+                "Schema drift: skipped 2 <stop> that failed to parse, the last with lat: \
+                 invalid float literal",
+                "Schema drift: ignored unknown attribute 'stopId' on <stop>",
+            ]
+        );
+        // only what's new is summarized again
+        assert!(drift.summary().is_empty());
+    }
+
+    #[test]
+    fn fails_when_a_required_element_fails() {
+        let drift = SchemaDrift::default();
+        let body: Result<Body> = deserialize(
+            r#"<body><route tag="1"><stop tag="a" lat="1"/></route><lastTime time=""/></body>"#,
+            &drift,
+        );
+        assert!(body.is_err());
+    }
+
+    #[test]
+    fn skips_streamed_elements_that_fail() {
+        let drift = SchemaDrift::default();
+        let element = |lat: &str| Element {
+            name: String::from("stop"),
+            depth: 3,
+            attributes: vec![
+                (String::from("tag"), String::from("a")),
+                (String::from("lat"), String::from(lat)),
+            ],
+        };
+        let stop: Option<Stop> = deserialize_element(element("1.5"), &drift);
+        assert_eq!(stop.unwrap().lat, 1.5);
+        let stop: Option<Stop> = deserialize_element(element("north"), &drift);
+        assert!(stop.is_none());
+        assert_eq!(drift.skipped.lock().unwrap()["stop"], 1);
+    }
+}
@@ -1,11 +1,20 @@
 use error_chain::ChainedError;
+use futures::Future;
 
 use serde_json;
+use std::collections::HashMap;
 use std::thread;
 use std::time::Duration;
 
 use client;
 use errors::*;
+use geo::{Area, Coordinate, LatLon};
+use mapmatch::{RouteMatcher, RouteProgress};
+use time;
+use xmlstream;
+
+// Faster than any vehicle in the feed, so a report that would need more is a GPS jump
+pub const DEFAULT_MAX_SPEED_KM_HR: u64 = 130;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -16,9 +25,9 @@ struct Vehicle {
     #[serde(default)]
     pub dir_tag: String,
     #[serde(deserialize_with = "client::from_string")]
-    pub lat: f32,
+    pub lat: Coordinate,
     #[serde(deserialize_with = "client::from_string")]
-    pub lon: f32,
+    pub lon: Coordinate,
     #[serde(deserialize_with = "client::from_string")]
     pub secs_since_report: u32,
     #[serde(deserialize_with = "client::from_string")]
@@ -47,88 +56,692 @@ struct Locations {
     pub updated_time: LastTime,
 }
 
-#[derive(Serialize, Debug)]
-struct VehicleTime {
+#[derive(Serialize, Debug, Clone)]
+pub struct VehicleTime {
     pub id: String,
     pub route_tag: String,
     pub dir_tag: String,
-    pub lat: f32,
-    pub lon: f32,
+    pub lat: Coordinate,
+    pub lon: Coordinate,
     pub epoch: u64,
     pub predictable: bool,
     pub heading: i16,
     pub speed_km_hr: u32,
     pub leading_vehicle_id: String,
+    // set on a vehicle's last known state once it has stopped reporting
+    #[serde(skip_serializing_if = "is_false")]
+    pub removed: bool,
+    // where the vehicle is along its route, when locations are matched to route configs
+    #[serde(flatten)]
+    pub progress: Option<RouteProgress>,
+    // whether heading and speed are as reported, or derived from the vehicle's last position
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heading_source: Option<MotionSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_source: Option<MotionSource>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MotionSource {
+    Reported,
+    Derived,
 }
 
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+// A response without vehicles still has the lastTime to request updates from next, and to
+// tell how long vehicles have been gone for
 impl client::Contents for Locations {
     fn is_empty(&self) -> bool {
-        self.vehicles.len() == 0
+        false
     }
 }
 
 fn get_locations_url(agency: &String, route: &String, epoch: &u64) -> String {
     format!(
-        "https://retro.umoiq.com/service/publicXMLFeed?command=vehicleLocations&a={agency}&r={route}&t={epoch:?}",
+        "{feed}?command=vehicleLocations&a={agency}&r={route}&t={epoch:?}",
+        feed = client::DEFAULT_FEED_URL,
         agency = agency,
         epoch = epoch,
         route = route,
     )
 }
 
-pub fn get_locations(agency: String, route: String, pause_seconds: Option<u64>) -> Result<()> {
+#[derive(Debug, Default)]
+pub struct LocationOptions {
+    pub pause_seconds: Option<u64>,
+    // print one vehicle per line instead of one array per response, parsing the response as
+    // it arrives instead of reading it whole
+    pub stream: bool,
+    // only print vehicles whose position, heading, direction or report time has changed
+    pub changes_only: bool,
+    // with changes_only, report a vehicle as removed once it hasn't reported for this long
+    pub removed_after: Option<Duration>,
+    // only print vehicles inside every one of these areas
+    pub areas: Vec<Area>,
+    // match each vehicle to its route's path, reporting its progress along it
+    pub matcher: Option<RouteMatcher>,
+    // derive missing headings and speeds from each vehicle's last two reports, dropping
+    // reports that would need the vehicle to move faster than max_speed_km_hr
+    pub derive_motion: bool,
+    pub max_speed_km_hr: f64,
+}
+
+pub fn get_locations(
+    client: &client::Client,
+    agency: String,
+    route: String,
+    options: LocationOptions,
+) -> Result<()> {
     let mut epoch = 0;
+    let mut tracker = VehicleTracker::new(options.changes_only, options.removed_after);
+    let mut motion = MotionTracker::new(options.derive_motion, options.max_speed_km_hr);
 
     loop {
         let url = get_locations_url(&agency, &route, &epoch);
-        let downloaded: Option<Locations> = client::download(&url).unwrap_or_else(|e| {
-            warn!(
-                "Download error: {} from URL={}",
-                e.display_chain().to_string(),
-                url
+
+        if options.stream {
+            let matcher = options.matcher.as_ref();
+            let streamed = stream_locations(
+                client,
+                &url,
+                &options.areas,
+                matcher,
+                &mut |vehicle, seen_at| match motion.update(vehicle) {
+                    Some(vehicle) if tracker.has_changed(&vehicle, seen_at) => {
+                        println!("{}", serde_json::to_string(&vehicle).unwrap())
+                    }
+                    _ => (),
+                },
             );
-            None
-        });
+            match streamed {
+                Ok(Some(updated_time)) => epoch = updated_time,
+                Ok(None) => (),
+                Err(e) => warn!(
+                    "Download error: {} from URL={}",
+                    e.display_chain().to_string(),
+                    url
+                ),
+            }
+            for removed in tracker.expire(epoch) {
+                println!("{}", serde_json::to_string(&removed).unwrap());
+            }
+        } else {
+            let downloaded: Option<Locations> =
+                client::download(client, &url).unwrap_or_else(|e| {
+                    warn!(
+                        "Download error: {} from URL={}",
+                        e.display_chain().to_string(),
+                        url
+                    );
+                    None
+                });
 
-        match downloaded {
             // a successful response may contain no locations if there are no vehicles, or
-            // if there are no updates to vehicle locations since the last given epoch
-            Some(locations) => {
-                let locations_json;
-                (locations_json, epoch) = parse_locations(locations);
-                println!("{}", locations_json);
+            // if there are no updates to vehicle locations since the last given epoch, but
+            // its lastTime still moves the epoch on, so vehicles that have gone are removed
+            let mut location_times = Vec::new();
+            if let Some(locations) = downloaded {
+                let all_times;
+                (all_times, epoch) =
+                    parse_locations(locations, &options.areas, options.matcher.as_ref());
+                location_times.extend(
+                    all_times
+                        .into_iter()
+                        .filter_map(|v| motion.update(v))
+                        .filter(|v| tracker.has_changed(v, epoch)),
+                );
             }
-            None => (),
-        };
+            location_times.extend(tracker.expire(epoch));
+
+            if !location_times.is_empty() {
+                println!("{}", serde_json::to_string(&location_times).unwrap());
+            }
+        }
 
-        match pause_seconds {
+        client.report_drift();
+        match options.pause_seconds {
             None => return Ok(()),
             Some(s) => thread::sleep(Duration::from_millis(s * 1000)),
         }
     }
 }
 
-fn parse_locations(locations: Locations) -> (String, u64) {
+// Async equivalent of a single locations request, returning the vehicle locations along with
+// the epoch to request updates from next
+pub fn get_locations_async(
+    client: &client::Client,
+    agency: &String,
+    route: &String,
+    epoch: u64,
+) -> impl Future<Item = Option<(Vec<VehicleTime>, u64)>, Error = Error> + Send {
+    client::download_async(client, get_locations_url(agency, route, &epoch))
+        .map(|locations: Option<Locations>| locations.map(|l| parse_locations(l, &[], None)))
+}
+
+// Parse the response as it arrives, passing each vehicle to `on_vehicle` along with the
+// response's time, and return the feed's lastTime to request updates from next. lastTime
+// comes after all of the vehicles in the response, and report times are relative to it, so
+// the parsed vehicles are held until it arrives; only they are kept in memory, not the
+// whole response.
+fn stream_locations(
+    client: &client::Client,
+    url: &str,
+    areas: &[Area],
+    matcher: Option<&RouteMatcher>,
+    on_vehicle: &mut dyn FnMut(VehicleTime, u64),
+) -> Result<Option<u64>> {
+    let reader = client::download_reader(client, url)?;
+
+    let mut vehicles = Vec::new();
+    let mut updated_time = None;
+    for element in xmlstream::Elements::new(reader) {
+        let element = element?;
+        match &element.name[..] {
+            "vehicle" => {
+                let vehicle: Option<Vehicle> = client::deserialize_element(client, element)?;
+                vehicles.extend(vehicle.filter(|v| is_inside(v, areas)));
+            }
+            "lastTime" => {
+                let last_time: Option<LastTime> = client::deserialize_element(client, element)?;
+                updated_time = last_time.map(|t| t.time);
+            }
+            _ => (),
+        }
+    }
+
+    // without a lastTime, report times are relative to when the response was read
+    let response_time = updated_time.unwrap_or_else(time::now_epoch);
+    for vehicle in vehicles {
+        on_vehicle(vehicle_time(vehicle, response_time, matcher), response_time);
+    }
+    Ok(updated_time)
+}
+
+// Remembers the last reported state of each vehicle, to tell which reports are new, and
+// when it was last in a response, to tell which vehicles have gone
+struct VehicleTracker {
+    changes_only: bool,
+    removed_after_ms: Option<u64>,
+    last_reported: HashMap<String, VehicleTime>,
+    last_seen: HashMap<String, u64>,
+}
+
+impl VehicleTracker {
+    fn new(changes_only: bool, removed_after: Option<Duration>) -> VehicleTracker {
+        VehicleTracker {
+            changes_only,
+            removed_after_ms: removed_after.map(|d| d.as_millis() as u64),
+            last_reported: HashMap::new(),
+            last_seen: HashMap::new(),
+        }
+    }
+
+    // Whether the vehicle should be printed, remembering its state for next time. `seen_at`
+    // is the feed's time for the response the vehicle was in.
+    fn has_changed(&mut self, vehicle: &VehicleTime, seen_at: u64) -> bool {
+        if !self.changes_only {
+            return true;
+        }
+        self.last_seen.insert(vehicle.id.clone(), seen_at);
+        let changed = match self.last_reported.get(&vehicle.id) {
+            None => true,
+            Some(last) => {
+                last.lat != vehicle.lat
+                    || last.lon != vehicle.lon
+                    || last.heading != vehicle.heading
+                    || last.dir_tag != vehicle.dir_tag
+                    // report times are derived from whole seconds since the report, so the
+                    // same report can come back with an epoch that is off by up to a second
+                    || (vehicle.epoch as i64 - last.epoch as i64).abs() > 1000
+            }
+        };
+        if changed {
+            self.last_reported
+                .insert(vehicle.id.clone(), vehicle.clone());
+        }
+        changed
+    }
+
+    // Forget vehicles that haven't been in a response for longer than `removed_after` as of
+    // the given epoch, returning their last known state marked as removed. A parked vehicle
+    // that keeps sending the same report is still there.
+    fn expire(&mut self, epoch: u64) -> Vec<VehicleTime> {
+        let removed_after_ms = match self.removed_after_ms {
+            Some(ms) if self.changes_only => ms,
+            _ => return Vec::new(),
+        };
+        let expired: Vec<String> = self
+            .last_seen
+            .iter()
+            .filter(|&(_, &seen_at)| epoch.saturating_sub(seen_at) > removed_after_ms)
+            .map(|(id, _)| id.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|id| {
+                self.last_seen.remove(&id);
+                self.last_reported.remove(&id)
+            })
+            .map(|mut v| {
+                v.removed = true;
+                v
+            })
+            .collect()
+    }
+}
+
+// Below this distance between reports, a vehicle is treated as stationary, since GPS noise
+// would give it a random heading
+const MIN_MOVEMENT_METERS: f64 = 10.0;
+
+// Remembers the last accepted report of each vehicle, to derive headings and speeds from
+struct MotionTracker {
+    enabled: bool,
+    max_speed_km_hr: f64,
+    last_accepted: HashMap<String, VehicleTime>,
+    // the last report dropped as a jump, which becomes the vehicle's position if the reports
+    // after it agree with it instead of the last accepted one
+    last_rejected: HashMap<String, VehicleTime>,
+}
+
+impl MotionTracker {
+    fn new(enabled: bool, max_speed_km_hr: f64) -> MotionTracker {
+        MotionTracker {
+            enabled,
+            max_speed_km_hr,
+            last_accepted: HashMap::new(),
+            last_rejected: HashMap::new(),
+        }
+    }
+
+    // The vehicle with any missing heading or speed derived, or None if it jumped further
+    // than it could have since its last report
+    fn update(&mut self, mut vehicle: VehicleTime) -> Option<VehicleTime> {
+        if !self.enabled {
+            return Some(vehicle);
+        }
+        vehicle.heading_source = Some(MotionSource::Reported);
+        vehicle.speed_source = Some(MotionSource::Reported);
+
+        let plausible_after = |last: &VehicleTime| match motion_between(last, &vehicle) {
+            Some((_, speed)) => speed <= self.max_speed_km_hr,
+            None => true,
+        };
+        let previous = match self.last_accepted.get(&vehicle.id) {
+            Some(last) if !plausible_after(last) => match self.last_rejected.get(&vehicle.id) {
+                Some(rejected) if rejected.epoch < vehicle.epoch && plausible_after(rejected) => {
+                    self.last_rejected.remove(&vehicle.id)
+                }
+                _ => {
+                    self.last_rejected
+                        .insert(vehicle.id.clone(), vehicle.clone());
+                    return None;
+                }
+            },
+            Some(last) => Some(last.clone()),
+            None => None,
+        };
+        self.last_rejected.remove(&vehicle.id);
+
+        if let Some(previous) = previous {
+            if previous.epoch == vehicle.epoch {
+                // the same report again, from a later poll
+                vehicle.heading = previous.heading;
+                vehicle.speed_km_hr = previous.speed_km_hr;
+                vehicle.heading_source = previous.heading_source;
+                vehicle.speed_source = previous.speed_source;
+            } else if let Some((bearing, speed)) = motion_between(&previous, &vehicle) {
+                if vehicle.heading < 0 {
+                    vehicle.heading = bearing.round() as i16 % 360;
+                    vehicle.heading_source = Some(MotionSource::Derived);
+                }
+                if vehicle.speed_km_hr == 0 {
+                    vehicle.speed_km_hr = speed.round() as u32;
+                    vehicle.speed_source = Some(MotionSource::Derived);
+                }
+            }
+        }
+
+        self.last_accepted
+            .insert(vehicle.id.clone(), vehicle.clone());
+        Some(vehicle)
+    }
+}
+
+// The bearing in degrees and speed in km/h a vehicle moved at from one report to a later one,
+// or None if it didn't move far enough to tell
+fn motion_between(from: &VehicleTime, to: &VehicleTime) -> Option<(f64, f64)> {
+    if to.epoch <= from.epoch {
+        return None;
+    }
+    let (a, b) = (LatLon::new(from.lat, from.lon), LatLon::new(to.lat, to.lon));
+    let meters = a.distance_to(&b);
+    if meters < MIN_MOVEMENT_METERS {
+        return None;
+    }
+    let seconds = (to.epoch - from.epoch) as f64 / 1000.0;
+    Some((a.bearing_to(&b), meters / seconds * 3.6))
+}
+
+fn vehicle_time(v: Vehicle, updated_time: u64, matcher: Option<&RouteMatcher>) -> VehicleTime {
+    let progress =
+        matcher.and_then(|m| m.locate(&v.route_tag, &v.dir_tag, &LatLon::new(v.lat, v.lon)));
+    VehicleTime {
+        id: v.id,
+        route_tag: v.route_tag,
+        dir_tag: v.dir_tag,
+        lat: v.lat,
+        lon: v.lon,
+        predictable: v.predictable,
+        heading: v.heading,
+        speed_km_hr: v.speed_km_hr,
+        leading_vehicle_id: v.leading_vehicle_id,
+        epoch: updated_time - ((v.secs_since_report * 1000) as u64),
+        removed: false,
+        progress,
+        heading_source: None,
+        speed_source: None,
+    }
+}
+
+fn is_inside(vehicle: &Vehicle, areas: &[Area]) -> bool {
+    let position = LatLon::new(vehicle.lat, vehicle.lon);
+    areas.iter().all(|area| area.contains(&position))
+}
+
+fn parse_locations(
+    locations: Locations,
+    areas: &[Area],
+    matcher: Option<&RouteMatcher>,
+) -> (Vec<VehicleTime>, u64) {
     let updated_time = locations.updated_time.time;
 
     let location_times: Vec<VehicleTime> = locations
         .vehicles
         .into_iter()
-        .map(|v| VehicleTime {
-            id: v.id,
-            route_tag: v.route_tag,
-            dir_tag: v.dir_tag,
-            lat: v.lat,
-            lon: v.lon,
-            predictable: v.predictable,
-            heading: v.heading,
-            speed_km_hr: v.speed_km_hr,
-            leading_vehicle_id: v.leading_vehicle_id,
-            epoch: updated_time - ((v.secs_since_report * 1000) as u64),
-        })
+        .filter(|v| is_inside(v, areas))
+        .map(|v| vehicle_time(v, updated_time, matcher))
         .collect();
 
-    let locations_json = serde_json::to_string(&location_times).unwrap();
+    (location_times, updated_time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::Contents;
+    use serde_xml_rs::deserialize;
+
+    fn locations(xml: &[u8]) -> Locations {
+        deserialize(xml).unwrap()
+    }
+
+    #[test]
+    fn parses_sf_muni_locations() {
+        let (vehicles, updated_time) = parse_locations(
+            locations(include_bytes!(
+                "../fixtures/recorded/responses/command_vehicleLocations_a_sf-muni_r_N_t_0-8cb4299112468677-0.xml"
+            )),
+            &[],
+            None,
+        );
+        assert_eq!(updated_time, 1792415520000);
+        assert_eq!(vehicles.len(), 3);
+
+        let first = &vehicles[0];
+        assert_eq!(first.id, "1501");
+        assert_eq!(first.dir_tag, "N____I_F00");
+        assert_eq!(first.epoch, 1792415520000 - 12_000);
+        assert_eq!(
+            (first.lat, first.lon),
+            (Coordinate(37.76118), Coordinate(-122.48702))
+        );
+        assert_eq!((first.heading, first.speed_km_hr), (85, 22));
+        assert!(first.predictable);
+        assert_eq!(first.leading_vehicle_id, "");
+
+        assert_eq!(vehicles[1].leading_vehicle_id, "1503");
+        // a vehicle without a dirTag
+        assert_eq!(vehicles[2].dir_tag, "");
+        assert_eq!(vehicles[2].heading, -1);
+        assert!(!vehicles[2].predictable);
+    }
+
+    #[test]
+    fn parses_ttc_locations() {
+        let (vehicles, _) = parse_locations(
+            locations(include_bytes!(
+                "../fixtures/recorded/responses/command_vehicleLocations_a_ttc_r_504_t_0-ede7014911a7fd3c-0.xml"
+            )),
+            &[],
+            None,
+        );
+        let ids: Vec<&str> = vehicles.iter().map(|v| &v.id[..]).collect();
+        assert_eq!(ids, vec!["4402", "4417"]);
+        assert_eq!(vehicles[1].epoch, 1792429800000 - 20_000);
+    }
+
+    #[test]
+    fn parses_unitrans_locations() {
+        let (vehicles, _) = parse_locations(
+            locations(include_bytes!(
+                "../fixtures/recorded/responses/command_vehicleLocations_a_unitrans_r_A_t_0-bff555188e5398fd-0.xml"
+            )),
+            &[],
+            None,
+        );
+        assert_eq!(vehicles.len(), 1);
+        // unitrans leaves out dirTag
+        assert_eq!(vehicles[0].dir_tag, "");
+        assert_eq!(vehicles[0].epoch, 1792440000000 - 8_000);
+    }
+
+    #[test]
+    fn parses_locations_without_vehicles() {
+        let empty = locations(
+            b"<body copyright=\"All data copyright Unitrans 2022.\">\n\
+              <lastTime time=\"1792440000000\"/>\n\
+              </body>",
+        );
+        // not empty, so that its lastTime is used
+        assert!(!empty.is_empty());
+        assert!(empty.vehicles.is_empty());
+        assert_eq!(empty.updated_time.time, 1792440000000);
+    }
+
+    fn report(id: &str, lat: f64, lon: f64, epoch: u64) -> VehicleTime {
+        VehicleTime {
+            id: String::from(id),
+            route_tag: String::from("N"),
+            dir_tag: String::from("N____O_F00"),
+            lat: Coordinate(lat),
+            lon: Coordinate(lon),
+            epoch,
+            predictable: true,
+            heading: -1,
+            speed_km_hr: 0,
+            leading_vehicle_id: String::new(),
+            removed: false,
+            progress: None,
+            heading_source: None,
+            speed_source: None,
+        }
+    }
 
-    return (locations_json, updated_time);
+    #[test]
+    fn tracks_changed_reports() {
+        let mut tracker = VehicleTracker::new(true, None);
+        let parked = report("1501", 37.76118, -122.48702, 1_000_000);
+        assert!(tracker.has_changed(&parked, 1_010_000));
+        assert!(!tracker.has_changed(&parked, 1_020_000));
+        // the same report, with its epoch rounded differently
+        let rounded = report("1501", 37.76118, -122.48702, 1_000_900);
+        assert!(!tracker.has_changed(&rounded, 1_030_000));
+
+        let moved = report("1501", 37.76181, -122.47712, 1_035_000);
+        assert!(tracker.has_changed(&moved, 1_040_000));
+        let mut turned = moved.clone();
+        turned.dir_tag = String::from("N____I_F00");
+        assert!(tracker.has_changed(&turned, 1_050_000));
+        let later = report("1501", 37.76181, -122.47712, 1_045_000);
+        assert!(tracker.has_changed(&later, 1_050_000));
+
+        let mut everything = VehicleTracker::new(false, None);
+        assert!(everything.has_changed(&parked, 1_010_000));
+        assert!(everything.has_changed(&parked, 1_020_000));
+    }
+
+    #[test]
+    fn expires_vehicles_missing_from_responses() {
+        let mut tracker = VehicleTracker::new(true, Some(Duration::from_secs(60)));
+        let parked = report("1501", 37.76118, -122.48702, 1_000_000);
+        let gone = report("1502", 37.76181, -122.47712, 1_000_000);
+        assert!(tracker.has_changed(&parked, 1_000_000));
+        assert!(tracker.has_changed(&gone, 1_000_000));
+
+        // the parked vehicle keeps sending the same report, long after it was made
+        for poll in 1..=12 {
+            let seen_at = 1_000_000 + poll * 10_000;
+            assert!(!tracker.has_changed(&parked, seen_at));
+            let expired = tracker.expire(seen_at);
+            let ids: Vec<&str> = expired.iter().map(|v| &v.id[..]).collect();
+            // the other vehicle is removed once, on the first poll more than a minute on
+            match poll {
+                7 => assert_eq!(ids, vec!["1502"]),
+                _ => assert!(ids.is_empty()),
+            }
+            assert!(expired.iter().all(|v| v.removed));
+        }
+
+        // a vehicle that comes back is reported again
+        assert!(tracker.has_changed(&gone, 1_130_000));
+
+        let mut without_removal = VehicleTracker::new(true, None);
+        assert!(without_removal.has_changed(&gone, 1_000_000));
+        assert!(without_removal.expire(2_000_000).is_empty());
+    }
+
+    #[test]
+    fn removes_vehicles_once_their_route_empties() {
+        let mut tracker = VehicleTracker::new(true, Some(Duration::from_secs(60)));
+        let (vehicles, epoch) = parse_locations(
+            locations(include_bytes!(
+                "../fixtures/recorded/responses/command_vehicleLocations_a_sf-muni_r_N_t_0-8cb4299112468677-0.xml"
+            )),
+            &[],
+            None,
+        );
+        for vehicle in &vehicles {
+            assert!(tracker.has_changed(vehicle, epoch));
+        }
+
+        // every vehicle has gone, and responses only move lastTime on
+        let mut removed = Vec::new();
+        for poll in 1..=3 {
+            let empty = format!(
+                "<body copyright=\"All data copyright San Francisco Muni 2022.\">\
+                 <lastTime time=\"{}\"/></body>",
+                epoch + poll * 30_000
+            );
+            let (vehicles, updated_time) = parse_locations(locations(empty.as_bytes()), &[], None);
+            assert!(vehicles.is_empty());
+            let expired = tracker.expire(updated_time);
+            assert_eq!(expired.is_empty(), poll < 3, "poll {}", poll);
+            removed.extend(expired);
+        }
+        let mut ids: Vec<&str> = removed.iter().map(|v| &v.id[..]).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["1501", "1502", "1503"]);
+        assert!(removed.iter().all(|v| v.removed));
+    }
+
+    #[test]
+    fn derives_missing_headings_and_speeds() {
+        let mut motion = MotionTracker::new(true, 130.0);
+        let first = motion
+            .update(report("1501", 37.76, -122.48, 1_000_000))
+            .unwrap();
+        assert_eq!(first.heading, -1);
+        assert_eq!(first.heading_source, Some(MotionSource::Reported));
+
+        // about 111m north in 10 seconds
+        let north = motion
+            .update(report("1501", 37.761, -122.48, 1_010_000))
+            .unwrap();
+        assert_eq!(north.heading, 0);
+        assert_eq!(north.speed_km_hr, 40);
+        assert_eq!(north.heading_source, Some(MotionSource::Derived));
+        assert_eq!(north.speed_source, Some(MotionSource::Derived));
+
+        // the same report from a later poll keeps what was derived for it
+        let again = motion
+            .update(report("1501", 37.761, -122.48, 1_010_000))
+            .unwrap();
+        assert_eq!((again.heading, again.speed_km_hr), (0, 40));
+        assert_eq!(again.heading_source, Some(MotionSource::Derived));
+        assert_eq!(again.speed_source, Some(MotionSource::Derived));
+
+        // reported values are kept
+        let mut east = report("1501", 37.761, -122.479, 1_020_000);
+        east.heading = 95;
+        east.speed_km_hr = 30;
+        let east = motion.update(east).unwrap();
+        assert_eq!((east.heading, east.speed_km_hr), (95, 30));
+        assert_eq!(east.heading_source, Some(MotionSource::Reported));
+        assert_eq!(east.speed_source, Some(MotionSource::Reported));
+
+        let mut untracked = MotionTracker::new(false, 130.0);
+        let raw = untracked
+            .update(report("1501", 37.76, -122.48, 1_000_000))
+            .unwrap();
+        assert_eq!(raw.heading_source, None);
+    }
+
+    #[test]
+    fn leaves_stationary_vehicles_without_a_heading() {
+        let mut motion = MotionTracker::new(true, 130.0);
+        motion.update(report("1501", 37.76, -122.48, 1_000_000));
+        // about 4m of GPS noise
+        let parked = motion
+            .update(report("1501", 37.76004, -122.48, 1_030_000))
+            .unwrap();
+        assert_eq!((parked.heading, parked.speed_km_hr), (-1, 0));
+        assert_eq!(parked.heading_source, Some(MotionSource::Reported));
+        assert_eq!(parked.speed_source, Some(MotionSource::Reported));
+    }
+
+    #[test]
+    fn drops_jumps_until_they_are_confirmed() {
+        let mut motion = MotionTracker::new(true, 130.0);
+        motion.update(report("1501", 37.76, -122.48, 1_000_000));
+        // 11km in 10 seconds
+        assert!(motion
+            .update(report("1501", 37.86, -122.48, 1_010_000))
+            .is_none());
+        // back where it was, so the jump was noise
+        let back = motion
+            .update(report("1501", 37.7601, -122.48, 1_020_000))
+            .unwrap();
+        assert_eq!(back.heading, 0);
+
+        // another jump, and then a report that agrees with it instead
+        assert!(motion
+            .update(report("1501", 37.86, -122.48, 1_030_000))
+            .is_none());
+        let moved = motion
+            .update(report("1501", 37.86, -122.479, 1_040_000))
+            .unwrap();
+        // derived from the confirmed jump, not the last accepted report
+        assert_eq!(moved.heading, 90);
+        assert_eq!(moved.speed_km_hr, 32);
+        // which is where the vehicle is from now on
+        let next = motion
+            .update(report("1501", 37.861, -122.479, 1_050_000))
+            .unwrap();
+        assert_eq!(next.heading, 0);
+    }
 }
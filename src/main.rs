@@ -1,19 +1,29 @@
+extern crate chrono;
+extern crate chrono_tz;
 extern crate env_logger;
+extern crate flate2;
+extern crate futures;
 extern crate reqwest;
 #[macro_use]
 extern crate log;
+#[macro_use]
 extern crate serde;
 extern crate serde_json;
 extern crate serde_xml_rs;
+extern crate tokio;
+extern crate xml;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate error_chain;
 
 extern crate clap;
+extern crate crossterm;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
 
 pub trait Contents {
     fn is_empty(&self) -> bool;
@@ -25,16 +35,39 @@ mod errors {
             ReqError(::reqwest::Error);
             IoError(::std::io::Error);
             SerdeError(::serde_xml_rs::Error);
+            JsonError(::serde_json::Error);
+            XmlError(::xml::reader::Error);
         }
     }
 }
 
+mod accuracy;
+mod adherence;
+mod board;
 mod client;
+mod geo;
+mod headway;
+mod lenient;
 mod location;
+mod mapmatch;
+mod nearby;
+mod output;
 mod prediction;
+mod ratelimit;
+mod recording;
+mod replay;
 mod routes;
 mod schedule;
+mod search;
+mod stopindex;
 mod stops;
+mod time;
+mod timetable;
+mod tui;
+mod xmlstream;
+
+const DEFAULT_RADIUS_METERS: u64 = 500;
+const DEFAULT_DEPARTURES: u64 = 5;
 
 fn main() -> Result<(), impl Error> {
     env_logger::init();
@@ -50,11 +83,112 @@ fn main() -> Result<(), impl Error> {
         .version(version_string)
         .author("Shahin Saneinejad")
         .about("Get real-time locations of transit vehicles as JSON")
+        .args(&[
+            Arg::with_name("max_bytes")
+                .long("max-bytes")
+                .value_name("BYTES")
+                .help("Maximum response bytes to download per --bytes-window (default: 2000000)")
+                .global(true),
+            Arg::with_name("bytes_window")
+                .long("bytes-window")
+                .value_name("SECONDS")
+                .help("Window over which --max-bytes applies (default: 20)")
+                .global(true),
+            Arg::with_name("max_requests")
+                .long("max-requests")
+                .value_name("REQUESTS")
+                .help("Maximum requests to make per --requests-window (default: 20)")
+                .global(true),
+            Arg::with_name("requests_window")
+                .long("requests-window")
+                .value_name("SECONDS")
+                .help("Window over which --max-requests applies (default: 10)")
+                .global(true),
+            Arg::with_name("locations_interval")
+                .long("locations-interval")
+                .value_name("SECONDS")
+                .help("Minimum time between vehicle location requests (default: 10)")
+                .global(true),
+            Arg::with_name("connect_timeout")
+                .long("connect-timeout")
+                .value_name("SECONDS")
+                .help("Timeout for connecting to the feed (default: 10)")
+                .global(true),
+            Arg::with_name("request_timeout")
+                .long("request-timeout")
+                .value_name("SECONDS")
+                .help("Timeout for a whole request, from connecting to reading the last of the response (default: 30)")
+                .global(true),
+            Arg::with_name("concurrency")
+                .long("concurrency")
+                .value_name("REQUESTS")
+                .help("Maximum number of requests to make at once (default: 4)")
+                .global(true),
+            Arg::with_name("proxy")
+                .long("proxy")
+                .value_name("URL")
+                .help("HTTP proxy to send requests through (default: the system proxy, if any)")
+                .global(true),
+            Arg::with_name("feed_url")
+                .long("feed-url")
+                .value_name("URL")
+                .help("Feed to send requests to, e.g. a local fake-feed (default: https://retro.umoiq.com/service/publicXMLFeed)")
+                .global(true),
+            Arg::with_name("record_dir")
+                .long("record-dir")
+                .value_name("DIR")
+                .help("Save every request's URL, response status, headers and body to files in DIR")
+                .conflicts_with("replay_dir")
+                .global(true),
+            Arg::with_name("replay_dir")
+                .long("replay-dir")
+                .value_name("DIR")
+                .help("Answer requests with the responses saved by --record-dir in DIR, instead of the network")
+                .global(true),
+            Arg::with_name("lenient")
+                .long("lenient")
+                .help("Skip records that fail to parse instead of the whole response, and report attributes that aren't parsed")
+                .global(true),
+        ])
         .subcommand(SubCommand::with_name("locations")
             .about("Get real-time locations for vehicles")
             .args_from_usage("<agency> 'Agency of the route to retrieve locations for (ex: sf-muni)'")
             .args_from_usage("[route] 'Optional name of the route to retrieve locations for (default: all routes)'")
             .args_from_usage("-p, --pause=[SECONDS] 'Repeat the request after pausing for the given SECONDS'")
+            .args_from_usage("--stream 'Print each vehicle as a line of JSON as soon as it is parsed'")
+            .args_from_usage("--changes-only 'Only print vehicles whose position, heading, direction or report time changed'")
+            .arg(Arg::with_name("removed_after")
+                .long("removed-after")
+                .value_name("SECONDS")
+                .requires("changes-only")
+                .help("With --changes-only, report vehicles as removed after not reporting for SECONDS"))
+            .arg(Arg::with_name("bbox")
+                .long("bbox")
+                .value_name("minLat,minLon,maxLat,maxLon")
+                .allow_hyphen_values(true)
+                .help("Only print vehicles inside the given bounding box"))
+            .arg(Arg::with_name("near")
+                .long("near")
+                .value_name("lat,lon")
+                .allow_hyphen_values(true)
+                .help("Only print vehicles within --radius of the given point"))
+            .arg(Arg::with_name("radius")
+                .long("radius")
+                .value_name("METERS")
+                .requires("near")
+                .help("Distance from --near to print vehicles within (default: 500)"))
+            .args_from_usage("--match-routes 'Report where each vehicle is along its route, from the route configs'")
+            .arg(Arg::with_name("off_route")
+                .long("off-route")
+                .value_name("METERS")
+                .requires("match-routes")
+                .help("With --match-routes, flag vehicles further than METERS from their route as off-route (default: 100)"))
+            .args_from_usage("--derive-motion 'Derive missing headings and speeds from consecutive reports, and drop impossible jumps'")
+            .arg(Arg::with_name("max_speed")
+                .long("max-speed")
+                .value_name("KM_HR")
+                .requires("derive-motion")
+                .help("With --derive-motion, drop reports that would need a vehicle to move faster than KM_HR (default: 130)"))
         )
         .subcommand(SubCommand::with_name("predictions")
             .about("Get predictions for vehicle arrival times")
@@ -80,6 +214,17 @@ fn main() -> Result<(), impl Error> {
                     .use_delimiter(true)
                     .value_delimiter(" ")
                     .last(true),
+                Arg::with_name("stop_name")
+                    .long("stop-name")
+                    .value_name("NAME")
+                    .help("Get predictions for the stops whose titles best match NAME (ex: 'Mission & 16th')")
+                    .conflicts_with("stops"),
+                Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["json", "table"])
+                    .default_value("json")
+                    .help("Print predictions as JSON, or as an arrivals board"),
             ])
         )
         .subcommand(SubCommand::with_name("schedule")
@@ -90,24 +235,136 @@ fn main() -> Result<(), impl Error> {
                     .index(1)
                     .required(true),
                 Arg::with_name("route")
-                    .help("Route to retrieve schedules for (ex: N)")
+                    .help("Routes to retrieve schedules for (ex: N J)")
                     .index(2)
-                    .required(true)
+                    .required_unless("all")
                     .multiple(true),
+                Arg::with_name("all")
+                    .long("all")
+                    .conflicts_with("route")
+                    .help("Retrieve schedules for every route of the agency"),
+                Arg::with_name("timezone")
+                    .long("timezone")
+                    .value_name("TIMEZONE")
+                    .default_value("local")
+                    .help("Timezone of the agency's schedule, e.g. America/Los_Angeles"),
+                Arg::with_name("date")
+                    .long("date")
+                    .value_name("YYYY-MM-DD")
+                    .help("Service day to give full dates and times for (default: today)"),
+                Arg::with_name("service")
+                    .long("service")
+                    .value_name("SERVICE")
+                    .possible_values(&["wkd", "sat", "sun", "today"])
+                    .help("Only the schedule for weekdays, Saturdays, Sundays, or the service running on --date"),
+                Arg::with_name("direction")
+                    .long("direction")
+                    .value_name("DIRECTION")
+                    .help("Only the schedule for one direction (ex: Inbound)"),
+                Arg::with_name("stop")
+                    .long("stop")
+                    .value_name("TAG")
+                    .help("Only blocks that stop at the given stop, with --from and --to applying to their times there"),
+                Arg::with_name("from")
+                    .long("from")
+                    .value_name("HH:MM")
+                    .help("Only blocks running at or after the given time of the service day"),
+                Arg::with_name("to")
+                    .long("to")
+                    .value_name("HH:MM")
+                    .help("Only blocks running at or before the given time of the service day, which may be past 24:00"),
+                Arg::with_name("view")
+                    .long("view")
+                    .value_name("VIEW")
+                    .possible_values(&["blocks", "timetable", "departures"])
+                    .help("Print the schedule by block as in the feed, as a timetable of stops by trips, or as each stop's departures (default: blocks for JSON, timetable otherwise)"),
+                Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .possible_values(&["json", "csv", "table"])
+                    .default_value("json")
+                    .help("Print the schedule as JSON, CSV or a table"),
+            ])
+        )
+        .subcommand(SubCommand::with_name("next-departures")
+            .about("List the next scheduled departures from a stop")
+            .args(&[
+                Arg::with_name("agency")
+                    .help("Agency of the route (ex: sf-muni)")
+                    .index(1)
+                    .required(true),
+                Arg::with_name("route")
+                    .help("Route to list departures for (ex: N)")
+                    .index(2)
+                    .required(true),
+                Arg::with_name("stop")
+                    .help("Tag of the stop to list departures from (ex: 5205)")
+                    .index(3)
+                    .required(true),
+                Arg::with_name("at")
+                    .long("at")
+                    .value_name("HH:MM")
+                    .help("List departures after the given time on --date (default: now)"),
+                Arg::with_name("date")
+                    .long("date")
+                    .value_name("YYYY-MM-DD")
+                    .help("Service day of --at (default: today)"),
+                Arg::with_name("timezone")
+                    .long("timezone")
+                    .value_name("TIMEZONE")
+                    .default_value("local")
+                    .help("Timezone of the agency's schedule, e.g. America/Los_Angeles"),
+                Arg::with_name("limit")
+                    .long("limit")
+                    .value_name("N")
+                    .help("List at most N departures (default: 5)"),
             ])
         )
         .subcommand(SubCommand::with_name("stops")
             .about("Get the published stops for a route")
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .subcommand(SubCommand::with_name("search")
+                .about("Find stops by name, across one or all routes")
+                .args(&[
+                    Arg::with_name("agency")
+                        .help("Agency to search the stops of (ex: sf-muni)")
+                        .index(1)
+                        .required(true),
+                    Arg::with_name("query")
+                        .help("Stop name to search for (ex: 'Mission St & 16th')")
+                        .index(2)
+                        .required(true),
+                    Arg::with_name("route")
+                        .long("route")
+                        .value_name("ROUTE")
+                        .help("Only search the stops of ROUTE (default: all routes)"),
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Return at most N stops"),
+                    Arg::with_name("max_cache_age")
+                        .long("max-cache-age")
+                        .value_name("SECONDS")
+                        .help("Rebuild the cached stop index if it is older than SECONDS (default: 86400)"),
+                ])
+            )
             .args(&[
                 Arg::with_name("agency")
                     .help("Agency of the route to stops for (ex: sf-muni)")
                     .index(1)
                     .required(true),
                 Arg::with_name("route")
-                    .help("Route to retrieve stops for (ex: N)")
+                    .help("Routes to retrieve stops for (ex: N J)")
                     .index(2)
-                    .required(true)
+                    .required_unless("all")
                     .multiple(true),
+                Arg::with_name("all")
+                    .long("all")
+                    .conflicts_with("route")
+                    .help("Retrieve stops for every route of the agency"),
+                Arg::with_name("stream")
+                    .long("stream")
+                    .help("Print each stop as a line of JSON as soon as it is parsed"),
             ])
         )
         .subcommand(SubCommand::with_name("routes")
@@ -119,56 +376,514 @@ fn main() -> Result<(), impl Error> {
                     .required(true),
             ])
         )
+        .subcommand(SubCommand::with_name("nearby")
+            .about("Find the stops of an agency around a location, nearest first")
+            .args(&[
+                Arg::with_name("agency")
+                    .help("Agency to find stops for (ex: sf-muni)")
+                    .index(1)
+                    .required(true),
+                Arg::with_name("position")
+                    .help("Location to find stops around (ex: 37.7648,-122.4194)")
+                    .value_name("lat,lon")
+                    .index(2)
+                    .required(true)
+                    .allow_hyphen_values(true),
+                Arg::with_name("radius")
+                    .long("radius")
+                    .value_name("METERS")
+                    .help("Distance to find stops within (default: 500)"),
+                Arg::with_name("limit")
+                    .long("limit")
+                    .value_name("N")
+                    .help("Return at most N stops"),
+                Arg::with_name("predictions")
+                    .long("predictions")
+                    .help("Attach live predictions for every route serving each stop"),
+                Arg::with_name("max_cache_age")
+                    .long("max-cache-age")
+                    .value_name("SECONDS")
+                    .help("Rebuild the cached stop index if it is older than SECONDS (default: 86400)"),
+            ])
+        )
+        .subcommand(SubCommand::with_name("accuracy")
+            .about("Measure how accurate the feed's predictions are")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("collect")
+                .about("Print predictions for a route, and arrivals inferred from vehicle locations, as lines of JSON")
+                .args(&[
+                    Arg::with_name("agency")
+                        .help("Agency of the route to collect predictions for (ex: sf-muni)")
+                        .index(1)
+                        .required(true),
+                    Arg::with_name("route")
+                        .help("Route to collect predictions for (ex: N)")
+                        .index(2)
+                        .required(true),
+                    Arg::with_name("stops")
+                        .long("stop")
+                        .value_name("TAG")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Stop to collect predictions for (default: every stop of the route)"),
+                    Arg::with_name("pause_seconds")
+                        .short("p")
+                        .long("pause")
+                        .value_name("SECONDS")
+                        .help("Time between prediction requests (default: 30)"),
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .help("Time between vehicle location requests (default: 10, at least 5)"),
+                    Arg::with_name("radius")
+                        .long("radius")
+                        .value_name("METERS")
+                        .help("Distance a vehicle has to pass a stop within to arrive at it (default: 30)"),
+                    Arg::with_name("polls")
+                        .long("polls")
+                        .value_name("COUNT")
+                        .help("Stop after polling vehicle locations COUNT times (default: run until interrupted)"),
+                ])
+            )
+            .subcommand(SubCommand::with_name("report")
+                .about("Report prediction errors by horizon, route and stop from the output of `accuracy collect`")
+                .args(&[
+                    Arg::with_name("file")
+                        .help("File written by `accuracy collect` (default: standard input)")
+                        .index(1),
+                ])
+            )
+        )
+        .subcommand(SubCommand::with_name("replay")
+            .about("Print recorded JSON output of locations, predictions and the like with its original timing (NDJSON only, not SQLite)")
+            .args(&[
+                Arg::with_name("file")
+                    .help("File of recorded output, one JSON value per line (default: standard input)")
+                    .index(1),
+                Arg::with_name("speed")
+                    .long("speed")
+                    .value_name("FACTOR")
+                    .help("Replay FACTOR times faster than recorded, e.g. 10 (default: 1)"),
+            ])
+        )
+        .subcommand(SubCommand::with_name("adherence")
+            .about("Get how early or late each vehicle on a route is, against its scheduled trip")
+            .args(&[
+                Arg::with_name("agency")
+                    .help("Agency of the route to get schedule adherence for (ex: sf-muni)")
+                    .index(1)
+                    .required(true),
+                Arg::with_name("route")
+                    .help("Route to get schedule adherence for (ex: N)")
+                    .index(2)
+                    .required(true),
+                Arg::with_name("timezone")
+                    .long("timezone")
+                    .value_name("TIMEZONE")
+                    .default_value("local")
+                    .help("Timezone of the agency's schedule, e.g. America/Los_Angeles"),
+                Arg::with_name("pause_seconds")
+                    .short("p")
+                    .long("pause")
+                    .value_name("SECONDS")
+                    .help("Repeat after pausing for the given SECONDS"),
+            ])
+        )
+        .subcommand(SubCommand::with_name("headways")
+            .about("Get the gaps between consecutive vehicles on a route, and flag bunching and gaps in service")
+            .args(&[
+                Arg::with_name("agency")
+                    .help("Agency of the route to get headways for (ex: sf-muni)")
+                    .index(1)
+                    .required(true),
+                Arg::with_name("route")
+                    .help("Route to get headways for (ex: N)")
+                    .index(2)
+                    .required(true),
+                Arg::with_name("stops")
+                    .long("stop")
+                    .value_name("TAG")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Stop to measure arrival gaps at, for each direction serving it (default: the middle stop of each direction)"),
+                Arg::with_name("scheduled_headway")
+                    .long("scheduled-headway")
+                    .value_name("SECONDS")
+                    .help("Scheduled time between vehicles (default: from the route's schedule, or else the median gap between arrivals)"),
+                Arg::with_name("timezone")
+                    .long("timezone")
+                    .value_name("TIMEZONE")
+                    .default_value("local")
+                    .help("Timezone of the agency's schedule, e.g. America/Los_Angeles"),
+                Arg::with_name("bunching_percent")
+                    .long("bunching-percent")
+                    .value_name("PERCENT")
+                    .help("Flag gaps below PERCENT of the expected headway as bunching (default: 50)"),
+                Arg::with_name("gap_percent")
+                    .long("gap-percent")
+                    .value_name("PERCENT")
+                    .help("Flag gaps above PERCENT of the expected headway as gaps in service (default: 150)"),
+                Arg::with_name("pause_seconds")
+                    .short("p")
+                    .long("pause")
+                    .value_name("SECONDS")
+                    .help("Repeat after pausing for the given SECONDS"),
+            ])
+        )
+        .subcommand(SubCommand::with_name("tui")
+            .about("Browse live vehicles, a route map and stop arrivals in the terminal")
+            .args(&[
+                Arg::with_name("agency")
+                    .help("Agency to browse the routes of (ex: sf-muni)")
+                    .index(1)
+                    .required(true),
+                Arg::with_name("route")
+                    .help("Route to show when starting (ex: N)")
+                    .index(2),
+                Arg::with_name("pause_seconds")
+                    .short("p")
+                    .long("pause")
+                    .value_name("SECONDS")
+                    .help("Refresh vehicles and arrivals every SECONDS (default: 10)"),
+            ])
+        )
         .get_matches();
 
-    match cli.subcommand() {
+    let client = match cli.subcommand() {
+        (_, Some(subc)) => client::Client::new(parse_client_config(innermost(subc))),
+        _ => client::Client::new(client::ClientConfig::default()),
+    }
+    .expect("Unable to build HTTP client");
+
+    let result = match cli.subcommand() {
         ("locations", Some(subc)) => {
             let route = String::from(subc.value_of("route").unwrap_or(""));
             let agency = String::from(subc.value_of("agency").unwrap());
-            let pause_seconds = match subc.value_of("pause") {
-                None => None,
-                Some(s) => Some(
-                    s.parse::<u64>()
-                        .expect(&format!("Must provide a positive integer, got '{}'", s)),
-                ),
+            let options = location::LocationOptions {
+                pause_seconds: parse_u64(subc, "pause"),
+                stream: subc.is_present("stream"),
+                changes_only: subc.is_present("changes-only"),
+                removed_after: parse_u64(subc, "removed_after").map(Duration::from_secs),
+                areas: parse_areas(subc),
+                matcher: match subc.is_present("match-routes") {
+                    true => Some(route_matcher(&client, &agency, &route, subc)?),
+                    false => None,
+                },
+                derive_motion: subc.is_present("derive-motion"),
+                max_speed_km_hr: parse_u64(subc, "max_speed")
+                    .unwrap_or(location::DEFAULT_MAX_SPEED_KM_HR)
+                    as f64,
             };
-            location::get_locations(agency, route, pause_seconds)
+            location::get_locations(&client, agency, route, options)
         }
         ("predictions", Some(subc)) => {
             let route = String::from(subc.value_of("route").unwrap_or(""));
             let agency = String::from(subc.value_of("agency").unwrap());
-            let pause_seconds = match subc.value_of("pause_seconds") {
-                None => None,
-                Some(s) => Some(
-                    s.parse::<u64>()
-                        .expect(&format!("Must provide a positive integer, got {}", s)),
-                ),
-            };
-            let stops: Vec<String> = match subc.values_of("stops") {
-                Some(stops) => stops
+            let pause_seconds = parse_u64(subc, "pause_seconds");
+            let stops: Vec<String> = match (subc.values_of("stops"), subc.value_of("stop_name")) {
+                (Some(stops), _) => stops
                     .collect::<Vec<_>>()
                     .into_iter()
-                    .map(|s| String::from(s))
+                    .map(String::from)
                     .collect(),
-                None => Vec::new(),
+                (None, Some(name)) => {
+                    search::get_stop_tags_for_name(&client, &agency, &route, name)?
+                }
+                (None, None) => Vec::new(),
             };
-            prediction::get_predictions(agency, route, stops, pause_seconds)
+            let format = subc.value_of("format").unwrap().parse().unwrap();
+            prediction::get_predictions(&client, agency, route, stops, pause_seconds, format)
         }
         ("schedule", Some(subc)) => {
-            let route = String::from(subc.value_of("route").unwrap_or(""));
             let agency = String::from(subc.value_of("agency").unwrap());
-            schedule::get_schedule(agency, route)
+            let routes = parse_routes(&client, &agency, subc)?;
+            let format = subc.value_of("format").unwrap().parse().unwrap();
+            let options = schedule::ScheduleOptions {
+                timezone: parse_timezone(subc),
+                date: parse_date(subc),
+                filter: schedule::ScheduleFilter {
+                    service: subc.value_of("service").map(String::from),
+                    direction: subc.value_of("direction").map(String::from),
+                    stop: subc.value_of("stop").map(String::from),
+                    from: parse_time(subc, "from"),
+                    to: parse_time(subc, "to"),
+                },
+                view: match (subc.value_of("view"), format) {
+                    (Some(view), _) => view.parse().unwrap(),
+                    (None, output::OutputFormat::Json) => timetable::View::Blocks,
+                    (None, _) => timetable::View::Timetable,
+                },
+                format,
+            };
+            schedule::get_schedule(&client, agency, routes, keyed(subc), options)
+        }
+        ("next-departures", Some(subc)) => {
+            let agency = String::from(subc.value_of("agency").unwrap());
+            let route = String::from(subc.value_of("route").unwrap());
+            let stop = String::from(subc.value_of("stop").unwrap());
+            let options = schedule::DeparturesOptions {
+                timezone: parse_timezone(subc),
+                date: parse_date(subc),
+                at: parse_time(subc, "at"),
+                limit: parse_u64(subc, "limit").unwrap_or(DEFAULT_DEPARTURES) as usize,
+            };
+            schedule::get_next_departures(&client, agency, route, stop, options)
+        }
+        ("stops", Some(subc)) if subc.subcommand_matches("search").is_some() => {
+            let subc = subc.subcommand_matches("search").unwrap();
+            let agency = String::from(subc.value_of("agency").unwrap());
+            let query = String::from(subc.value_of("query").unwrap());
+            let options = search::SearchOptions {
+                route: subc.value_of("route").map(String::from),
+                limit: parse_u64(subc, "limit").map(|n| n as usize),
+                max_cache_age: Duration::from_secs(
+                    parse_u64(subc, "max_cache_age").unwrap_or(stopindex::DEFAULT_MAX_AGE_SECONDS),
+                ),
+            };
+            search::search_stops(&client, agency, query, options)
         }
         ("stops", Some(subc)) => {
-            let route = String::from(subc.value_of("route").unwrap_or(""));
             let agency = String::from(subc.value_of("agency").unwrap());
-            stops::get_stops(agency, route)
+            let routes = parse_routes(&client, &agency, subc)?;
+            let stream = subc.is_present("stream");
+            stops::get_stops(&client, agency, routes, keyed(subc), stream)
         }
         ("routes", Some(subc)) => {
             let agency = String::from(subc.value_of("agency").unwrap());
-            routes::get_routes(agency)
+            routes::get_routes(&client, agency)
+        }
+        ("nearby", Some(subc)) => {
+            let agency = String::from(subc.value_of("agency").unwrap());
+            let position = subc
+                .value_of("position")
+                .unwrap()
+                .parse::<geo::LatLon>()
+                .unwrap_or_else(|e| panic!("Invalid position: {}", e));
+            let options = nearby::NearbyOptions {
+                radius_meters: parse_u64(subc, "radius").unwrap_or(DEFAULT_RADIUS_METERS) as f64,
+                limit: parse_u64(subc, "limit").map(|n| n as usize),
+                predictions: subc.is_present("predictions"),
+                max_cache_age: Duration::from_secs(
+                    parse_u64(subc, "max_cache_age").unwrap_or(stopindex::DEFAULT_MAX_AGE_SECONDS),
+                ),
+            };
+            nearby::get_nearby(&client, agency, position, options)
+        }
+        ("accuracy", Some(subc)) => match subc.subcommand() {
+            ("collect", Some(subc)) => {
+                let agency = String::from(subc.value_of("agency").unwrap());
+                let route = String::from(subc.value_of("route").unwrap());
+                let options = accuracy::CollectOptions {
+                    stops: subc
+                        .values_of("stops")
+                        .map(|s| s.map(String::from).collect())
+                        .unwrap_or_default(),
+                    pause_seconds: parse_u64(subc, "pause_seconds")
+                        .unwrap_or(accuracy::DEFAULT_PAUSE_SECONDS),
+                    interval_seconds: parse_u64(subc, "interval")
+                        .unwrap_or(accuracy::DEFAULT_INTERVAL_SECONDS),
+                    arrival_radius_meters: parse_u64(subc, "radius")
+                        .unwrap_or(accuracy::DEFAULT_ARRIVAL_RADIUS_METERS)
+                        as f64,
+                    polls: parse_u64(subc, "polls"),
+                };
+                accuracy::collect(&client, agency, route, options)
+            }
+            ("report", Some(subc)) => accuracy::report(subc.value_of("file").map(String::from)),
+            (c, _) => panic!("Unimplemented subcommand 'accuracy {}'", c),
+        },
+        ("replay", Some(subc)) => {
+            let options = replay::ReplayOptions {
+                speed: subc.value_of("speed").map_or(1.0, |s| {
+                    s.parse::<f64>()
+                        .unwrap_or_else(|_| panic!("Invalid --speed '{}'", s))
+                }),
+            };
+            replay::replay(subc.value_of("file").map(String::from), options)
+        }
+        ("adherence", Some(subc)) => {
+            let agency = String::from(subc.value_of("agency").unwrap());
+            let route = String::from(subc.value_of("route").unwrap());
+            let options = adherence::AdherenceOptions {
+                timezone: parse_timezone(subc),
+                pause_seconds: parse_u64(subc, "pause_seconds"),
+            };
+            adherence::get_adherence(&client, agency, route, options)
+        }
+        ("headways", Some(subc)) => {
+            let agency = String::from(subc.value_of("agency").unwrap());
+            let route = String::from(subc.value_of("route").unwrap());
+            let options = headway::HeadwayOptions {
+                stops: subc
+                    .values_of("stops")
+                    .map(|s| s.map(String::from).collect())
+                    .unwrap_or_default(),
+                scheduled_headway: parse_u64(subc, "scheduled_headway").map(Duration::from_secs),
+                timezone: parse_timezone(subc),
+                bunching_ratio: parse_u64(subc, "bunching_percent")
+                    .unwrap_or(headway::DEFAULT_BUNCHING_PERCENT)
+                    as f64
+                    / 100.0,
+                gap_ratio: parse_u64(subc, "gap_percent").unwrap_or(headway::DEFAULT_GAP_PERCENT)
+                    as f64
+                    / 100.0,
+                pause_seconds: parse_u64(subc, "pause_seconds"),
+            };
+            headway::get_headways(&client, agency, route, options)
+        }
+        ("tui", Some(subc)) => {
+            let agency = String::from(subc.value_of("agency").unwrap());
+            let route = subc.value_of("route").map(String::from);
+            let pause_seconds =
+                parse_u64(subc, "pause_seconds").unwrap_or(tui::DEFAULT_PAUSE_SECONDS);
+            tui::run(&client, agency, route, pause_seconds)
         }
         (c, Some(_)) => panic!("Unimplemented subcommand '{}'", c),
         _ => panic!("Missing or invalid subcommand"),
+    };
+    // anything lenient parsing skipped that a polling loop hasn't already reported
+    client.report_drift();
+    result
+}
+
+// Global args given after a nested subcommand, e.g. `stops search`, are only in its matches
+fn innermost<'a>(subc: &'a ArgMatches<'a>) -> &'a ArgMatches<'a> {
+    match subc.subcommand() {
+        (_, Some(inner)) => innermost(inner),
+        _ => subc,
+    }
+}
+
+fn parse_u64(subc: &ArgMatches, name: &str) -> Option<u64> {
+    subc.value_of(name).map(|s| {
+        s.parse::<u64>()
+            .unwrap_or_else(|_| panic!("Must provide a positive integer, got '{}'", s))
+    })
+}
+
+fn parse_timezone(subc: &ArgMatches) -> schedule::Timezone {
+    let s = subc.value_of("timezone").unwrap();
+    s.parse()
+        .unwrap_or_else(|e: errors::Error| panic!("Invalid --timezone: {}", e))
+}
+
+// The routes given as arguments, or every route of the agency with --all
+fn parse_routes(
+    client: &client::Client,
+    agency: &String,
+    subc: &ArgMatches,
+) -> errors::Result<Vec<String>> {
+    match subc.values_of("route") {
+        Some(routes) => Ok(routes.map(String::from).collect()),
+        None => routes::get_route_tags(client, agency),
+    }
+}
+
+// A matcher for the route's config, or for every route of the agency when no route is given
+fn route_matcher(
+    client: &client::Client,
+    agency: &String,
+    route: &str,
+    subc: &ArgMatches,
+) -> errors::Result<mapmatch::RouteMatcher> {
+    let route_tags = match route.is_empty() {
+        true => routes::get_route_tags(client, agency)?,
+        false => vec![String::from(route)],
+    };
+    let configs = client.block_on(stops::get_stops_for_routes_async(
+        client,
+        agency,
+        &route_tags,
+    ))?;
+    let off_route_meters =
+        parse_u64(subc, "off_route").unwrap_or(mapmatch::DEFAULT_OFF_ROUTE_METERS);
+    Ok(mapmatch::RouteMatcher::new(
+        &configs,
+        off_route_meters as f64,
+    ))
+}
+
+// Output for more than one route, or for --all, is keyed by route
+fn keyed(subc: &ArgMatches) -> bool {
+    subc.is_present("all") || subc.values_of("route").map_or(0, |r| r.len()) > 1
+}
+
+fn parse_date(subc: &ArgMatches) -> Option<chrono::NaiveDate> {
+    subc.value_of("date").map(|s| {
+        chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .unwrap_or_else(|e| panic!("Invalid --date '{}': {}", s, e))
+    })
+}
+
+fn parse_time(subc: &ArgMatches, name: &str) -> Option<i64> {
+    subc.value_of(name)
+        .map(|s| schedule::parse_time(s).unwrap_or_else(|e| panic!("Invalid --{}: {}", name, e)))
+}
+
+fn parse_areas(subc: &ArgMatches) -> Vec<geo::Area> {
+    let mut areas = Vec::new();
+    if let Some(s) = subc.value_of("bbox") {
+        let bbox = s
+            .parse::<geo::BoundingBox>()
+            .unwrap_or_else(|e| panic!("Invalid --bbox: {}", e));
+        areas.push(geo::Area::Bounds(bbox));
+    }
+    if let Some(s) = subc.value_of("near") {
+        let center = s
+            .parse::<geo::LatLon>()
+            .unwrap_or_else(|e| panic!("Invalid --near: {}", e));
+        let radius_meters = parse_u64(subc, "radius").unwrap_or(DEFAULT_RADIUS_METERS);
+        areas.push(geo::Area::Circle {
+            center,
+            radius_meters: radius_meters as f64,
+        });
+    }
+    areas
+}
+
+fn parse_client_config(subc: &ArgMatches) -> client::ClientConfig {
+    let mut config = client::ClientConfig::default();
+    if let Some(s) = parse_u64(subc, "connect_timeout") {
+        config.connect_timeout = Duration::from_secs(s);
+    }
+    if let Some(s) = parse_u64(subc, "request_timeout") {
+        config.request_timeout = Duration::from_secs(s);
+    }
+    if let Some(n) = parse_u64(subc, "concurrency") {
+        config.concurrency = n as usize;
+    }
+    config.proxy = subc.value_of("proxy").map(String::from);
+    if let Some(url) = subc.value_of("feed_url") {
+        config.feed_url = String::from(url);
+    }
+    config.recording = match (subc.value_of("record_dir"), subc.value_of("replay_dir")) {
+        (Some(dir), _) => Some(recording::Recording::Record(PathBuf::from(dir))),
+        (None, Some(dir)) => Some(recording::Recording::Replay(PathBuf::from(dir))),
+        (None, None) => None,
+    };
+    config.lenient = subc.is_present("lenient");
+    config.rate_limits = parse_rate_limits(subc);
+    config
+}
+
+fn parse_rate_limits(subc: &ArgMatches) -> ratelimit::RateLimits {
+    let mut limits = ratelimit::RateLimits::default();
+    if let Some(b) = parse_u64(subc, "max_bytes") {
+        limits.max_bytes = b;
+    }
+    if let Some(s) = parse_u64(subc, "bytes_window") {
+        limits.bytes_window = Duration::from_secs(s);
+    }
+    if let Some(r) = parse_u64(subc, "max_requests") {
+        limits.max_requests = r;
+    }
+    if let Some(s) = parse_u64(subc, "requests_window") {
+        limits.requests_window = Duration::from_secs(s);
+    }
+    if let Some(s) = parse_u64(subc, "locations_interval") {
+        limits
+            .command_intervals
+            .insert(String::from("vehicleLocations"), Duration::from_secs(s));
     }
+    limits
 }
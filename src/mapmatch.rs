@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+
+use geo::{self, LatLon};
+use stops::FlatRoute;
+
+// A vehicle further than this from its route is reported as off-route
+pub const DEFAULT_OFF_ROUTE_METERS: u64 = 100;
+
+// A stop further than this from a path isn't taken to be on it
+const STOP_ON_PATH_METERS: f64 = 50.0;
+
+// Where a vehicle is along its route, from matching its position to the route's config
+// Flattened into `VehicleTime`, so its fields are named the same way
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RouteProgress {
+    // how far along its direction the vehicle is, measured along the route's path between
+    // each pair of the direction's stops, or straight between them where no path joins
+    // them. Only known when the vehicle reports a known direction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance_along_meters: Option<f64>,
+    // the last stop of the direction that the vehicle has passed, and the stop after it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_stop_tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_stop_tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance_to_next_stop_meters: Option<f64>,
+    // how far the vehicle is from the route's path, or from the line through its direction's
+    // stops if the route has no path
+    pub distance_from_route_meters: f64,
+    pub off_route: bool,
+}
+
+// The line a direction runs along, through its stops, and the distance to each of its stops
+// along it
+#[derive(Debug)]
+struct DirectionLine {
+    points: Vec<LatLon>,
+    stop_tags: Vec<String>,
+    distances: Vec<f64>,
+}
+
+// Matches vehicle positions to the paths of the routes it was made from
+#[derive(Debug)]
+pub struct RouteMatcher {
+    // by route tag and then direction tag
+    directions: HashMap<String, HashMap<String, DirectionLine>>,
+    paths: HashMap<String, Vec<Vec<LatLon>>>,
+    off_route_meters: f64,
+}
+
+impl RouteMatcher {
+    pub fn new(routes: &[FlatRoute], off_route_meters: f64) -> RouteMatcher {
+        let directions = routes
+            .iter()
+            .map(|r| {
+                let lines = r
+                    .directions
+                    .iter()
+                    .filter(|d| !d.stops.is_empty())
+                    .map(|d| {
+                        let stops: Vec<LatLon> =
+                            d.stops.iter().map(|s| LatLon::new(s.lat, s.lon)).collect();
+                        let (points, distances) = direction_line(&stops, &r.paths);
+                        let line = DirectionLine {
+                            points,
+                            stop_tags: d.stops.iter().map(|s| s.tag.clone()).collect(),
+                            distances,
+                        };
+                        (d.tag.clone(), line)
+                    })
+                    .collect();
+                (r.tag.clone(), lines)
+            })
+            .collect();
+        let paths = routes
+            .iter()
+            .map(|r| (r.tag.clone(), r.paths.clone()))
+            .collect();
+
+        RouteMatcher {
+            directions,
+            paths,
+            off_route_meters,
+        }
+    }
+
+    // The distance along a direction to each of its stops, in the direction's order
+    pub fn stop_distances(&self, route_tag: &str, dir_tag: &str) -> Option<&[f64]> {
+        self.directions
+            .get(route_tag)?
+            .get(dir_tag)
+            .map(|l| &l.distances[..])
+    }
+
+    // Where a vehicle on a route is along it, or None if the route is unknown
+    pub fn locate(
+        &self,
+        route_tag: &str,
+        dir_tag: &str,
+        position: &LatLon,
+    ) -> Option<RouteProgress> {
+        let lines = self.directions.get(route_tag)?;
+        let line = lines.get(dir_tag);
+        let along = line.and_then(|l| geo::locate_on_path(&l.points, position));
+
+        let from_path = self
+            .paths
+            .get(route_tag)
+            .into_iter()
+            .flatten()
+            .filter_map(|p| geo::locate_on_path(p, position))
+            .map(|p| p.offset)
+            .min_by(|a, b| a.total_cmp(b));
+        // without a direction, the nearest of the route's directions
+        let from_stops = || match along {
+            Some(a) => Some(a.offset),
+            None => lines
+                .values()
+                .filter_map(|l| geo::locate_on_path(&l.points, position))
+                .map(|p| p.offset)
+                .min_by(|a, b| a.total_cmp(b)),
+        };
+        let distance_from_route = from_path.or_else(from_stops)?;
+
+        let mut progress = RouteProgress {
+            distance_along_meters: None,
+            previous_stop_tag: None,
+            next_stop_tag: None,
+            distance_to_next_stop_meters: None,
+            distance_from_route_meters: distance_from_route,
+            off_route: distance_from_route > self.off_route_meters,
+        };
+        if let (Some(line), Some(along)) = (line, along) {
+            let distance = along.distance_along;
+            let next = line.distances.iter().position(|&d| d > distance);
+            let previous = match next {
+                Some(0) => None,
+                Some(i) => Some(i - 1),
+                None => Some(line.distances.len() - 1),
+            };
+            progress.distance_along_meters = Some(distance);
+            progress.previous_stop_tag = previous.map(|i| line.stop_tags[i].clone());
+            progress.next_stop_tag = next.map(|i| line.stop_tags[i].clone());
+            progress.distance_to_next_stop_meters = next.map(|i| line.distances[i] - distance);
+        }
+        Some(progress)
+    }
+}
+
+// Join a direction's stops into one line, following the route's path from each stop to the
+// next where one path passes both. The feed's paths are pieces of the route in no particular
+// order or direction, so they can't be followed as they are. Returns the line's
+// points and the distance to each stop along it.
+fn direction_line(stops: &[LatLon], paths: &[Vec<LatLon>]) -> (Vec<LatLon>, Vec<f64>) {
+    let mut points = vec![stops[0]];
+    let mut stop_indexes = vec![0];
+    for pair in stops.windows(2) {
+        let piece = paths
+            .iter()
+            .filter_map(|path| {
+                let from = geo::locate_on_path(path, &pair[0])?;
+                let to = geo::locate_on_path(path, &pair[1])?;
+                match from.offset <= STOP_ON_PATH_METERS
+                    && to.offset <= STOP_ON_PATH_METERS
+                    && (to.distance_along - from.distance_along).abs() > 0.0
+                {
+                    true => Some((from.offset + to.offset, path, from, to)),
+                    false => None,
+                }
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, path, from, to)| {
+                // a path may run either way
+                match from.distance_along < to.distance_along {
+                    true => geo::subpath(path, from.distance_along, to.distance_along),
+                    false => {
+                        let mut piece = geo::subpath(path, to.distance_along, from.distance_along);
+                        piece.reverse();
+                        piece
+                    }
+                }
+            })
+            .unwrap_or_else(|| pair.to_vec());
+        // the piece starts where the last one ended, near the same stop
+        points.extend(piece.into_iter().skip(1));
+        stop_indexes.push(points.len() - 1);
+    }
+
+    let distances = geo::cumulative_distances(&points);
+    let stop_distances = stop_indexes.into_iter().map(|i| distances[i]).collect();
+    (points, stop_distances)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::Coordinate;
+    use stops::{FlatDirection, Stop};
+
+    fn point(lat: f64, lon: f64) -> LatLon {
+        LatLon::new(Coordinate(lat), Coordinate(lon))
+    }
+
+    // A route with stops at three corners of a square about 1.1km across, whose path runs
+    // around the square's edges instead of across its diagonal
+    fn route(paths: Vec<Vec<LatLon>>) -> FlatRoute {
+        let stop = |tag: &str, lat: f64, lon: f64| Stop {
+            tag: String::from(tag),
+            title: String::new(),
+            lat: Coordinate(lat),
+            lon: Coordinate(lon),
+            stop_id: None,
+        };
+        FlatRoute {
+            tag: String::from("1"),
+            title: String::new(),
+            lat_min: Coordinate(37.76),
+            lat_max: Coordinate(37.78),
+            lon_min: Coordinate(-122.48),
+            lon_max: Coordinate(-122.46),
+            directions: vec![FlatDirection {
+                tag: String::from("1_out"),
+                title: String::new(),
+                name: String::new(),
+                use_for_ui: true,
+                stops: vec![
+                    stop("a", 37.76, -122.48),
+                    stop("b", 37.77, -122.47),
+                    stop("c", 37.78, -122.46),
+                ],
+            }],
+            paths,
+        }
+    }
+
+    #[test]
+    fn measures_progress_along_the_path() {
+        // out of order, one running backwards, and past the stops at either end
+        let paths = vec![
+            vec![
+                point(37.78, -122.45),
+                point(37.78, -122.47),
+                point(37.77, -122.47),
+            ],
+            vec![
+                point(37.75, -122.48),
+                point(37.77, -122.48),
+                point(37.77, -122.47),
+            ],
+        ];
+        let matcher = RouteMatcher::new(&[route(paths)], 100.0);
+
+        // on the path's corner between the first two stops, off their diagonal
+        let corner = point(37.77, -122.48);
+        let progress = matcher.locate("1", "1_out", &corner).unwrap();
+        let to_corner = point(37.76, -122.48).distance_to(&corner);
+        let to_b = corner.distance_to(&point(37.77, -122.47));
+        assert!((progress.distance_along_meters.unwrap() - to_corner).abs() < 0.01);
+        assert!((progress.distance_to_next_stop_meters.unwrap() - to_b).abs() < 0.01);
+        assert_eq!(progress.previous_stop_tag, Some(String::from("a")));
+        assert_eq!(progress.next_stop_tag, Some(String::from("b")));
+        assert!(progress.distance_from_route_meters < 0.01);
+        assert!(!progress.off_route);
+
+        // past the second stop, around the next corner
+        let progress = matcher
+            .locate("1", "1_out", &point(37.78, -122.468))
+            .unwrap();
+        assert_eq!(progress.previous_stop_tag, Some(String::from("b")));
+        assert_eq!(progress.next_stop_tag, Some(String::from("c")));
+        assert!(progress.distance_along_meters.unwrap() > to_corner + to_b + 1000.0);
+    }
+
+    #[test]
+    fn falls_back_to_the_line_through_the_stops() {
+        let matcher = RouteMatcher::new(&[route(vec![])], 100.0);
+        let progress = matcher
+            .locate("1", "1_out", &point(37.77, -122.47))
+            .unwrap();
+        let diagonal = point(37.76, -122.48).distance_to(&point(37.77, -122.47));
+        assert!((progress.distance_along_meters.unwrap() - diagonal).abs() < 0.01);
+        assert_eq!(progress.previous_stop_tag, Some(String::from("b")));
+
+        // the corner is far from the diagonal
+        let progress = matcher
+            .locate("1", "1_out", &point(37.77, -122.48))
+            .unwrap();
+        assert!(progress.off_route);
+    }
+}
@@ -0,0 +1,107 @@
+use serde_json;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use client;
+use errors::*;
+use geo::{Coordinate, LatLon};
+use prediction::{self, Predictions, RouteStop};
+use stopindex::{self, RouteDirection};
+
+#[derive(Serialize, Debug)]
+struct NearbyStop {
+    pub tag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_id: Option<String>,
+    pub title: String,
+    pub lat: Coordinate,
+    pub lon: Coordinate,
+    pub distance_meters: f64,
+    pub routes: Vec<RouteDirection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub predictions: Option<Vec<Predictions>>,
+}
+
+#[derive(Debug)]
+pub struct NearbyOptions {
+    pub radius_meters: f64,
+    pub limit: Option<usize>,
+    // attach live predictions for every route serving each stop
+    pub predictions: bool,
+    // rebuild the cached stop index if it is older than this
+    pub max_cache_age: Duration,
+}
+
+pub fn get_nearby(
+    client: &client::Client,
+    agency: String,
+    position: LatLon,
+    options: NearbyOptions,
+) -> Result<()> {
+    let index = stopindex::get_stop_index(client, &agency, options.max_cache_age)?;
+
+    let mut nearby: Vec<NearbyStop> = index
+        .stops
+        .into_iter()
+        .map(|s| (position.distance_to(&s.position()), s))
+        .filter(|&(distance, _)| distance <= options.radius_meters)
+        .map(|(distance, s)| NearbyStop {
+            tag: s.tag,
+            stop_id: s.stop_id,
+            title: s.title,
+            lat: s.lat,
+            lon: s.lon,
+            distance_meters: distance,
+            routes: s.routes,
+            predictions: None,
+        })
+        .collect();
+    nearby.sort_by(|a, b| a.distance_meters.total_cmp(&b.distance_meters));
+    if let Some(limit) = options.limit {
+        nearby.truncate(limit);
+    }
+
+    if options.predictions {
+        attach_predictions(client, &agency, &mut nearby)?;
+    }
+
+    println!("{}", serde_json::to_string(&nearby).unwrap());
+    Ok(())
+}
+
+fn attach_predictions(
+    client: &client::Client,
+    agency: &String,
+    nearby: &mut [NearbyStop],
+) -> Result<()> {
+    let mut route_stops: Vec<RouteStop> = Vec::new();
+    for stop in nearby.iter() {
+        for route in &stop.routes {
+            let route_stop = RouteStop {
+                route_tag: route.route_tag.clone(),
+                stop_tag: stop.tag.clone(),
+            };
+            if !route_stops.contains(&route_stop) {
+                route_stops.push(route_stop);
+            }
+        }
+    }
+
+    let predictions = client.block_on(prediction::get_route_stop_predictions_async(
+        client,
+        agency,
+        &route_stops,
+    ))?;
+
+    let mut predictions_for_stops: HashMap<String, Vec<Predictions>> = HashMap::new();
+    for p in predictions.map(|p| p.predictions).unwrap_or_default() {
+        predictions_for_stops
+            .entry(p.stop_tag.clone())
+            .or_default()
+            .push(p);
+    }
+    for stop in nearby.iter_mut() {
+        stop.predictions = Some(predictions_for_stops.remove(&stop.tag).unwrap_or_default());
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+
+use errors::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    // comma-separated values with a header row, for spreadsheets
+    Csv,
+    // human-readable text, for reading at a glance in a terminal
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("Unknown output format '{}'", s).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    Bold,
+    Dim,
+    Red,
+    Yellow,
+}
+
+// Styles text with ANSI escape codes, but only when writing to a terminal
+#[derive(Debug, Clone, Copy)]
+pub struct Styler {
+    pub enabled: bool,
+}
+
+impl Styler {
+    pub fn for_stdout() -> Styler {
+        Styler {
+            enabled: is_stdout_tty(),
+        }
+    }
+
+    pub fn paint(&self, color: Color, text: &str) -> String {
+        if !self.enabled {
+            return String::from(text);
+        }
+        let code = match color {
+            Color::Bold => "1",
+            Color::Dim => "2",
+            Color::Red => "31",
+            Color::Yellow => "33",
+        };
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    }
+}
+
+// A line of CSV, quoting the fields that need it
+pub fn csv_line(fields: &[&str]) -> String {
+    fields
+        .iter()
+        .map(|f| match f.contains([',', '"', '\n', '\r']) {
+            true => format!("\"{}\"", f.replace('"', "\"\"")),
+            false => String::from(*f),
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+pub fn is_stdout_tty() -> bool {
+    io::stdout().is_terminal()
+}
+
+// Move the cursor to the top left and clear the screen, so the next output replaces the last
+pub fn clear_screen() -> &'static str {
+    "\x1b[H\x1b[2J"
+}
@@ -1,41 +1,67 @@
 use error_chain::ChainedError;
+use futures::Future;
 
 use serde_json;
+use std::str::FromStr;
 use std::thread;
 use std::time::Duration;
 
+use board;
 use client;
 use client::from_string;
 use errors::*;
+use output::{self, Styler};
 use stops;
 
+// How predictions are printed, which unlike schedules can't be CSV
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    // an arrivals board, for reading at a glance in a terminal
+    Board,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "json" => Ok(Format::Json),
+            "table" => Ok(Format::Board),
+            _ => Err(format!("Unknown predictions format '{}'", s).into()),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct PredictionsList {
+pub struct PredictionsList {
     pub predictions: Vec<Predictions>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct Predictions {
+pub struct Predictions {
     #[serde(rename = "direction", default)]
     pub directions: Vec<Direction>,
     pub agency_title: String,
     pub route_title: String,
     pub route_tag: String,
+    #[serde(default)]
+    pub stop_title: String,
     pub stop_tag: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct Direction {
+pub struct Direction {
     pub title: String,
     pub prediction: Vec<Prediction>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct Prediction {
+pub struct Prediction {
     #[serde(deserialize_with = "from_string")]
     #[serde(rename = "epochTime")]
     pub epoch: u64,
@@ -55,8 +81,11 @@ struct Prediction {
     pub vehicle: String,
     #[serde(deserialize_with = "from_string", default)]
     pub vehicles_in_consist: u32,
-    pub block: String,
-    pub trip_tag: String,
+    // missing for some agencies
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub block: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub trip_tag: Option<String>,
 }
 
 impl client::Contents for PredictionsList {
@@ -71,49 +100,174 @@ impl client::Contents for PredictionsList {
     }
 }
 
-fn get_predictions_url(agency: &String, route: &String, stops: &Vec<String>) -> String {
+// predictionsForMultiStops takes every stop in the URL, so predictions for many stops are
+// requested in batches to keep URLs to a reasonable length
+const STOPS_PER_REQUEST: usize = 100;
+
+// A stop on a particular route, since stop tags may be shared by several routes
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteStop {
+    pub route_tag: String,
+    pub stop_tag: String,
+}
+
+fn get_predictions_url(agency: &String, stops: &[RouteStop]) -> String {
     let route_stops: Vec<String> = stops
-        .into_iter()
-        .map(|s| route.to_string() + "|" + s)
+        .iter()
+        .map(|s| s.route_tag.to_string() + "|" + &s.stop_tag)
         .collect();
     format!(
-        "https://retro.umoiq.com/service/publicXMLFeed?command=predictionsForMultiStops&a={agency}&stops={stops}",
+        "{feed}?command=predictionsForMultiStops&a={agency}&stops={stops}",
+        feed = client::DEFAULT_FEED_URL,
         agency = agency,
         stops = route_stops.join("&stops="),
     )
 }
 
 pub fn get_predictions(
+    client: &client::Client,
     agency: String,
     route: String,
     stops: Vec<String>,
     pause_seconds: Option<u64>,
+    format: Format,
 ) -> Result<()> {
     let stops = match stops.len() {
-        0 => stops::get_stop_tags(&agency, &route)?,
+        0 => stops::get_stop_tags(client, &agency, &route)?,
         _ => stops,
     };
 
     loop {
-        let url = get_predictions_url(&agency, &route, &stops);
-        let downloaded: Option<PredictionsList> = client::download(&url).unwrap_or_else(|e| {
-            warn!(
-                "Download error: {} from URL={}",
-                e.display_chain().to_string(),
-                url
-            );
-            None
-        });
-        let predictions = match downloaded {
-            Some(predictions) => predictions,
-            None => continue,
-        };
-        let predictions_json = serde_json::to_string(&predictions).unwrap();
-        println!("{}", predictions_json);
+        let downloaded = client
+            .block_on(get_predictions_async(client, &agency, &route, &stops))
+            .unwrap_or_else(|e| {
+                warn!(
+                    "Download error: {} for route={}",
+                    e.display_chain().to_string(),
+                    route
+                );
+                None
+            });
+        match (downloaded, format) {
+            (Some(predictions), Format::Json) => {
+                let predictions_json = serde_json::to_string(&predictions).unwrap();
+                println!("{}", predictions_json);
+            }
+            (Some(predictions), Format::Board) => {
+                let styler = Styler::for_stdout();
+                // redraw the board in place when polling in a terminal
+                if pause_seconds.is_some() && styler.enabled {
+                    print!("{}", output::clear_screen());
+                }
+                println!("{}", board::render(predictions, styler));
+            }
+            (None, _) => (),
+        }
 
+        client.report_drift();
         match pause_seconds {
             None => return Ok(()),
             Some(s) => thread::sleep(Duration::from_millis(s * 1000)),
         }
     }
 }
+
+// Async equivalent of a single predictions request, fetching batches of stops concurrently
+// and combining them into one list
+pub fn get_predictions_async(
+    client: &client::Client,
+    agency: &String,
+    route: &str,
+    stops: &[String],
+) -> impl Future<Item = Option<PredictionsList>, Error = Error> + Send {
+    let route_stops: Vec<RouteStop> = stops
+        .iter()
+        .map(|s| RouteStop {
+            route_tag: String::from(route),
+            stop_tag: s.clone(),
+        })
+        .collect();
+    get_route_stop_predictions_async(client, agency, &route_stops)
+}
+
+// Like `get_predictions_async`, for stops on any number of routes
+pub fn get_route_stop_predictions_async(
+    client: &client::Client,
+    agency: &String,
+    stops: &[RouteStop],
+) -> impl Future<Item = Option<PredictionsList>, Error = Error> + Send {
+    let urls: Vec<String> = stops
+        .chunks(STOPS_PER_REQUEST)
+        .map(|batch| get_predictions_url(agency, batch))
+        .collect();
+    client::download_all(client, urls).map(|batches: Vec<Option<PredictionsList>>| {
+        let predictions: Vec<Predictions> = batches
+            .into_iter()
+            .flatten()
+            .flat_map(|b| b.predictions)
+            .collect();
+        match predictions.len() {
+            0 => None,
+            _ => Some(PredictionsList { predictions }),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::Contents;
+    use serde_xml_rs::deserialize;
+
+    #[test]
+    fn parses_sf_muni_predictions() {
+        let list: PredictionsList = deserialize(
+            &include_bytes!("../fixtures/recorded/responses/command_predictionsForMultiStops_a_sf-muni_stops_N_5186_stops_N_5178-438d886d2fe129c9-0.xml")[..],
+        )
+        .unwrap();
+        assert!(!list.is_empty());
+        assert_eq!(list.predictions.len(), 2);
+
+        let stop = &list.predictions[0];
+        assert_eq!(stop.agency_title, "San Francisco Muni");
+        assert_eq!((&stop.route_tag[..], &stop.stop_tag[..]), ("N", "5186"));
+        assert_eq!(stop.stop_title, "Judah St & 28th Ave");
+        assert_eq!(stop.directions.len(), 1);
+
+        let predictions = &stop.directions[0].prediction;
+        assert_eq!(predictions.len(), 2);
+        assert_eq!(predictions[0].epoch, 1792415580000);
+        assert_eq!((predictions[0].seconds, predictions[0].minutes), (60, 1));
+        assert_eq!(predictions[0].vehicles_in_consist, 2);
+        assert_eq!(predictions[0].block, Some(String::from("9701")));
+        assert_eq!(predictions[0].trip_tag, Some(String::from("11208765")));
+        assert!(!predictions[0].affected_by_layover);
+        assert!(predictions[1].affected_by_layover);
+
+        assert!(list.predictions[1].directions[0].prediction[0].delayed);
+    }
+
+    #[test]
+    fn parses_predictions_without_blocks() {
+        let list: PredictionsList = deserialize(
+            &include_bytes!("../fixtures/recorded/responses/command_predictionsForMultiStops_a_ttc_stops_504_15637-0fc61963c40d2fed-0.xml")[..],
+        )
+        .unwrap();
+        let prediction = &list.predictions[0].directions[0].prediction[0];
+        assert_eq!(prediction.vehicle, "4402");
+        assert_eq!(prediction.dir_tag, "504_0_504A");
+        assert_eq!(prediction.block, None);
+        assert_eq!(prediction.trip_tag, None);
+        assert_eq!(prediction.vehicles_in_consist, 0);
+    }
+
+    #[test]
+    fn parses_stops_without_predictions() {
+        let list: PredictionsList = deserialize(
+            &include_bytes!("../fixtures/recorded/responses/command_predictionsForMultiStops_a_unitrans_stops_A_22258-9527e746dad8279f-0.xml")[..],
+        )
+        .unwrap();
+        assert!(list.is_empty());
+        assert_eq!(list.predictions[0].stop_title, "Silo Terminal");
+    }
+}
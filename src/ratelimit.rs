@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The feed documents a limit of about 2MB per 20 seconds per client, and asks that clients
+// not poll vehicle locations more often than every 10 seconds.
+// https://retro.umoiq.com/xmlFeedDocs/NextBusXMLFeed.pdf
+pub const DEFAULT_MAX_BYTES: u64 = 2_000_000;
+pub const DEFAULT_BYTES_WINDOW_SECONDS: u64 = 20;
+pub const DEFAULT_MAX_REQUESTS: u64 = 20;
+pub const DEFAULT_REQUESTS_WINDOW_SECONDS: u64 = 10;
+pub const DEFAULT_LOCATIONS_INTERVAL_SECONDS: u64 = 10;
+
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub max_bytes: u64,
+    pub bytes_window: Duration,
+    pub max_requests: u64,
+    pub requests_window: Duration,
+    // minimum time between two requests for the same feed command, e.g. vehicleLocations, for
+    // the same agency and route
+    pub command_intervals: HashMap<String, Duration>,
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        let mut command_intervals = HashMap::new();
+        command_intervals.insert(
+            String::from("vehicleLocations"),
+            Duration::from_secs(DEFAULT_LOCATIONS_INTERVAL_SECONDS),
+        );
+        RateLimits {
+            max_bytes: DEFAULT_MAX_BYTES,
+            bytes_window: Duration::from_secs(DEFAULT_BYTES_WINDOW_SECONDS),
+            max_requests: DEFAULT_MAX_REQUESTS,
+            requests_window: Duration::from_secs(DEFAULT_REQUESTS_WINDOW_SECONDS),
+            command_intervals,
+        }
+    }
+}
+
+// A bucket of `capacity` tokens that refills continuously over `window`. The level may go
+// negative when a cost is only known after the fact (e.g. response bytes), in which case
+// the caller waits until the debt has been paid back.
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u64, window: Duration) -> TokenBucket {
+        let capacity = capacity as f64;
+        let window_secs = window.as_secs_f64().max(f64::EPSILON);
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / window_secs,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.updated = now;
+    }
+
+    // How long until at least `amount` tokens are available
+    fn wait_time(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let needed = amount.min(self.capacity) - self.tokens;
+        if needed <= 0.0 || self.refill_per_sec <= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(needed / self.refill_per_sec)
+        }
+    }
+
+    fn consume(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.tokens -= amount;
+    }
+}
+
+// The feed command, agency and route of a request, e.g. vehicleLocations for sf-muni's N
+type RequestKey = (String, String, String);
+
+#[derive(Debug)]
+struct LimiterState {
+    bytes: TokenBucket,
+    requests: TokenBucket,
+    last_requests: HashMap<RequestKey, Instant>,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<LimiterState>,
+    command_intervals: HashMap<String, Duration>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            state: Mutex::new(LimiterState {
+                bytes: TokenBucket::new(limits.max_bytes, limits.bytes_window),
+                requests: TokenBucket::new(limits.max_requests, limits.requests_window),
+                last_requests: HashMap::new(),
+            }),
+            command_intervals: limits.command_intervals,
+        }
+    }
+
+    // Block until a request for the given URL is allowed, then count it against the limits
+    pub fn acquire(&self, url: &str) {
+        while let Some(wait) = self.try_acquire(url) {
+            thread::sleep(wait);
+        }
+    }
+
+    // Count a request for the given URL against the limits if it is allowed now, otherwise
+    // return how long to wait before trying again
+    pub fn try_acquire(&self, url: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let key = request_key(url);
+        let mut state = self.state.lock().unwrap();
+
+        // wait for any bandwidth debt from earlier responses to be paid off
+        let bytes_wait = state.bytes.wait_time(0.0, now);
+        let requests_wait = state.requests.wait_time(1.0, now);
+        let command_wait = key
+            .as_ref()
+            .and_then(|key| {
+                let interval = self.command_intervals.get(&key.0)?;
+                let last = state.last_requests.get(key)?;
+                Some((*last + *interval).saturating_duration_since(now))
+            })
+            .unwrap_or_else(|| Duration::from_secs(0));
+
+        let wait = bytes_wait.max(requests_wait).max(command_wait);
+        if wait > Duration::from_secs(0) {
+            info!(
+                r#"throttled request="{}" wait_ms="{}""#,
+                url,
+                wait.as_millis()
+            );
+            return Some(wait);
+        }
+
+        state.requests.consume(1.0, now);
+        if let Some(key) = key {
+            state.last_requests.insert(key, now);
+        }
+        None
+    }
+
+    // Count a response body against the bandwidth limit once its size is known
+    pub fn record_bytes(&self, bytes: usize) {
+        self.state
+            .lock()
+            .unwrap()
+            .bytes
+            .consume(bytes as f64, Instant::now());
+    }
+}
+
+// The command of a feed URL, with its agency and route, which are empty for commands that
+// don't take them
+fn request_key(url: &str) -> Option<RequestKey> {
+    let command = query_param(url, "command")?;
+    let param = |name| String::from(query_param(url, name).unwrap_or(""));
+    Some((String::from(command), param("a"), param("r")))
+}
+
+// Extract the value of a query parameter from a feed URL
+fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    let query = url.split('?').nth(1)?;
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) if key == name => Some(value),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refills_over_its_window() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, Duration::from_secs(10));
+        bucket.updated = start;
+        bucket.consume(10.0, start);
+        assert_eq!(bucket.wait_time(1.0, start), Duration::from_secs(1));
+
+        // one token a second, up to the capacity
+        let later = start + Duration::from_secs(4);
+        assert_eq!(bucket.wait_time(1.0, later), Duration::from_secs(0));
+        assert_eq!(bucket.tokens, 4.0);
+        bucket.refill(start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 10.0);
+    }
+
+    #[test]
+    fn bucket_waits_for_debt_to_be_paid() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100, Duration::from_secs(10));
+        bucket.updated = start;
+        // a response bigger than the whole bucket
+        bucket.consume(150.0, start);
+        assert_eq!(bucket.wait_time(0.0, start), Duration::from_secs(5));
+        // and no more than the capacity is ever waited for
+        bucket.refill(start + Duration::from_secs(5));
+        assert_eq!(
+            bucket.wait_time(1000.0, start + Duration::from_secs(5)),
+            Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn allows_a_burst_of_requests_then_throttles() {
+        let limits = RateLimits {
+            max_requests: 3,
+            requests_window: Duration::from_secs(30),
+            ..RateLimits::default()
+        };
+        let limiter = RateLimiter::new(limits);
+        let url = "https://example.com/feed?command=routeList&a=sf-muni";
+        for _ in 0..3 {
+            assert_eq!(limiter.try_acquire(url), None);
+        }
+        let wait = limiter.try_acquire(url).unwrap();
+        assert!(wait > Duration::from_secs(9) && wait <= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn spaces_requests_for_a_command_by_its_interval() {
+        let limiter = RateLimiter::new(RateLimits::default());
+        let locations = "https://example.com/feed?command=vehicleLocations&a=sf-muni&r=N&t=0";
+        assert_eq!(limiter.try_acquire(locations), None);
+        let wait = limiter.try_acquire(locations).unwrap();
+        assert!(wait > Duration::from_secs(9) && wait <= Duration::from_secs(10));
+        // even from a later epoch
+        let later = "https://example.com/feed?command=vehicleLocations&a=sf-muni&r=N&t=1000";
+        assert!(limiter.try_acquire(later).is_some());
+        // other commands, and other routes and agencies, aren't held up
+        for url in &[
+            "https://example.com/feed?command=routeList&a=sf-muni",
+            "https://example.com/feed?command=vehicleLocations&a=sf-muni&r=J&t=0",
+            "https://example.com/feed?command=vehicleLocations&a=ttc&r=N&t=0",
+        ] {
+            assert_eq!(limiter.try_acquire(url), None);
+        }
+    }
+
+    #[test]
+    fn throttles_after_too_many_bytes() {
+        let limiter = RateLimiter::new(RateLimits::default());
+        let url = "https://example.com/feed?command=routeList&a=sf-muni";
+        assert_eq!(limiter.try_acquire(url), None);
+        limiter.record_bytes(DEFAULT_MAX_BYTES as usize + 100_000);
+        let wait = limiter.try_acquire(url).unwrap();
+        // 100kB of debt at 100kB a second
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn keys_requests_by_command_agency_and_route() {
+        let key = |command: &str, agency: &str, route: &str| {
+            Some((
+                String::from(command),
+                String::from(agency),
+                String::from(route),
+            ))
+        };
+        assert_eq!(
+            request_key("https://example.com/feed?a=sf-muni&command=vehicleLocations&r=N&t=0"),
+            key("vehicleLocations", "sf-muni", "N")
+        );
+        assert_eq!(
+            request_key("https://example.com/feed?command=routeList&a=sf-muni"),
+            key("routeList", "sf-muni", "")
+        );
+        assert_eq!(request_key("https://example.com/feed?a=sf-muni"), None);
+        assert_eq!(request_key("https://example.com/feed"), None);
+    }
+}
@@ -0,0 +1,168 @@
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::StatusCode;
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use errors::*;
+
+// Longest part of a recording's file name taken from its URL, well under file name limits
+const MAX_NAME_CHARS: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recording {
+    // save every response to files in the directory
+    Record(PathBuf),
+    // answer every request with the responses saved in the directory, without the network
+    Replay(PathBuf),
+}
+
+// A response as it came from the feed, or as it was recorded
+#[derive(Debug)]
+pub struct RecordedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+// Everything about a response but its body, which is saved next to it as-is
+#[derive(Serialize, Deserialize, Debug)]
+struct ResponseMeta {
+    pub url: String,
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+}
+
+// Records or replays responses. The same URL can be requested more than once, e.g. when
+// polling, so each response to a URL is numbered in the order it was requested, and a replay
+// serves them in that order, repeating the last once they run out.
+#[derive(Debug)]
+pub struct Recorder {
+    recording: Recording,
+    counts: Mutex<HashMap<String, usize>>,
+}
+
+impl Recorder {
+    pub fn new(recording: Recording) -> Result<Recorder> {
+        if let Recording::Record(ref dir) = recording {
+            fs::create_dir_all(dir)
+                .chain_err(|| format!("Unable to create record directory {}", dir.display()))?;
+        }
+        Ok(Recorder {
+            recording,
+            counts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // The recorded response to a URL when replaying, or None when the request should go to
+    // the network
+    pub fn replayed(&self, url: &str) -> Option<Result<RecordedResponse>> {
+        match self.recording {
+            Recording::Replay(ref dir) => Some(self.read(dir, url)),
+            Recording::Record(_) => None,
+        }
+    }
+
+    // Save a response from the network, when recording
+    pub fn record(&self, url: &str, response: &RecordedResponse) -> Result<()> {
+        let dir = match self.recording {
+            Recording::Record(ref dir) => dir,
+            Recording::Replay(_) => return Ok(()),
+        };
+        let name = file_name(url);
+        let path = dir.join(format!("{}-{}", name, self.next(&name)));
+
+        let meta = ResponseMeta {
+            url: String::from(url),
+            status: response.status.as_u16(),
+            headers: response
+                .headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), String::from_utf8_lossy(v.as_bytes()).into()))
+                .collect(),
+        };
+        fs::write(
+            path.with_extension("json"),
+            serde_json::to_string_pretty(&meta).unwrap(),
+        )
+        .and_then(|_| fs::write(path.with_extension("xml"), &response.body))
+        .chain_err(|| format!("Unable to record response to {}", path.display()))
+    }
+
+    fn read(&self, dir: &Path, url: &str) -> Result<RecordedResponse> {
+        let name = file_name(url);
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(name.clone()).or_insert(0);
+        let path = match dir.join(format!("{}-{}", name, count)) {
+            p if p.with_extension("json").exists() => {
+                *count += 1;
+                p
+            }
+            _ if *count > 0 => dir.join(format!("{}-{}", name, *count - 1)),
+            _ => {
+                return Err(
+                    format!("No recorded response in {} for URL={}", dir.display(), url).into(),
+                )
+            }
+        };
+        drop(counts);
+
+        let meta: ResponseMeta = serde_json::from_str(
+            &fs::read_to_string(path.with_extension("json"))
+                .chain_err(|| format!("Unable to read {}", path.display()))?,
+        )
+        .chain_err(|| format!("Invalid recorded response {}", path.display()))?;
+        let body = fs::read_to_string(path.with_extension("xml"))
+            .chain_err(|| format!("Unable to read {}", path.display()))?;
+
+        let mut headers = HeaderMap::new();
+        for (k, v) in &meta.headers {
+            if let (Ok(k), Ok(v)) = (
+                HeaderName::from_bytes(k.as_bytes()),
+                HeaderValue::from_str(v),
+            ) {
+                headers.append(k, v);
+            }
+        }
+        Ok(RecordedResponse {
+            status: StatusCode::from_u16(meta.status)
+                .chain_err(|| format!("Invalid recorded status {}", meta.status))?,
+            headers,
+            body,
+        })
+    }
+
+    // The number of the next response to a URL with the given file name
+    fn next(&self, name: &str) -> usize {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(String::from(name)).or_insert(0);
+        *count += 1;
+        *count - 1
+    }
+}
+
+// A file name for a URL's responses, from its query, e.g.
+// command_routeConfig_a_sf-muni_r_N-0c3a1b5e9f2d4a68, with a hash of the whole URL to tell
+// apart URLs whose queries are cut short
+fn file_name(url: &str) -> String {
+    let query = url.split('?').nth(1).unwrap_or(url);
+    let readable: String = query
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '_',
+        })
+        .take(MAX_NAME_CHARS)
+        .collect();
+    format!("{}-{:016x}", readable, fnv1a(url))
+}
+
+// A hash that stays the same across builds, unlike the standard library's, so recordings can
+// be replayed by any build
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,149 @@
+use serde_json::{self, Value};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use errors::*;
+
+#[derive(Debug)]
+pub struct ReplayOptions {
+    // how many times faster than it was recorded to replay the output
+    pub speed: f64,
+}
+
+// Print the lines of recorded JSON output as they were printed live, spaced out by the times
+// they were recorded at divided by `speed`. Lines that aren't JSON, or that don't say when
+// they were recorded, are printed straight after the line before them.
+pub fn replay(path: Option<String>, options: ReplayOptions) -> Result<()> {
+    if options.speed <= 0.0 {
+        return Err(format!("Replay speed must be positive, got {}", options.speed).into());
+    }
+    let reader: Box<dyn BufRead> = match path {
+        Some(ref p) if p != "-" => Box::new(BufReader::new(File::open(p)?)),
+        _ => Box::new(BufReader::new(io::stdin())),
+    };
+
+    // the first recorded epoch, and when it was replayed
+    let mut start: Option<(u64, Instant)> = None;
+    // records within a response aren't in order of their report times, so the replay waits for
+    // the latest epoch seen so far, and never goes back in time
+    let mut latest = 0;
+    for line in reader.lines() {
+        let line = line?;
+        let epoch = serde_json::from_str(&line)
+            .ok()
+            .and_then(|value: Value| recorded_epoch(&value));
+        if let Some(epoch) = epoch {
+            latest = latest.max(epoch);
+            match start {
+                None => start = Some((epoch, Instant::now())),
+                Some((first, started)) => {
+                    let offset = (latest - first) as f64 / 1000.0 / options.speed;
+                    let due = started + Duration::from_secs_f64(offset);
+                    let now = Instant::now();
+                    if due > now {
+                        thread::sleep(due - now);
+                    }
+                }
+            }
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+// Milliseconds since the Unix epoch when a line of output was recorded, from the report times
+// of vehicles or the times that predictions were made
+fn recorded_epoch(value: &Value) -> Option<u64> {
+    match value {
+        // a response of vehicle locations, or of schedule adherence
+        Value::Array(values) => values.iter().filter_map(recorded_epoch).max(),
+        Value::Object(fields) => {
+            if let Some(epoch) = fields
+                .get("epoch")
+                .or_else(|| fields.get("prediction_epoch"))
+                .and_then(Value::as_u64)
+            {
+                return Some(epoch);
+            }
+            // predictions only say when vehicles will arrive and how many seconds away that
+            // is, which together give when the prediction was made
+            fields
+                .get("predictions")?
+                .as_array()?
+                .iter()
+                .filter_map(|p| p.get("direction")?.as_array())
+                .flatten()
+                .filter_map(|d| d.get("prediction")?.as_array())
+                .flatten()
+                .filter_map(|p| {
+                    let arrival = p.get("epochTime")?.as_u64()?;
+                    let seconds = p.get("seconds")?.as_u64()?;
+                    Some(arrival.saturating_sub(seconds * 1000))
+                })
+                .max()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn epoch(json: &str) -> Option<u64> {
+        recorded_epoch(&serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn takes_the_latest_report_time_of_a_response() {
+        assert_eq!(
+            epoch(r#"[{"id":"1501","epoch":1792414790000},{"id":"1502","epoch":1792414795000}]"#),
+            Some(1792414795000)
+        );
+        assert_eq!(
+            epoch(r#"{"id":"1501","epoch":1792414790000}"#),
+            Some(1792414790000)
+        );
+        // accuracy collect's predictions
+        assert_eq!(
+            epoch(
+                r#"{"type":"prediction","predicted_epoch":1792415400000,"prediction_epoch":1792414800000}"#
+            ),
+            Some(1792414800000)
+        );
+        assert_eq!(epoch("[]"), None);
+    }
+
+    #[test]
+    fn takes_when_predictions_were_made() {
+        // the latest of each arrival less the seconds until it
+        let predictions = r#"{"predictions":[
+            {"stopTag":"5186","direction":[{"prediction":[
+                {"epochTime":1792415400000,"seconds":600,"vehicle":"1501"},
+                {"epochTime":1792415460000,"seconds":655,"vehicle":"1502"}
+            ]}]},
+            {"stopTag":"4006","direction":[]},
+            {"stopTag":"5205"}
+        ]}"#;
+        assert_eq!(epoch(predictions), Some(1792414805000));
+
+        // more seconds away than the arrival's epoch time doesn't wrap around
+        assert_eq!(
+            epoch(
+                r#"{"predictions":[{"direction":[{"prediction":[{"epochTime":1000,"seconds":5}]}]}]}"#
+            ),
+            Some(0)
+        );
+        assert_eq!(epoch(r#"{"predictions":[{"direction":[]}]}"#), None);
+    }
+
+    #[test]
+    fn has_no_time_for_other_lines() {
+        assert_eq!(epoch(r#"{"route_tag":"N"}"#), None);
+        assert_eq!(epoch(r#"{"epoch":"soon"}"#), None);
+        assert_eq!(epoch("42"), None);
+        assert_eq!(epoch(r#""text""#), None);
+    }
+}
@@ -5,14 +5,14 @@ use serde_json;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct Routes {
+pub struct Routes {
     #[serde(rename = "route")]
     pub routes: Vec<Route>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct Route {
+pub struct Route {
     pub tag: String,
     pub title: String,
 }
@@ -25,14 +25,15 @@ impl client::Contents for Routes {
 
 fn get_routes_url(agency: &String) -> String {
     format!(
-        "https://retro.umoiq.com/service/publicXMLFeed?command=routeList&a={agency}",
+        "{feed}?command=routeList&a={agency}",
+        feed = client::DEFAULT_FEED_URL,
         agency = agency,
     )
 }
 
-fn _get_routes(agency: &String) -> Result<Routes> {
+pub fn _get_routes(client: &client::Client, agency: &String) -> Result<Routes> {
     let url = get_routes_url(agency);
-    let downloaded: Option<Routes> = client::download(&url).unwrap_or_else(|e| {
+    let downloaded: Option<Routes> = client::download(client, &url).unwrap_or_else(|e| {
         warn!(
             "Download error: {} from URL={}",
             e.display_chain().to_string(),
@@ -44,16 +45,64 @@ fn _get_routes(agency: &String) -> Result<Routes> {
     Ok(routes)
 }
 
-pub fn get_routes(agency: String) -> Result<()> {
-    let routes = _get_routes(&agency)?;
+pub fn get_routes(client: &client::Client, agency: String) -> Result<()> {
+    let routes = _get_routes(client, &agency)?;
     let routes_json = serde_json::to_string(&routes).unwrap();
     println!("{}", routes_json);
     Ok(())
 }
 
-#[allow(dead_code)]
-pub fn get_route_tags(agency: &String) -> Result<Vec<String>> {
-    let route_list = _get_routes(agency)?;
+pub fn get_route_tags(client: &client::Client, agency: &String) -> Result<Vec<String>> {
+    let route_list = _get_routes(client, agency)?;
     let routes: Vec<String> = route_list.routes.into_iter().map(|r| r.tag).collect();
     Ok(routes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_xml_rs::deserialize;
+
+    #[test]
+    fn parses_route_lists() {
+        let routes: Routes = deserialize(
+            &include_bytes!(
+                "../fixtures/recorded/responses/command_routeList_a_sf-muni-241efc7a2d589ae6-0.xml"
+            )[..],
+        )
+        .unwrap();
+        let tags: Vec<&str> = routes.routes.iter().map(|r| &r.tag[..]).collect();
+        assert_eq!(tags, vec!["J", "N"]);
+        assert_eq!(routes.routes[1].title, "N-Judah");
+
+        let routes: Routes = deserialize(
+            &include_bytes!(
+                "../fixtures/recorded/responses/command_routeList_a_ttc-972f55c22bced258-0.xml"
+            )[..],
+        )
+        .unwrap();
+        let routes: Vec<(&str, &str)> = routes
+            .routes
+            .iter()
+            .map(|r| (&r.tag[..], &r.title[..]))
+            .collect();
+        assert_eq!(routes, vec![("501", "501-Queen"), ("504", "504-King")]);
+
+        // a title that is just the tag
+        let routes: Routes =
+            deserialize(&include_bytes!("../fixtures/recorded/responses/command_routeList_a_unitrans-72fea459f723c153-0.xml")[..])
+                .unwrap();
+        assert_eq!(routes.routes.len(), 1);
+        assert_eq!(routes.routes[0].tag, "A");
+        assert_eq!(routes.routes[0].title, "A");
+    }
+
+    #[test]
+    fn ignores_route_attributes_it_doesnt_use() {
+        let routes: Routes = deserialize(
+            &br#"<body><route tag="501" title="501-Queen" shortTitle="Queen"/></body>"#[..],
+        )
+        .unwrap();
+        assert_eq!(routes.routes[0].title, "501-Queen");
+    }
+}
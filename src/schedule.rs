@@ -1,33 +1,58 @@
-use error_chain::ChainedError;
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDate, NaiveTime, TimeZone, Weekday};
+use chrono_tz::Tz;
+use futures::Future;
+use serde::Deserializer;
+use std::str::FromStr;
 
 use serde_json;
 
 use client;
 use client::from_string;
 use errors::*;
+use output::OutputFormat;
+use time::{self, MS_PER_DAY};
+use timetable::{self, View};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct Schedule {
+pub struct Schedule {
     #[serde(rename = "route")]
     pub routes: Vec<Route>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct Route {
+pub struct Route {
     pub tag: String,
     pub title: String,
     pub schedule_class: String,
     pub service_class: String,
     pub direction: String,
+    #[serde(default)]
+    pub header: Header,
     #[serde(rename = "tr")]
     pub blocks: Vec<VehicleBlock>,
 }
 
+// The stops that every block of a schedule table lists times for, in order
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Header {
+    #[serde(rename = "stop", default)]
+    pub stops: Vec<HeaderStop>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct VehicleBlock {
+pub struct HeaderStop {
+    pub tag: String,
+    #[serde(rename(deserialize = "$value"), default)]
+    pub title: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VehicleBlock {
     #[serde(rename = "blockID")]
     pub block_id: String,
     #[serde(rename = "stop")]
@@ -36,10 +61,265 @@ struct VehicleBlock {
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct VehicleStop {
+pub struct VehicleStop {
     pub tag: String,
-    #[serde(deserialize_with = "from_string", default)]
-    pub epoch_time: i64,
+    // milliseconds since the start of the service day, or None for a stop the block skips
+    #[serde(deserialize_with = "skipped_time", default)]
+    pub epoch_time: Option<i64>,
+    // the rest are rendered from `epoch_time` by `render_times`, for printing
+    #[serde(skip_deserializing)]
+    pub time: Option<String>,
+    #[serde(skip_deserializing)]
+    pub datetime: Option<String>,
+}
+
+// The feed gives skipped stops a time of -1
+fn skipped_time<'de, D>(deserializer: D) -> ::std::result::Result<Option<i64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time: i64 = from_string(deserializer)?;
+    Ok(match time {
+        t if t < 0 => None,
+        t => Some(t),
+    })
+}
+
+#[derive(Debug)]
+pub struct ScheduleOptions {
+    pub timezone: Timezone,
+    // the service day to give full dates and times for (default: today in `timezone`)
+    pub date: Option<NaiveDate>,
+    pub filter: ScheduleFilter,
+    pub view: View,
+    pub format: OutputFormat,
+}
+
+// Which parts of a schedule to print; everything by default
+#[derive(Debug, Default)]
+pub struct ScheduleFilter {
+    // a service class like "wkd", or "today" for the class of the service day
+    pub service: Option<String>,
+    pub direction: Option<String>,
+    // only blocks that stop here, with the time window applying to their time at this stop
+    pub stop: Option<String>,
+    // times in milliseconds since the start of the service day
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+#[derive(Debug)]
+pub struct DeparturesOptions {
+    pub timezone: Timezone,
+    pub date: Option<NaiveDate>,
+    // time of the service day to list departures after (default: now)
+    pub at: Option<i64>,
+    pub limit: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct Departure {
+    pub route_tag: String,
+    pub stop_tag: String,
+    pub stop_title: String,
+    pub direction: String,
+    pub service_class: String,
+    pub block_id: String,
+    pub time: String,
+    pub datetime: String,
+    pub minutes_away: f64,
+}
+
+// The timezone an agency's schedule times are in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timezone {
+    Local,
+    Named(Tz),
+}
+
+// Parse "local", or an IANA timezone name like "America/Los_Angeles"
+impl FromStr for Timezone {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Timezone> {
+        match s {
+            "local" => Ok(Timezone::Local),
+            _ => s
+                .parse::<Tz>()
+                .map(Timezone::Named)
+                .map_err(|e| format!("Unknown timezone '{}': {}", s, e).into()),
+        }
+    }
+}
+
+impl Timezone {
+    // The date in this timezone at the given milliseconds since the Unix epoch
+    pub fn date(&self, epoch: i64) -> NaiveDate {
+        match *self {
+            Timezone::Local => Local.timestamp_millis_opt(epoch).unwrap().date_naive(),
+            Timezone::Named(tz) => tz.timestamp_millis_opt(epoch).unwrap().date_naive(),
+        }
+    }
+
+    // Milliseconds since the Unix epoch of a schedule time, given in milliseconds since the
+    // start of the service day, which may be more than a day for service past midnight
+    pub fn scheduled_epoch(&self, service_date: NaiveDate, time: i64) -> Option<i64> {
+        self.scheduled_datetime(service_date, time)
+            .map(|t| t.timestamp_millis())
+    }
+
+    // Like `scheduled_epoch`, as an RFC 3339 date and time with this timezone's offset
+    pub fn scheduled_rfc3339(&self, service_date: NaiveDate, time: i64) -> Option<String> {
+        self.scheduled_datetime(service_date, time)
+            .map(|t| t.to_rfc3339())
+    }
+
+    fn scheduled_datetime(
+        &self,
+        service_date: NaiveDate,
+        time: i64,
+    ) -> Option<DateTime<FixedOffset>> {
+        let (days, time_of_day) = split_time(time)?;
+        let local = (service_date + chrono::Duration::days(days)).and_time(time_of_day);
+        // times in the hour skipped by daylight saving have no epoch, and times in the hour
+        // repeated by it have two, of which the earlier is taken
+        match *self {
+            Timezone::Local => Local
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|t| t.fixed_offset()),
+            Timezone::Named(tz) => tz
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|t| t.fixed_offset()),
+        }
+    }
+}
+
+// A schedule time as HH:MM:SS, with hours past 23 for service past midnight, as the feed
+// and GTFS give them
+fn format_time(time: i64) -> String {
+    let seconds = time / 1000;
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// Whole days past the service day, and the time of day on that day, of a schedule time
+fn split_time(time: i64) -> Option<(i64, NaiveTime)> {
+    let time_of_day = NaiveTime::from_num_seconds_from_midnight_opt(
+        (time.rem_euclid(MS_PER_DAY) / 1000) as u32,
+        0,
+    )?;
+    Some((time.div_euclid(MS_PER_DAY), time_of_day))
+}
+
+// Whether a table of a schedule runs on a date. Agencies that don't name their service
+// classes by day have every table considered to run every day.
+pub fn runs_on(schedule: &Schedule, table: &Route, date: NaiveDate) -> bool {
+    let classes_by_day = schedule
+        .routes
+        .iter()
+        .any(|r| ["wkd", "sat", "sun"].contains(&&r.service_class[..]));
+    !classes_by_day || table.service_class == service_class(date)
+}
+
+// Parse a time of the service day, "HH:MM" or "HH:MM:SS", into milliseconds since its start.
+// Hours past 23 are times after midnight, at the end of the service day.
+pub fn parse_time(s: &str) -> Result<i64> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err(format!("Expected 'HH:MM' or 'HH:MM:SS', got '{}'", s).into());
+    }
+    let mut seconds = 0;
+    for (part, unit) in parts.iter().zip(&[3600, 60, 1]) {
+        let n: i64 = part.parse().chain_err(|| format!("Invalid time '{}'", s))?;
+        if n < 0 || (*unit < 3600 && n >= 60) {
+            return Err(format!("Invalid time '{}'", s).into());
+        }
+        seconds += n * unit;
+    }
+    Ok(seconds * 1000)
+}
+
+// Remove the tables and blocks of a schedule that don't match a filter, and any tables left
+// without blocks
+pub fn filter_schedule(schedule: &mut Schedule, filter: &ScheduleFilter, service_date: NaiveDate) {
+    let service = filter.service.as_ref().map(|s| match &s[..] {
+        "today" => service_class(service_date),
+        s => s,
+    });
+    schedule.routes.retain(|r| {
+        service.is_none_or(|s| r.service_class == s)
+            && filter
+                .direction
+                .as_ref()
+                .is_none_or(|d| r.direction.eq_ignore_ascii_case(d))
+    });
+    for table in &mut schedule.routes {
+        table.blocks.retain(|b| block_matches(b, filter));
+    }
+    schedule.routes.retain(|r| !r.blocks.is_empty());
+}
+
+fn block_matches(block: &VehicleBlock, filter: &ScheduleFilter) -> bool {
+    let in_window =
+        |t: i64| filter.from.is_none_or(|from| t >= from) && filter.to.is_none_or(|to| t <= to);
+    let mut times = block
+        .stops
+        .iter()
+        .filter(|s| filter.stop.as_ref().is_none_or(|tag| &s.tag == tag))
+        .filter_map(|s| s.epoch_time);
+    match (&filter.stop, filter.from, filter.to) {
+        (&None, None, None) => true,
+        // a block matches a window without a stop if it runs at any time during it
+        (&None, _, _) => {
+            let times: Vec<i64> = times.collect();
+            match (times.iter().min(), times.iter().max()) {
+                (Some(&first), Some(&last)) => {
+                    filter.from.is_none_or(|from| last >= from)
+                        && filter.to.is_none_or(|to| first <= to)
+                }
+                _ => false,
+            }
+        }
+        (&Some(_), _, _) => times.any(in_window),
+    }
+}
+
+// Fill in the printable times of every stop of a schedule. Only the tables that run on the
+// given service day get full dates and times on it; the others just get times of day.
+pub fn render_times(schedule: &mut Schedule, timezone: Timezone, service_date: NaiveDate) {
+    let runs: Vec<bool> = schedule
+        .routes
+        .iter()
+        .map(|r| runs_on(schedule, r, service_date))
+        .collect();
+    for (table, runs) in schedule.routes.iter_mut().zip(runs) {
+        let stops = table.blocks.iter_mut().flat_map(|b| b.stops.iter_mut());
+        for stop in stops {
+            let time = match stop.epoch_time {
+                Some(t) => t,
+                None => continue,
+            };
+            stop.time = Some(format_time(time));
+            if runs {
+                stop.datetime = timezone.scheduled_rfc3339(service_date, time);
+            }
+        }
+    }
+}
+
+// The service class that usually runs on a date, e.g. "wkd" on weekdays
+pub fn service_class(date: NaiveDate) -> &'static str {
+    match date.weekday() {
+        Weekday::Sat => "sat",
+        Weekday::Sun => "sun",
+        _ => "wkd",
+    }
 }
 
 impl client::Contents for Schedule {
@@ -50,29 +330,273 @@ impl client::Contents for Schedule {
 
 fn get_schedule_url(agency: &String, route: &String) -> String {
     format!(
-        "https://retro.umoiq.com/service/publicXMLFeed?command=schedule&a={agency}&r={route}",
+        "{feed}?command=schedule&a={agency}&r={route}",
+        feed = client::DEFAULT_FEED_URL,
         agency = agency,
         route = route,
     )
 }
 
-fn _get_schedule(agency: &String, route: &String) -> Result<Schedule> {
+pub fn _get_schedule(client: &client::Client, agency: &String, route: &String) -> Result<Schedule> {
     let url = get_schedule_url(agency, route);
-    let downloaded: Option<Schedule> = client::download(&url).unwrap_or_else(|e| {
-        warn!(
-            "Download error: {} from URL={}",
-            e.display_chain().to_string(),
-            url
-        );
-        None
-    });
-    let schedule = downloaded.unwrap();
-    Ok(schedule)
+    let downloaded: Option<Schedule> =
+        client::download(client, &url).chain_err(|| format!("Download error from URL={}", url))?;
+    downloaded.ok_or_else(|| format!("No schedule for route {}", route).into())
+}
+
+// Async equivalent of `_get_schedule` for many routes, with at most `client.concurrency`
+// schedule requests in flight
+pub fn get_schedules_async(
+    client: &client::Client,
+    agency: &String,
+    routes: &[String],
+) -> impl Future<Item = Vec<Option<Schedule>>, Error = Error> + Send {
+    let urls: Vec<String> = routes
+        .iter()
+        .map(|route| get_schedule_url(agency, route))
+        .collect();
+    client::download_all(client, urls)
+}
+
+// Print the schedules of routes, keyed by route tag if `keyed`, with at most
+// `client.concurrency` schedule requests in flight
+pub fn get_schedule(
+    client: &client::Client,
+    agency: String,
+    routes: Vec<String>,
+    keyed: bool,
+    options: ScheduleOptions,
+) -> Result<()> {
+    let mut schedules: Vec<(String, Option<Schedule>)> = match routes.len() {
+        1 => vec![(
+            routes[0].clone(),
+            Some(_get_schedule(client, &agency, &routes[0])?),
+        )],
+        _ => routes
+            .iter()
+            .cloned()
+            .zip(client.block_on(get_schedules_async(client, &agency, &routes))?)
+            .inspect(|(route, schedule)| {
+                if schedule.is_none() {
+                    warn!("No schedule for route={}", route);
+                }
+            })
+            .collect(),
+    };
+
+    let date = options
+        .date
+        .unwrap_or_else(|| options.timezone.date(time::now_epoch() as i64));
+    for schedule in schedules.iter_mut().filter_map(|(_, s)| s.as_mut()) {
+        filter_schedule(schedule, &options.filter, date);
+        render_times(schedule, options.timezone, date);
+    }
+    timetable::print(schedules, keyed, options.view, options.format)
 }
 
-pub fn get_schedule(agency: String, route: String) -> Result<()> {
-    let schedule = _get_schedule(&agency, &route)?;
-    let schedule_json = serde_json::to_string(&schedule).unwrap();
-    println!("{}", schedule_json);
+// Print the next scheduled departures from a stop, soonest first
+pub fn get_next_departures(
+    client: &client::Client,
+    agency: String,
+    route: String,
+    stop: String,
+    options: DeparturesOptions,
+) -> Result<()> {
+    let schedule = _get_schedule(client, &agency, &route)?;
+    let timezone = options.timezone;
+    let date = options
+        .date
+        .unwrap_or_else(|| timezone.date(time::now_epoch() as i64));
+    let after = match options.at {
+        Some(at) => match timezone.scheduled_epoch(date, at) {
+            Some(epoch) => epoch,
+            None => return Err(format!("No such time on {}", date).into()),
+        },
+        None => time::now_epoch() as i64,
+    };
+
+    // service past midnight at the end of the previous day may still be to come
+    let service_dates = [date.pred_opt().unwrap(), date];
+    let mut departures: Vec<(i64, Departure)> = Vec::new();
+    for &service_date in &service_dates {
+        let tables = schedule
+            .routes
+            .iter()
+            .filter(|r| runs_on(&schedule, r, service_date));
+        for table in tables {
+            let stop_title = table
+                .header
+                .stops
+                .iter()
+                .find(|s| s.tag == stop)
+                .map(|s| s.title.clone())
+                .unwrap_or_default();
+            for block in &table.blocks {
+                let time = match block.stops.iter().find(|s| s.tag == stop) {
+                    Some(&VehicleStop {
+                        epoch_time: Some(t),
+                        ..
+                    }) => t,
+                    _ => continue,
+                };
+                let epoch = match timezone.scheduled_epoch(service_date, time) {
+                    Some(e) if e >= after => e,
+                    _ => continue,
+                };
+                departures.push((
+                    epoch,
+                    Departure {
+                        route_tag: table.tag.clone(),
+                        stop_tag: stop.clone(),
+                        stop_title: stop_title.clone(),
+                        direction: table.direction.clone(),
+                        service_class: table.service_class.clone(),
+                        block_id: block.block_id.clone(),
+                        time: format_time(time),
+                        datetime: timezone.scheduled_rfc3339(service_date, time).unwrap(),
+                        minutes_away: (epoch - after) as f64 / 60_000.0,
+                    },
+                ));
+            }
+        }
+    }
+    departures.sort_by_key(|d| d.0);
+    let departures: Vec<Departure> = departures
+        .into_iter()
+        .take(options.limit)
+        .map(|(_, d)| d)
+        .collect();
+
+    println!("{}", serde_json::to_string(&departures).unwrap());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_xml_rs::deserialize;
+
+    #[test]
+    fn parses_sf_muni_schedule() {
+        let schedule: Schedule =
+            deserialize(&include_bytes!("../fixtures/recorded/responses/command_schedule_a_sf-muni_r_N-64b953eb548d1351-0.xml")[..])
+                .unwrap();
+        assert_eq!(schedule.routes.len(), 2);
+
+        let inbound = &schedule.routes[0];
+        assert_eq!(inbound.tag, "N");
+        assert_eq!(inbound.schedule_class, "2022T_FALL");
+        assert_eq!(inbound.service_class, "wkd");
+        assert_eq!(inbound.direction, "Inbound");
+
+        let titles: Vec<&str> = inbound.header.stops.iter().map(|s| &s.title[..]).collect();
+        assert_eq!(
+            titles,
+            vec![
+                "Judah St & La Playa St",
+                "Judah St & 28th Ave",
+                "Carl St & Cole St"
+            ]
+        );
+
+        assert_eq!(inbound.blocks.len(), 2);
+        assert_eq!(inbound.blocks[0].block_id, "9701");
+        assert_eq!(inbound.blocks[0].stops[1].epoch_time, Some(22200000));
+        // the second block skips 28th Ave
+        assert_eq!(inbound.blocks[1].stops[1].tag, "5186");
+        assert_eq!(inbound.blocks[1].stops[1].epoch_time, None);
+        assert!(inbound.blocks[1].stops[1].time.is_none());
+
+        assert_eq!(schedule.routes[1].direction, "Outbound");
+        assert_eq!(schedule.routes[1].blocks[0].stops.len(), 2);
+    }
+
+    #[test]
+    fn dates_only_the_tables_running_on_the_service_day() {
+        let mut schedule: Schedule =
+            deserialize(&include_bytes!("../fixtures/recorded/responses/command_schedule_a_sf-muni_r_N-64b953eb548d1351-0.xml")[..])
+                .unwrap();
+        schedule.routes[1].service_class = String::from("sat");
+        let monday = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        let timezone: Timezone = "America/Los_Angeles".parse().unwrap();
+        render_times(&mut schedule, timezone, monday);
+
+        let weekday = &schedule.routes[0].blocks[0].stops[0];
+        assert_eq!(weekday.time.as_deref(), Some("06:00:00"));
+        assert_eq!(
+            weekday.datetime.as_deref(),
+            Some("2026-10-19T06:00:00-07:00")
+        );
+        // Saturday service doesn't run on a Monday, so has no date to give its times on
+        let saturday = &schedule.routes[1].blocks[0].stops[0];
+        assert_eq!(saturday.time.as_deref(), Some("06:40:00"));
+        assert_eq!(saturday.datetime, None);
+    }
+
+    #[test]
+    fn parses_ttc_schedule() {
+        let mut schedule: Schedule =
+            deserialize(&include_bytes!("../fixtures/recorded/responses/command_schedule_a_ttc_r_504-398d90bf1f8d1c0e-0.xml")[..])
+                .unwrap();
+        assert_eq!(schedule.routes.len(), 2);
+
+        let east = &schedule.routes[0];
+        assert_eq!(east.title, "504-King");
+        assert_eq!(east.schedule_class, "SEP22");
+        assert_eq!(east.direction, "East");
+        let tags: Vec<&str> = east.header.stops.iter().map(|s| &s.tag[..]).collect();
+        assert_eq!(tags, vec!["14260", "15637", "8808"]);
+        assert_eq!(east.header.stops[2].title, "King St East At Parliament St");
+
+        // trips after midnight have times past 24 hours
+        assert_eq!(east.blocks[0].block_id, "504_1_10");
+        assert_eq!(east.blocks[0].stops[0].epoch_time, Some(MS_PER_DAY));
+        assert_eq!(east.blocks[1].stops[1].epoch_time, None);
+        assert_eq!(east.blocks[1].stops[2].epoch_time, Some(90600000));
+
+        // its service classes are named by day
+        let west = &schedule.routes[1];
+        assert_eq!(west.service_class, "sat");
+        let monday = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        assert!(runs_on(&schedule, east, monday));
+        assert!(!runs_on(&schedule, west, monday));
+
+        // and are printed that way, dated on the next morning
+        render_times(&mut schedule, "America/Toronto".parse().unwrap(), monday);
+        let late = &schedule.routes[0].blocks[1].stops[2];
+        assert_eq!(late.time.as_deref(), Some("25:10:00"));
+        assert_eq!(late.datetime.as_deref(), Some("2026-10-20T01:10:00-04:00"));
+    }
+
+    #[test]
+    fn parses_unitrans_schedule() {
+        let schedule: Schedule =
+            deserialize(&include_bytes!("../fixtures/recorded/responses/command_schedule_a_unitrans_r_A-8b0d16cc0f02bdb9-0.xml")[..])
+                .unwrap();
+        assert_eq!(schedule.routes.len(), 2);
+
+        // a table without a header
+        let outbound = &schedule.routes[0];
+        assert_eq!(outbound.service_class, "MTWRF");
+        assert!(outbound.header.stops.is_empty());
+        assert_eq!(outbound.blocks[0].block_id, "A1");
+        assert_eq!(outbound.blocks[0].stops[1].tag, "22024");
+        assert_eq!(outbound.blocks[0].stops[1].epoch_time, Some(27900000));
+
+        // and one whose header stops have no titles
+        let inbound = &schedule.routes[1];
+        let header: Vec<(&str, &str)> = inbound
+            .header
+            .stops
+            .iter()
+            .map(|s| (&s.tag[..], &s.title[..]))
+            .collect();
+        assert_eq!(header, vec![("22024", ""), ("22258", "")]);
+        assert_eq!(inbound.blocks[0].stops[1].epoch_time, None);
+
+        // service classes that aren't named by day run every day
+        let sunday = NaiveDate::from_ymd_opt(2026, 10, 25).unwrap();
+        assert!(runs_on(&schedule, outbound, sunday));
+        assert!(runs_on(&schedule, inbound, sunday));
+    }
+}
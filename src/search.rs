@@ -0,0 +1,296 @@
+use serde_json;
+use std::cmp;
+use std::time::Duration;
+
+use client;
+use errors::*;
+use stopindex::{self, IndexedStop, RouteDirection};
+use stops;
+
+// Common abbreviations in stop titles, expanded so that "Mission St" matches "mission street"
+const ABBREVIATIONS: &[(&str, &str)] = &[
+    ("av", "avenue"),
+    ("ave", "avenue"),
+    ("blvd", "boulevard"),
+    ("ctr", "center"),
+    ("ct", "court"),
+    ("dr", "drive"),
+    ("e", "east"),
+    ("hwy", "highway"),
+    ("ln", "lane"),
+    ("mt", "mount"),
+    ("n", "north"),
+    ("pkwy", "parkway"),
+    ("pl", "place"),
+    ("rd", "road"),
+    ("s", "south"),
+    ("sq", "square"),
+    ("st", "street"),
+    ("sta", "station"),
+    ("ter", "terrace"),
+    ("w", "west"),
+];
+
+// Words that only join street names, e.g. "Mission St & 16th St" or "Mission and 16th"
+const IGNORED_WORDS: &[&str] = &["and", "at", "the"];
+
+#[derive(Serialize, Debug)]
+struct StopMatch {
+    pub tag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_id: Option<String>,
+    pub title: String,
+    pub routes: Vec<RouteDirection>,
+    pub score: f64,
+}
+
+#[derive(Debug)]
+pub struct SearchOptions {
+    // search only the stops of this route, instead of every route of the agency
+    pub route: Option<String>,
+    pub limit: Option<usize>,
+    // rebuild the cached stop index if it is older than this
+    pub max_cache_age: Duration,
+}
+
+pub fn search_stops(
+    client: &client::Client,
+    agency: String,
+    query: String,
+    options: SearchOptions,
+) -> Result<()> {
+    let stops = match options.route {
+        Some(ref route) => stopindex::index_stops(stops::_get_stops(client, &agency, route)?),
+        None => stopindex::get_stop_index(client, &agency, options.max_cache_age)?.stops,
+    };
+
+    let mut matches = find_stops(stops, &query);
+    if let Some(limit) = options.limit {
+        matches.truncate(limit);
+    }
+
+    println!("{}", serde_json::to_string(&matches).unwrap());
+    Ok(())
+}
+
+// Tags of the stops on a route whose titles best match a stop name
+pub fn get_stop_tags_for_name(
+    client: &client::Client,
+    agency: &String,
+    route: &String,
+    name: &str,
+) -> Result<Vec<String>> {
+    let stops = stopindex::index_stops(stops::_get_stops(client, agency, route)?);
+    let matches = find_stops(stops, name);
+
+    // a stop name usually matches one stop in each direction, equally well
+    let best_score = match matches.first() {
+        Some(m) => m.score,
+        None => return Err(format!("No stops on route {} match '{}'", route, name).into()),
+    };
+    Ok(matches
+        .into_iter()
+        .take_while(|m| m.score >= best_score)
+        .map(|m| m.tag)
+        .collect())
+}
+
+// Stops whose titles match every word of the query, best matches first
+fn find_stops(stops: Vec<IndexedStop>, query: &str) -> Vec<StopMatch> {
+    let query_words = words(query);
+    if query_words.is_empty() {
+        return Vec::new();
+    }
+
+    let mut matches: Vec<StopMatch> = stops
+        .into_iter()
+        .filter_map(|stop| {
+            let score = match_score(&query_words, &words(&stop.title))?;
+            Some(StopMatch {
+                tag: stop.tag,
+                stop_id: stop.stop_id,
+                title: stop.title,
+                routes: stop.routes,
+                score,
+            })
+        })
+        .collect();
+    matches.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.title.cmp(&b.title))
+    });
+    matches
+}
+
+// Lowercase words of a title or query, with abbreviations expanded
+fn words(s: &str) -> Vec<String> {
+    s.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty() && !IGNORED_WORDS.contains(w))
+        .map(|w| {
+            ABBREVIATIONS
+                .iter()
+                .find(|&&(abbreviation, _)| abbreviation == w)
+                .map(|&(_, expanded)| String::from(expanded))
+                .unwrap_or_else(|| String::from(w))
+        })
+        .collect()
+}
+
+// How well each query word matches its best title word, or None if any query word doesn't
+// match at all. Titles with fewer words beyond those in the query score slightly higher.
+fn match_score(query_words: &[String], title_words: &[String]) -> Option<f64> {
+    let mut total = 0.0;
+    for query_word in query_words {
+        let best = title_words
+            .iter()
+            .map(|title_word| word_score(query_word, title_word))
+            .fold(0.0, f64::max);
+        if best <= 0.0 {
+            return None;
+        }
+        total += best;
+    }
+    let coverage = (query_words.len() as f64 / title_words.len() as f64).min(1.0);
+    Some(0.9 * total / query_words.len() as f64 + 0.1 * coverage)
+}
+
+fn word_score(query_word: &str, title_word: &str) -> f64 {
+    if query_word == title_word {
+        return 1.0;
+    }
+    // e.g. "16" for "16th", or "miss" for "mission"
+    if title_word.starts_with(query_word) {
+        return 0.8;
+    }
+    // tolerate typos in longer words, but not in numbers, where "16th" and "18th" differ
+    if query_word.chars().any(|c| c.is_numeric()) {
+        return 0.0;
+    }
+    let allowed_edits = match query_word.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    };
+    if allowed_edits > 0 && edit_distance(query_word, title_word) <= allowed_edits {
+        return 0.6;
+    }
+    0.0
+}
+
+// Edit distance between two words, counting a swap of adjacent letters as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = cmp::min(
+                d[i - 1][j - 1] + cost,
+                cmp::min(d[i - 1][j] + 1, d[i][j - 1] + 1),
+            );
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = cmp::min(d[i][j], d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::Coordinate;
+
+    fn stop(tag: &str, title: &str) -> IndexedStop {
+        IndexedStop {
+            tag: String::from(tag),
+            stop_id: None,
+            title: String::from(title),
+            lat: Coordinate(37.7648),
+            lon: Coordinate(-122.4194),
+            routes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn counts_edits_and_transpositions() {
+        assert_eq!(edit_distance("mission", "mission"), 0);
+        assert_eq!(edit_distance("misison", "mission"), 1);
+        assert_eq!(edit_distance("mision", "mission"), 1);
+        assert_eq!(edit_distance("missoin", "mission"), 1);
+        assert_eq!(edit_distance("mssion", "mission"), 1);
+        assert_eq!(edit_distance("valencia", "valnecai"), 2);
+        assert_eq!(edit_distance("", "judah"), 5);
+        assert_eq!(edit_distance("church", "market"), 6);
+    }
+
+    #[test]
+    fn scores_words() {
+        assert_eq!(word_score("mission", "mission"), 1.0);
+        assert_eq!(word_score("16", "16th"), 0.8);
+        assert_eq!(word_score("miss", "mission"), 0.8);
+        // one typo in a word of up to 7 letters, two in longer words
+        assert_eq!(word_score("misison", "mission"), 0.6);
+        assert_eq!(word_score("valnecai", "valencia"), 0.6);
+        assert_eq!(word_score("mossoin", "mission"), 0.0);
+        assert_eq!(word_score("ocan", "ocean"), 0.6);
+        // none in short words or numbers
+        assert_eq!(word_score("cal", "col"), 0.0);
+        assert_eq!(word_score("18th", "16th"), 0.0);
+    }
+
+    #[test]
+    fn expands_abbreviations() {
+        assert_eq!(
+            words("Mission St & 16th St"),
+            vec!["mission", "street", "16th", "street"]
+        );
+        assert_eq!(
+            words("Judah/Sunset Blvd"),
+            vec!["judah", "sunset", "boulevard"]
+        );
+        assert_eq!(words("Mission and 16th"), vec!["mission", "16th"]);
+        assert_eq!(words("N Point St"), vec!["north", "point", "street"]);
+    }
+
+    #[test]
+    fn scores_matches() {
+        let title = words("Mission St & 16th St");
+        let exact = match_score(&words("mission street 16th street"), &title).unwrap();
+        assert!((exact - 1.0).abs() < 1e-9);
+        // abbreviated in the query but not the title, or the other way around
+        assert_eq!(
+            match_score(&words("Mission Street & 16th Street"), &title),
+            match_score(&words("mission st 16th st"), &title)
+        );
+        let partial = match_score(&words("mission 16"), &title).unwrap();
+        let typo = match_score(&words("misison 16th"), &title).unwrap();
+        assert!(partial < exact && typo < exact);
+        assert_eq!(match_score(&words("mission 18th"), &title), None);
+    }
+
+    #[test]
+    fn finds_best_matches_first() {
+        let stops = vec![
+            stop("3310", "Mission St & 16th St"),
+            stop("5552", "16th St & Mission St"),
+            stop("3311", "Mission St & 18th St"),
+            stop("5553", "16th St & Mission St Station"),
+            stop("4618", "Valencia St & 16th St"),
+        ];
+        let tags: Vec<String> = find_stops(stops, "16th & misison")
+            .into_iter()
+            .map(|m| m.tag)
+            .collect();
+        assert_eq!(tags, vec!["5552", "3310", "5553"]);
+    }
+}
@@ -0,0 +1,163 @@
+use error_chain::ChainedError;
+use serde_json;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use client;
+use errors::*;
+use geo::{Coordinate, LatLon};
+use routes;
+use stops;
+use time;
+
+pub const DEFAULT_MAX_AGE_SECONDS: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RouteDirection {
+    pub route_tag: String,
+    pub direction_tag: String,
+    pub direction_title: String,
+}
+
+// A stop and every route and direction that serves it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexedStop {
+    pub tag: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub stop_id: Option<String>,
+    pub title: String,
+    pub lat: Coordinate,
+    pub lon: Coordinate,
+    pub routes: Vec<RouteDirection>,
+}
+
+impl IndexedStop {
+    pub fn position(&self) -> LatLon {
+        LatLon::new(self.lat, self.lon)
+    }
+}
+
+// Every stop of an agency, built from the routeConfig of all of its routes
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StopIndex {
+    pub agency: String,
+    // milliseconds since the Unix epoch when the index was built
+    pub created_epoch: u64,
+    pub stops: Vec<IndexedStop>,
+}
+
+// Get the stop index for an agency from the local cache, rebuilding it from the feed if the
+// cached copy is missing or older than `max_age`
+pub fn get_stop_index(
+    client: &client::Client,
+    agency: &String,
+    max_age: Duration,
+) -> Result<StopIndex> {
+    let path = cache_path(agency);
+
+    if let Some(ref path) = path {
+        match read_index(path) {
+            Ok(index) => {
+                if time::now_epoch().saturating_sub(index.created_epoch)
+                    <= max_age.as_millis() as u64
+                {
+                    debug!(r#"stop_index="{}" cached="true""#, path.display());
+                    return Ok(index);
+                }
+            }
+            Err(e) => debug!(r#"stop_index="{}" error="{}""#, path.display(), e),
+        }
+    }
+
+    let index = build_index(client, agency)?;
+    if let Some(ref path) = path {
+        if let Err(e) = write_index(path, &index) {
+            warn!(
+                "Unable to cache stop index at {}: {}",
+                path.display(),
+                e.display_chain()
+            );
+        }
+    }
+    Ok(index)
+}
+
+fn build_index(client: &client::Client, agency: &String) -> Result<StopIndex> {
+    let route_tags = routes::get_route_tags(client, agency)?;
+    let flat_routes = client.block_on(stops::get_stops_for_routes_async(
+        client,
+        agency,
+        &route_tags,
+    ))?;
+
+    Ok(StopIndex {
+        agency: agency.clone(),
+        created_epoch: time::now_epoch(),
+        stops: index_stops(flat_routes),
+    })
+}
+
+// Merge the stops of the given routes into one list of stops, each with its routes
+pub fn index_stops(flat_routes: Vec<stops::FlatRoute>) -> Vec<IndexedStop> {
+    // stop tags are shared by every route that serves the stop, so merge them by tag
+    let mut stops_for_tags: HashMap<String, IndexedStop> = HashMap::new();
+    for route in flat_routes {
+        for direction in route.directions {
+            for stop in direction.stops {
+                let route_direction = RouteDirection {
+                    route_tag: route.tag.clone(),
+                    direction_tag: direction.tag.clone(),
+                    direction_title: direction.title.clone(),
+                };
+                let indexed =
+                    stops_for_tags
+                        .entry(stop.tag.clone())
+                        .or_insert_with(|| IndexedStop {
+                            tag: stop.tag,
+                            stop_id: stop.stop_id,
+                            title: stop.title,
+                            lat: stop.lat,
+                            lon: stop.lon,
+                            routes: Vec::new(),
+                        });
+                if !indexed.routes.contains(&route_direction) {
+                    indexed.routes.push(route_direction);
+                }
+            }
+        }
+    }
+
+    let mut stops: Vec<IndexedStop> = stops_for_tags.into_values().collect();
+    stops.sort_by(|a, b| a.tag.cmp(&b.tag));
+    stops
+}
+
+// $XDG_CACHE_HOME/nextbus, falling back to ~/.cache/nextbus
+fn cache_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("nextbus"))
+}
+
+fn cache_path(agency: &String) -> Option<PathBuf> {
+    cache_dir().map(|dir| dir.join(format!("stops-{}.json", agency)))
+}
+
+fn read_index(path: &PathBuf) -> Result<StopIndex> {
+    let file = File::open(path)?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+fn write_index(path: &PathBuf, index: &StopIndex) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = File::create(path)?;
+    serde_json::to_writer(BufWriter::new(file), index)?;
+    Ok(())
+}
@@ -1,10 +1,12 @@
-use error_chain::ChainedError;
-use std::collections::HashMap;
+use futures::Future;
+use std::collections::{BTreeMap, HashMap};
 
 use serde_json;
 
 use client;
 use errors::*;
+use geo::{Coordinate, LatLon};
+use xmlstream;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -18,25 +20,37 @@ struct RouteConfig {
 struct Route {
     pub tag: String,
     pub title: String,
-    pub lat_min: String,
-    pub lat_max: String,
-    pub lon_min: String,
-    pub lon_max: String,
+    #[serde(deserialize_with = "client::from_string")]
+    pub lat_min: Coordinate,
+    #[serde(deserialize_with = "client::from_string")]
+    pub lat_max: Coordinate,
+    #[serde(deserialize_with = "client::from_string")]
+    pub lon_min: Coordinate,
+    #[serde(deserialize_with = "client::from_string")]
+    pub lon_max: Coordinate,
     #[serde(rename = "stop")]
     pub stops: Vec<Stop>,
     #[serde(rename = "direction")]
     pub directions: Vec<Direction>,
+    // pieces of the route's geometry, which follow the streets but aren't in any order and
+    // aren't tied to a direction
+    #[serde(rename = "path", default)]
+    pub paths: Vec<Path>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 #[derive(Clone)]
-struct Stop {
+pub struct Stop {
     pub tag: String,
     pub title: String,
-    pub lat: String,
-    pub lon: String,
-    pub stop_id: String,
+    #[serde(deserialize_with = "client::from_string")]
+    pub lat: Coordinate,
+    #[serde(deserialize_with = "client::from_string")]
+    pub lon: Coordinate,
+    // missing for some agencies
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub stop_id: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -44,8 +58,9 @@ struct Stop {
 struct Direction {
     pub tag: String,
     pub title: String,
+    #[serde(default)]
     pub name: String,
-    #[serde(rename = "useForUI")]
+    #[serde(rename = "useForUI", default)]
     pub use_for_ui: bool,
     #[serde(rename = "stop")]
     pub stop_tags: Vec<StopTag>,
@@ -58,20 +73,45 @@ struct StopTag {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+struct Path {
+    #[serde(rename = "point", default)]
+    pub points: Vec<PathPoint>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PathPoint {
+    #[serde(deserialize_with = "client::from_string")]
+    pub lat: Coordinate,
+    #[serde(deserialize_with = "client::from_string")]
+    pub lon: Coordinate,
+}
+
+#[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct FlatRoute {
+struct RouteStop {
+    pub route_tag: String,
+    #[serde(flatten)]
+    pub stop: Stop,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FlatRoute {
     pub tag: String,
     pub title: String,
-    pub lat_min: String,
-    pub lat_max: String,
-    pub lon_min: String,
-    pub lon_max: String,
+    pub lat_min: Coordinate,
+    pub lat_max: Coordinate,
+    pub lon_min: Coordinate,
+    pub lon_max: Coordinate,
     pub directions: Vec<FlatDirection>,
+    // the route's geometry, left out of the printed stops
+    #[serde(skip)]
+    pub paths: Vec<Vec<LatLon>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct FlatDirection {
+pub struct FlatDirection {
     pub tag: String,
     pub title: String,
     pub name: String,
@@ -88,24 +128,53 @@ impl client::Contents for RouteConfig {
 
 fn get_stops_url(agency: &String, route: &String) -> String {
     format!(
-        "https://retro.umoiq.com/service/publicXMLFeed?command=routeConfig&a={agency}&r={route}",
+        "{feed}?command=routeConfig&a={agency}&r={route}",
+        feed = client::DEFAULT_FEED_URL,
         agency = agency,
         route = route,
     )
 }
 
-fn _get_stops(agency: &String, route: &String) -> Result<Vec<FlatRoute>> {
+pub fn _get_stops(
+    client: &client::Client,
+    agency: &String,
+    route: &String,
+) -> Result<Vec<FlatRoute>> {
     let url = get_stops_url(agency, route);
-    let downloaded: Option<RouteConfig> = client::download(&url).unwrap_or_else(|e| {
-        warn!(
-            "Download error: {} from URL={}",
-            e.display_chain().to_string(),
-            url
-        );
-        None
-    });
-    let route_config = downloaded.unwrap();
+    let downloaded: Option<RouteConfig> =
+        client::download(client, &url).chain_err(|| format!("Download error from URL={}", url))?;
+    // no routes for a route the feed doesn't know
+    Ok(downloaded.map(flatten_route_config).unwrap_or_default())
+}
 
+// Async equivalent of `_get_stops` for many routes, with at most `client.concurrency`
+// routeConfig requests in flight
+pub fn get_stops_for_routes_async(
+    client: &client::Client,
+    agency: &String,
+    routes: &[String],
+) -> impl Future<Item = Vec<FlatRoute>, Error = Error> + Send {
+    let urls: Vec<String> = routes
+        .iter()
+        .map(|route| get_stops_url(agency, route))
+        .collect();
+    let routes = routes.to_vec();
+    client::download_all(client, urls).map(move |route_configs: Vec<Option<RouteConfig>>| {
+        routes
+            .iter()
+            .zip(route_configs)
+            .filter_map(|(route, config)| {
+                if config.is_none() {
+                    warn!("No route config for route={}", route);
+                }
+                config
+            })
+            .flat_map(flatten_route_config)
+            .collect()
+    })
+}
+
+fn flatten_route_config(route_config: RouteConfig) -> Vec<FlatRoute> {
     let stops_for_tags: HashMap<String, Stop> = route_config
         .routes
         .iter()
@@ -123,10 +192,10 @@ fn _get_stops(agency: &String, route: &String) -> Result<Vec<FlatRoute>> {
         .map(|r| FlatRoute {
             tag: r.tag.clone(),
             title: r.title.clone(),
-            lat_min: r.lat_min.clone(),
-            lat_max: r.lat_max.clone(),
-            lon_min: r.lon_min.clone(),
-            lon_max: r.lon_max.clone(),
+            lat_min: r.lat_min,
+            lat_max: r.lat_max,
+            lon_min: r.lon_min,
+            lon_max: r.lon_max,
             directions: r
                 .directions
                 .iter()
@@ -134,37 +203,102 @@ fn _get_stops(agency: &String, route: &String) -> Result<Vec<FlatRoute>> {
                     tag: d.tag.clone(),
                     title: d.title.clone(),
                     name: d.name.clone(),
-                    use_for_ui: d.use_for_ui.clone(),
+                    use_for_ui: d.use_for_ui,
+                    // leaving out any stop the route doesn't list, e.g. one skipped by
+                    // lenient parsing
                     stops: d
                         .stop_tags
                         .iter()
-                        .map(|st| Stop {
-                            tag: st.tag.clone(),
-                            title: stops_for_tags[&st.tag].title.clone(),
-                            lat: stops_for_tags[&st.tag].lat.clone(),
-                            lon: stops_for_tags[&st.tag].lon.clone(),
-                            stop_id: stops_for_tags[&st.tag].stop_id.clone(),
-                        })
+                        .filter_map(|st| stops_for_tags.get(&st.tag).cloned())
                         .collect(),
                 })
                 .collect(),
+            paths: r
+                .paths
+                .iter()
+                .map(|p| {
+                    p.points
+                        .iter()
+                        .map(|pt| LatLon::new(pt.lat, pt.lon))
+                        .collect()
+                })
+                .collect(),
         })
         .collect();
 
     // TODO: for each route_config.directions, turn it into a FlatDirection
     // create a map from stopTag: Stop, and for each route.directions[i].stop_tags loop up the Stop
-    Ok(flats)
+    flats
 }
 
-pub fn get_stops(agency: String, route: String) -> Result<()> {
-    let stops = _get_stops(&agency, &route)?;
-    let stops_json = serde_json::to_string(&stops).unwrap();
-    println!("{}", stops_json);
+// Print the stops of routes. With several routes, or if `keyed`, print an object with each
+// route's stops by route tag, fetched with at most `client.concurrency` requests in flight.
+pub fn get_stops(
+    client: &client::Client,
+    agency: String,
+    routes: Vec<String>,
+    keyed: bool,
+    stream: bool,
+) -> Result<()> {
+    if stream {
+        for route in &routes {
+            stream_stops(client, &agency, route)?;
+        }
+        return Ok(());
+    }
+    if !keyed && routes.len() == 1 {
+        let stops = _get_stops(client, &agency, &routes[0])?;
+        let stops_json = serde_json::to_string(&stops).unwrap();
+        println!("{}", stops_json);
+        return Ok(());
+    }
+
+    let flat_routes = client.block_on(get_stops_for_routes_async(client, &agency, &routes))?;
+    let mut stops_for_routes: BTreeMap<String, Option<FlatRoute>> = flat_routes
+        .into_iter()
+        .map(|r| (r.tag.clone(), Some(r)))
+        .collect();
+    // a route whose config failed to download is null, rather than missing
+    for route in &routes {
+        stops_for_routes.entry(route.clone()).or_insert(None);
+    }
+    println!("{}", serde_json::to_string(&stops_for_routes).unwrap());
     Ok(())
 }
 
-pub fn get_stop_tags(agency: &String, route: &String) -> Result<Vec<String>> {
-    let route_list = _get_stops(agency, route)?;
+// Print each of a route's stops as a line of JSON as soon as it is parsed from the response
+fn stream_stops(client: &client::Client, agency: &String, route: &String) -> Result<()> {
+    let url = get_stops_url(agency, route);
+    let reader = client::download_reader(client, &url)?;
+
+    let mut route_tag = String::new();
+    for element in xmlstream::Elements::new(reader) {
+        let element = element?;
+        match (&element.name[..], element.depth) {
+            // <body><route><stop/>...<direction><stop/></direction></route></body>, where stops
+            // inside a direction only carry a tag
+            ("route", 2) => route_tag = String::from(element.attribute("tag").unwrap_or("")),
+            ("stop", 3) => {
+                if let Some(stop) = client::deserialize_element(client, element)? {
+                    let route_stop = RouteStop {
+                        route_tag: route_tag.clone(),
+                        stop,
+                    };
+                    println!("{}", serde_json::to_string(&route_stop).unwrap());
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+pub fn get_stop_tags(
+    client: &client::Client,
+    agency: &String,
+    route: &String,
+) -> Result<Vec<String>> {
+    let route_list = _get_stops(client, agency, route)?;
     let mut stop_tags: Vec<String> = route_list
         .into_iter()
         .flat_map(|r: FlatRoute| {
@@ -183,3 +317,64 @@ pub fn get_stop_tags(agency: &String, route: &String) -> Result<Vec<String>> {
     stop_tags.dedup();
     Ok(stop_tags)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_xml_rs::deserialize;
+
+    fn flatten(xml: &[u8]) -> Vec<FlatRoute> {
+        flatten_route_config(deserialize(xml).unwrap())
+    }
+
+    #[test]
+    fn parses_sf_muni_route_config() {
+        let routes = flatten(include_bytes!(
+            "../fixtures/recorded/responses/command_routeConfig_a_sf-muni_r_N-8b2848ca03315937-0.xml"
+        ));
+        assert_eq!(routes.len(), 1);
+        let route = &routes[0];
+        assert_eq!((&route.tag[..], &route.title[..]), ("N", "N-Judah"));
+        assert_eq!(route.lon_min, Coordinate(-122.5086));
+        assert_eq!(route.directions.len(), 2);
+
+        let inbound = &route.directions[0];
+        assert_eq!(inbound.tag, "N____I_F00");
+        assert_eq!(inbound.name, "Inbound");
+        assert!(inbound.use_for_ui);
+        assert_eq!(inbound.stops.len(), 5);
+        assert_eq!(inbound.stops[2].title, "Judah St & 28th Ave");
+        assert_eq!(inbound.stops[2].stop_id, Some(String::from("15186")));
+        // outbound lists the same stops the other way round
+        assert_eq!(route.directions[1].stops[0].tag, "4006");
+
+        assert_eq!(route.paths.len(), 2);
+        assert_eq!(route.paths[1].len(), 3);
+        assert_eq!(route.paths[1][1].lat, Coordinate(37.76293));
+    }
+
+    #[test]
+    fn parses_stops_without_stop_ids() {
+        let routes = flatten(include_bytes!(
+            "../fixtures/recorded/responses/command_routeConfig_a_ttc_r_504-c7286fafa7990b38-0.xml"
+        ));
+        let stops = &routes[0].directions[0].stops;
+        assert_eq!(stops.len(), 4);
+        assert_eq!(stops[0].stop_id, None);
+        assert_eq!(stops[2].stop_id, Some(String::from("2196")));
+        // directions without a name
+        assert_eq!(routes[0].directions[0].name, "");
+    }
+
+    #[test]
+    fn parses_directions_without_ui_flags() {
+        let routes = flatten(include_bytes!(
+            "../fixtures/recorded/responses/command_routeConfig_a_unitrans_r_A-748c4405c1f6ccdb-0.xml"
+        ));
+        let direction = &routes[0].directions[0];
+        assert_eq!(direction.tag, "A_outbound");
+        assert!(!direction.use_for_ui);
+        assert_eq!(direction.stops.len(), 2);
+        assert!(routes[0].paths.is_empty());
+    }
+}
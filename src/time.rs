@@ -0,0 +1,11 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+// Milliseconds since the Unix epoch, the unit of the feed's times
+pub fn now_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
@@ -0,0 +1,487 @@
+use serde_json::{self, Value};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use errors::*;
+use output::{self, OutputFormat};
+use schedule::{Route, Schedule};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum View {
+    // the schedule as the feed organizes it, by block and then by stop
+    Blocks,
+    // stops as rows and trips as columns
+    Timetable,
+    // each stop with the list of its departures
+    Departures,
+}
+
+impl FromStr for View {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<View> {
+        match s {
+            "blocks" => Ok(View::Blocks),
+            "timetable" => Ok(View::Timetable),
+            "departures" => Ok(View::Departures),
+            _ => Err(format!("Unknown schedule view '{}'", s).into()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Timetable {
+    pub route_tag: String,
+    pub title: String,
+    pub service_class: String,
+    pub direction: String,
+    // the block running each trip, in order of the trips' first times
+    pub trips: Vec<String>,
+    pub rows: Vec<TimetableRow>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TimetableRow {
+    pub stop_tag: String,
+    pub stop_title: String,
+    // the time of each trip at the stop, or None where the trip skips it
+    pub times: Vec<Option<String>>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StopDepartures {
+    pub route_tag: String,
+    pub service_class: String,
+    pub direction: String,
+    pub stop_tag: String,
+    pub stop_title: String,
+    pub departures: Vec<String>,
+}
+
+// Print the schedules of routes, whose times have been rendered by `schedule::render_times`.
+// As JSON, `keyed` prints an object with each route's schedule by route tag, and otherwise
+// prints the schedule of the only route. A route without a schedule is null in the object, and
+// left out of CSV and tables. As CSV or a table, every row names its route.
+pub fn print(
+    schedules: Vec<(String, Option<Schedule>)>,
+    keyed: bool,
+    view: View,
+    format: OutputFormat,
+) -> Result<()> {
+    if let (View::Blocks, OutputFormat::Csv) | (View::Blocks, OutputFormat::Table) = (view, format)
+    {
+        return Err("Schedule blocks can only be printed as JSON".into());
+    }
+
+    if format == OutputFormat::Json {
+        let values: BTreeMap<String, Value> = schedules
+            .iter()
+            .map(|(route, schedule)| {
+                let value = schedule.as_ref().map_or(Value::Null, |s| to_json(s, view));
+                (route.clone(), value)
+            })
+            .collect();
+        let json = match keyed {
+            true => serde_json::to_string(&values),
+            false => serde_json::to_string(&values.into_values().next().unwrap_or(Value::Null)),
+        };
+        println!("{}", json.unwrap());
+        return Ok(());
+    }
+
+    let timetables: Vec<Timetable> = schedules
+        .iter()
+        .filter_map(|(_, s)| s.as_ref())
+        .flat_map(|s| s.routes.iter().map(pivot))
+        .collect();
+    let text = match (view, format) {
+        (View::Timetable, OutputFormat::Csv) => timetables_csv(&timetables),
+        (View::Timetable, _) => timetables_table(&timetables),
+        (_, format) => {
+            let departures: Vec<StopDepartures> =
+                timetables.into_iter().flat_map(stop_departures).collect();
+            match format {
+                OutputFormat::Csv => departures_csv(&departures),
+                _ => departures_table(&departures),
+            }
+        }
+    };
+    print!("{}", text);
+    Ok(())
+}
+
+fn to_json(schedule: &Schedule, view: View) -> Value {
+    let timetables = || schedule.routes.iter().map(pivot);
+    match view {
+        View::Blocks => serde_json::to_value(schedule),
+        View::Timetable => serde_json::to_value(timetables().collect::<Vec<Timetable>>()),
+        View::Departures => serde_json::to_value(
+            timetables()
+                .flat_map(stop_departures)
+                .collect::<Vec<StopDepartures>>(),
+        ),
+    }
+    .unwrap()
+}
+
+// A schedule table with stops as rows, in the order of its header, and trips as columns
+fn pivot(table: &Route) -> Timetable {
+    let mut blocks: Vec<_> = table.blocks.iter().collect();
+    blocks.sort_by_key(|b| b.stops.iter().filter_map(|s| s.epoch_time).min());
+
+    // tables without a header list their stops in the order of the blocks' stops
+    let stops: Vec<(String, String)> = match table.header.stops.len() {
+        0 => table
+            .blocks
+            .first()
+            .map(|b| {
+                b.stops
+                    .iter()
+                    .map(|s| (s.tag.clone(), String::new()))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        _ => table
+            .header
+            .stops
+            .iter()
+            .map(|s| (s.tag.clone(), s.title.clone()))
+            .collect(),
+    };
+
+    // a loop route's first and last stops share a tag, so a block's stops are matched to the
+    // header by position when they line up with it, and otherwise the header's nth stop with
+    // a tag is matched to the block's nth stop with it
+    let rows = stops
+        .iter()
+        .enumerate()
+        .map(|(i, (tag, title))| {
+            let nth = stops[..i].iter().filter(|(t, _)| t == tag).count();
+            TimetableRow {
+                stop_tag: tag.clone(),
+                stop_title: title.clone(),
+                times: blocks
+                    .iter()
+                    .map(|b| {
+                        let stop = match b.stops.get(i) {
+                            Some(s) if &s.tag == tag => Some(s),
+                            _ => b.stops.iter().filter(|s| &s.tag == tag).nth(nth),
+                        };
+                        stop.and_then(|s| s.time.clone())
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+
+    Timetable {
+        route_tag: table.tag.clone(),
+        title: table.title.clone(),
+        service_class: table.service_class.clone(),
+        direction: table.direction.clone(),
+        trips: blocks.iter().map(|b| b.block_id.clone()).collect(),
+        rows,
+    }
+}
+
+fn stop_departures(timetable: Timetable) -> Vec<StopDepartures> {
+    let route_tag = timetable.route_tag;
+    let service_class = timetable.service_class;
+    let direction = timetable.direction;
+    timetable
+        .rows
+        .into_iter()
+        .map(|row| StopDepartures {
+            route_tag: route_tag.clone(),
+            service_class: service_class.clone(),
+            direction: direction.clone(),
+            stop_tag: row.stop_tag,
+            stop_title: row.stop_title,
+            departures: row.times.into_iter().flatten().collect(),
+        })
+        .collect()
+}
+
+// Each timetable as its own section of rows, separated by blank lines
+fn timetables_csv(timetables: &[Timetable]) -> String {
+    let mut sections = Vec::new();
+    for t in timetables {
+        let mut header = vec![
+            "route_tag",
+            "service_class",
+            "direction",
+            "stop_tag",
+            "stop_title",
+        ];
+        header.extend(t.trips.iter().map(|b| &b[..]));
+        let mut lines = vec![output::csv_line(&header)];
+        for row in &t.rows {
+            let mut fields = vec![
+                &t.route_tag[..],
+                &t.service_class[..],
+                &t.direction[..],
+                &row.stop_tag[..],
+                &row.stop_title[..],
+            ];
+            fields.extend(row.times.iter().map(|t| t.as_ref().map_or("", |t| &t[..])));
+            lines.push(output::csv_line(&fields));
+        }
+        sections.push(lines.join("\n") + "\n");
+    }
+    sections.join("\n")
+}
+
+// A row for every departure
+fn departures_csv(departures: &[StopDepartures]) -> String {
+    let mut lines = vec![output::csv_line(&[
+        "route_tag",
+        "service_class",
+        "direction",
+        "stop_tag",
+        "stop_title",
+        "time",
+    ])];
+    for d in departures {
+        for time in &d.departures {
+            lines.push(output::csv_line(&[
+                &d.route_tag,
+                &d.service_class,
+                &d.direction,
+                &d.stop_tag,
+                &d.stop_title,
+                time,
+            ]));
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+//   N-Judah, Inbound (wkd)
+//   Ocean Beach    06:00:00  06:15:00  ...
+//   Embarcadero    --        06:52:00  ...
+fn timetables_table(timetables: &[Timetable]) -> String {
+    // stops without titles, as in tables without headers, go by their tags
+    let label = |r: &TimetableRow| match r.stop_title.is_empty() {
+        true => r.stop_tag.clone(),
+        false => r.stop_title.clone(),
+    };
+    let mut lines = Vec::new();
+    for t in timetables {
+        lines.push(format!(
+            "{}, {} ({})",
+            t.title, t.direction, t.service_class
+        ));
+        let title_width = t
+            .rows
+            .iter()
+            .map(|r| label(r).chars().count())
+            .max()
+            .unwrap_or(0);
+        let time_width = t
+            .rows
+            .iter()
+            .flat_map(|r| r.times.iter().flatten())
+            .map(|t| t.chars().count())
+            .max()
+            .unwrap_or(0);
+        for row in &t.rows {
+            let times: Vec<String> = row
+                .times
+                .iter()
+                .map(|t| {
+                    format!(
+                        "{:width$}",
+                        t.as_deref().unwrap_or("--"),
+                        width = time_width
+                    )
+                })
+                .collect();
+            lines.push(format!(
+                "  {:width$}  {}",
+                label(row),
+                times.join("  ").trim_end(),
+                width = title_width
+            ));
+        }
+        lines.push(String::new());
+    }
+    lines.join("\n")
+}
+
+fn departures_table(departures: &[StopDepartures]) -> String {
+    let mut lines = Vec::new();
+    let mut section = None;
+    for d in departures {
+        let current = (&d.route_tag, &d.direction, &d.service_class);
+        if section != Some(current) {
+            if section.is_some() {
+                lines.push(String::new());
+            }
+            lines.push(format!(
+                "{}, {} ({})",
+                d.route_tag, d.direction, d.service_class
+            ));
+            section = Some(current);
+        }
+        lines.push(format!("  {} ({})", d.stop_title, d.stop_tag));
+        lines.push(format!("    {}", d.departures.join(" ")));
+    }
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schedule::{Header, HeaderStop, VehicleBlock, VehicleStop};
+
+    fn stop(tag: &str, time: Option<&str>) -> VehicleStop {
+        VehicleStop {
+            tag: String::from(tag),
+            epoch_time: time.map(|t| {
+                let hms: Vec<i64> = t.split(':').map(|n| n.parse().unwrap()).collect();
+                (hms[0] * 3600 + hms[1] * 60 + hms[2]) * 1000
+            }),
+            time: time.map(String::from),
+            datetime: None,
+        }
+    }
+
+    fn block(id: &str, stops: Vec<VehicleStop>) -> VehicleBlock {
+        VehicleBlock {
+            block_id: String::from(id),
+            stops,
+        }
+    }
+
+    fn route(header: &[&str], blocks: Vec<VehicleBlock>) -> Route {
+        Route {
+            tag: String::from("N"),
+            title: String::from("N-Judah"),
+            schedule_class: String::from("2022T_FALL"),
+            service_class: String::from("wkd"),
+            direction: String::from("Inbound"),
+            header: Header {
+                stops: header
+                    .iter()
+                    .map(|tag| HeaderStop {
+                        tag: String::from(*tag),
+                        title: tag.to_uppercase(),
+                    })
+                    .collect(),
+            },
+            blocks,
+        }
+    }
+
+    fn times(row: &TimetableRow) -> Vec<Option<&str>> {
+        row.times.iter().map(|t| t.as_deref()).collect()
+    }
+
+    #[test]
+    fn pivots_blocks_into_stops_by_trips() {
+        let late = stop("c", Some("24:10:00"));
+        let table = route(
+            &["a", "b", "c"],
+            vec![
+                block(
+                    "2",
+                    vec![stop("a", Some("23:40:00")), stop("b", None), late],
+                ),
+                block(
+                    "1",
+                    vec![
+                        stop("a", Some("06:00:00")),
+                        stop("b", Some("06:10:00")),
+                        stop("c", Some("06:20:00")),
+                    ],
+                ),
+            ],
+        );
+        let timetable = pivot(&table);
+
+        // trips in order of their first times, and stops in the header's order
+        assert_eq!(timetable.trips, vec!["1", "2"]);
+        let tags: Vec<&str> = timetable.rows.iter().map(|r| &r.stop_tag[..]).collect();
+        assert_eq!(tags, vec!["a", "b", "c"]);
+        assert_eq!(timetable.rows[0].stop_title, "A");
+        assert_eq!(
+            times(&timetable.rows[0]),
+            vec![Some("06:00:00"), Some("23:40:00")]
+        );
+        assert_eq!(times(&timetable.rows[1]), vec![Some("06:10:00"), None]);
+        assert_eq!(
+            times(&timetable.rows[2]),
+            vec![Some("06:20:00"), Some("24:10:00")]
+        );
+    }
+
+    #[test]
+    fn matches_a_loop_routes_stops_by_position() {
+        // the loop starts and ends at a
+        let table = route(
+            &["a", "b", "a"],
+            vec![
+                block(
+                    "1",
+                    vec![
+                        stop("a", Some("06:00:00")),
+                        stop("b", Some("06:10:00")),
+                        stop("a", Some("06:20:00")),
+                    ],
+                ),
+                // a block that doesn't line up with the header, whose only time at a is
+                // taken to be its first
+                block(
+                    "2",
+                    vec![stop("b", Some("07:10:00")), stop("a", Some("07:20:00"))],
+                ),
+            ],
+        );
+        let timetable = pivot(&table);
+
+        assert_eq!(
+            times(&timetable.rows[0]),
+            vec![Some("06:00:00"), Some("07:20:00")]
+        );
+        assert_eq!(
+            times(&timetable.rows[1]),
+            vec![Some("06:10:00"), Some("07:10:00")]
+        );
+        assert_eq!(times(&timetable.rows[2]), vec![Some("06:20:00"), None]);
+    }
+
+    #[test]
+    fn lists_each_stops_departures() {
+        let table = route(
+            &["a", "b"],
+            vec![
+                block(
+                    "1",
+                    vec![stop("a", Some("06:00:00")), stop("b", Some("06:10:00"))],
+                ),
+                block("2", vec![stop("a", Some("06:30:00")), stop("b", None)]),
+            ],
+        );
+        let departures = stop_departures(pivot(&table));
+
+        assert_eq!(departures.len(), 2);
+        assert_eq!(departures[0].stop_tag, "a");
+        assert_eq!(departures[0].departures, vec!["06:00:00", "06:30:00"]);
+        // the skipped stop has no departure
+        assert_eq!(departures[1].departures, vec!["06:10:00"]);
+        assert_eq!(
+            departures_csv(&departures),
+            "route_tag,service_class,direction,stop_tag,stop_title,time\n\
+             N,wkd,Inbound,a,A,06:00:00\n\
+             N,wkd,Inbound,a,A,06:30:00\n\
+             N,wkd,Inbound,b,B,06:10:00\n"
+        );
+        assert_eq!(
+            departures_table(&departures),
+            "N, Inbound (wkd)\n  A (a)\n    06:00:00 06:30:00\n  B (b)\n    06:10:00\n"
+        );
+    }
+}
@@ -0,0 +1,967 @@
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::Print;
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use error_chain::ChainedError;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::slice;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use board;
+use client;
+use errors::*;
+use location::{self, VehicleTime};
+use output::Styler;
+use prediction::{self, PredictionsList};
+use routes::{self, Route};
+use stops::{self, FlatRoute, Stop};
+use time;
+
+pub const DEFAULT_PAUSE_SECONDS: u64 = 10;
+
+// How long to wait for a key press before redrawing with any new data from the poller
+const FRAME_INTERVAL: Duration = Duration::from_millis(250);
+
+const ROUTES_WIDTH: u16 = 24;
+
+// Vehicles that haven't reported for this long, as of the feed's latest response, are taken
+// off the list and the map
+const REMOVED_AFTER_MS: u64 = 5 * 60 * 1000;
+
+// What the poller should fetch, sent from the UI whenever the selection changes
+enum Request {
+    Route(String),
+    Stop(String),
+}
+
+// Data fetched by the poller in the background
+enum Update {
+    Config(FlatRoute),
+    Vehicles {
+        route_tag: String,
+        vehicles: Vec<VehicleTime>,
+        // the feed's time for the response
+        epoch: u64,
+    },
+    Arrivals {
+        stop_tag: String,
+        predictions: Option<PredictionsList>,
+    },
+    Error(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pane {
+    Routes,
+    Stops,
+}
+
+struct StopEntry {
+    direction: String,
+    stop: Stop,
+}
+
+struct App {
+    agency: String,
+    routes: Vec<Route>,
+    route_cursor: usize,
+    // the route whose vehicles and stops are shown, once one has been picked
+    route: Option<String>,
+    config: Option<FlatRoute>,
+    stops: Vec<StopEntry>,
+    stop_cursor: usize,
+    stop: Option<String>,
+    vehicles: BTreeMap<String, VehicleTime>,
+    // the selected stop's arrivals, rendered as a board
+    arrivals: Option<String>,
+    focus: Pane,
+    status: String,
+}
+
+// Restores the terminal when the UI exits, including by panicking
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn enter() -> Result<TerminalGuard> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen, Hide)?;
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// Browse the routes of an agency, with the live vehicles, a map and stop arrivals of the
+// selected route, refreshed in the background every `pause_seconds`
+pub fn run(
+    client: &client::Client,
+    agency: String,
+    route: Option<String>,
+    pause_seconds: u64,
+) -> Result<()> {
+    let route_list = routes::_get_routes(client, &agency)?.routes;
+    let mut app = App::new(agency, route_list);
+
+    let (request_tx, request_rx) = mpsc::channel();
+    let (update_tx, update_rx) = mpsc::channel();
+    let pause = Duration::from_secs(pause_seconds);
+
+    thread::scope(|scope| {
+        let agency = app.agency.clone();
+        scope.spawn(move || poll(client, &agency, pause, request_rx, update_tx));
+
+        if let Some(tag) = route {
+            if let Some(i) = app.routes.iter().position(|r| r.tag == tag) {
+                app.route_cursor = i;
+                app.select_route(&request_tx);
+            }
+        }
+
+        let _guard = TerminalGuard::enter()?;
+        let result = app.event_loop(&request_tx, &update_rx);
+        // hanging up stops the poller once it finishes its current request
+        drop(request_tx);
+        result
+    })
+}
+
+// Fetch the selected route's configuration once, then its vehicles and the selected stop's
+// arrivals every `pause`, until the UI hangs up
+fn poll(
+    client: &client::Client,
+    agency: &String,
+    pause: Duration,
+    requests: Receiver<Request>,
+    updates: Sender<Update>,
+) {
+    let mut route: Option<String> = None;
+    let mut stop: Option<String> = None;
+    let mut epoch = 0;
+    let mut next_poll = Instant::now();
+
+    loop {
+        let wait = next_poll.saturating_duration_since(Instant::now());
+        let fetched = match requests.recv_timeout(wait) {
+            Ok(Request::Route(tag)) => {
+                epoch = 0;
+                stop = None;
+                next_poll = Instant::now();
+                let config = client
+                    .block_on(stops::get_stops_for_routes_async(
+                        client,
+                        agency,
+                        slice::from_ref(&tag),
+                    ))
+                    .map(|mut c| c.pop())
+                    .map(|c| {
+                        if let Some(c) = c {
+                            let _ = updates.send(Update::Config(c));
+                        }
+                    });
+                route = Some(tag);
+                config
+            }
+            Ok(Request::Stop(tag)) => {
+                stop = Some(tag);
+                fetch_arrivals(client, agency, &route, &stop, &updates)
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                next_poll = Instant::now() + pause;
+                fetch_vehicles(client, agency, &route, &mut epoch, &updates)
+                    .and_then(|_| fetch_arrivals(client, agency, &route, &stop, &updates))
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        if let Err(e) = fetched {
+            let _ = updates.send(Update::Error(e.display_chain().to_string()));
+        }
+    }
+}
+
+fn fetch_vehicles(
+    client: &client::Client,
+    agency: &String,
+    route: &Option<String>,
+    epoch: &mut u64,
+    updates: &Sender<Update>,
+) -> Result<()> {
+    let route = match *route {
+        Some(ref r) => r,
+        None => return Ok(()),
+    };
+    let locations =
+        client.block_on(location::get_locations_async(client, agency, route, *epoch))?;
+    if let Some((vehicles, updated_time)) = locations {
+        *epoch = updated_time;
+        let _ = updates.send(Update::Vehicles {
+            route_tag: route.clone(),
+            vehicles,
+            epoch: updated_time,
+        });
+    }
+    Ok(())
+}
+
+fn fetch_arrivals(
+    client: &client::Client,
+    agency: &String,
+    route: &Option<String>,
+    stop: &Option<String>,
+    updates: &Sender<Update>,
+) -> Result<()> {
+    let (route, stop) = match (route, stop) {
+        (Some(r), Some(s)) => (r, s),
+        _ => return Ok(()),
+    };
+    let predictions = client.block_on(prediction::get_predictions_async(
+        client,
+        agency,
+        route,
+        slice::from_ref(stop),
+    ))?;
+    let _ = updates.send(Update::Arrivals {
+        stop_tag: stop.clone(),
+        predictions,
+    });
+    Ok(())
+}
+
+impl App {
+    fn new(agency: String, routes: Vec<Route>) -> App {
+        App {
+            agency,
+            routes,
+            route_cursor: 0,
+            route: None,
+            config: None,
+            stops: Vec::new(),
+            stop_cursor: 0,
+            stop: None,
+            vehicles: BTreeMap::new(),
+            arrivals: None,
+            focus: Pane::Routes,
+            status: String::from("Select a route"),
+        }
+    }
+
+    fn event_loop(&mut self, requests: &Sender<Request>, updates: &Receiver<Update>) -> Result<()> {
+        let mut out = io::stdout();
+        loop {
+            while let Ok(update) = updates.try_recv() {
+                self.apply(update);
+            }
+            let (width, height) = terminal::size()?;
+            self.draw(&mut out, width, height)?;
+
+            if !event::poll(FRAME_INTERVAL)? {
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Release && !self.handle_key(key, requests) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Returns false once the user has asked to quit
+    fn handle_key(&mut self, key: KeyEvent, requests: &Sender<Request>) -> bool {
+        let page = 10;
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Tab | KeyCode::BackTab | KeyCode::Left | KeyCode::Right => {
+                self.focus = match self.focus {
+                    Pane::Routes if !self.stops.is_empty() => Pane::Stops,
+                    _ => Pane::Routes,
+                };
+            }
+            KeyCode::Up | KeyCode::Char('k') => self.move_cursor(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_cursor(1),
+            KeyCode::PageUp => self.move_cursor(-page),
+            KeyCode::PageDown => self.move_cursor(page),
+            KeyCode::Enter => match self.focus {
+                Pane::Routes => self.select_route(requests),
+                Pane::Stops => self.select_stop(requests),
+            },
+            _ => (),
+        }
+        true
+    }
+
+    fn move_cursor(&mut self, delta: isize) {
+        let (cursor, len) = match self.focus {
+            Pane::Routes => (&mut self.route_cursor, self.routes.len()),
+            Pane::Stops => (&mut self.stop_cursor, self.stops.len()),
+        };
+        if len == 0 {
+            return;
+        }
+        *cursor = (*cursor as isize + delta).clamp(0, len as isize - 1) as usize;
+    }
+
+    fn select_route(&mut self, requests: &Sender<Request>) {
+        let route = match self.routes.get(self.route_cursor) {
+            Some(r) => r,
+            None => return,
+        };
+        self.route = Some(route.tag.clone());
+        self.config = None;
+        self.stops.clear();
+        self.stop_cursor = 0;
+        self.stop = None;
+        self.vehicles.clear();
+        self.arrivals = None;
+        self.status = format!("Loading route {}", route.title);
+        let _ = requests.send(Request::Route(route.tag.clone()));
+    }
+
+    fn select_stop(&mut self, requests: &Sender<Request>) {
+        let tag = match self.stops.get(self.stop_cursor) {
+            Some(entry) => entry.stop.tag.clone(),
+            None => return,
+        };
+        self.stop = Some(tag.clone());
+        self.arrivals = None;
+        let _ = requests.send(Request::Stop(tag));
+    }
+
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Config(config) => {
+                if self.route.as_ref() != Some(&config.tag) {
+                    return;
+                }
+                // some agencies don't flag any directions for display, e.g. unitrans
+                let flagged = config.directions.iter().any(|d| d.use_for_ui);
+                self.stops = config
+                    .directions
+                    .iter()
+                    .filter(|d| d.use_for_ui || !flagged)
+                    .flat_map(|d| {
+                        d.stops.iter().map(move |s| StopEntry {
+                            direction: d.name.clone(),
+                            stop: s.clone(),
+                        })
+                    })
+                    .collect();
+                self.status = format!("Route {}", config.title);
+                self.config = Some(config);
+            }
+            Update::Vehicles {
+                route_tag,
+                vehicles,
+                epoch,
+            } => {
+                if self.route.as_ref() != Some(&route_tag) {
+                    return;
+                }
+                // each response only has the vehicles that reported since the last one, so
+                // the others are kept until they have been quiet for too long
+                for v in vehicles {
+                    self.vehicles.insert(v.id.clone(), v);
+                }
+                self.vehicles
+                    .retain(|_, v| epoch.saturating_sub(v.epoch) <= REMOVED_AFTER_MS);
+            }
+            Update::Arrivals {
+                stop_tag,
+                predictions,
+            } => {
+                if self.stop.as_ref() == Some(&stop_tag) {
+                    self.arrivals =
+                        predictions.map(|p| board::render(p, Styler { enabled: false }));
+                }
+            }
+            Update::Error(e) => self.status = format!("Error: {}", e.lines().next().unwrap_or("")),
+        }
+    }
+
+    fn draw<W: Write>(&self, out: &mut W, width: u16, height: u16) -> Result<()> {
+        self.render(width as usize, height as usize, time::now_epoch())
+            .flush(out)
+    }
+
+    // The frame to draw, with vehicles' ages as of `now`
+    fn render(&self, width: usize, height: usize, now: u64) -> Screen {
+        let mut screen = Screen::new(width, height);
+        if width < ROUTES_WIDTH as usize + 20 || height < 10 {
+            screen.text(0, 0, width, "Terminal too small");
+            return screen;
+        }
+
+        screen.text(
+            0,
+            0,
+            width,
+            &format!("nextbus {} | {}", self.agency, self.status),
+        );
+        screen.text(
+            0,
+            height - 1,
+            width,
+            "q quit  tab switch pane  up/down move  enter select",
+        );
+
+        let body = height - 2;
+        let routes_width = ROUTES_WIDTH as usize;
+        let middle_width = (width - routes_width) / 2;
+        let right_width = width - routes_width - middle_width;
+        let vehicles_height = body * 2 / 5;
+        let stops_height = body / 2;
+
+        let routes = Rect::new(0, 1, routes_width, body);
+        let vehicles = Rect::new(routes_width, 1, middle_width, vehicles_height);
+        let map = Rect::new(
+            routes_width,
+            1 + vehicles_height,
+            middle_width,
+            body - vehicles_height,
+        );
+        let stops = Rect::new(routes_width + middle_width, 1, right_width, stops_height);
+        let arrivals = Rect::new(
+            routes_width + middle_width,
+            1 + stops_height,
+            right_width,
+            body - stops_height,
+        );
+
+        self.draw_routes(&mut screen, routes);
+        self.draw_vehicles(&mut screen, vehicles, now);
+        self.draw_map(&mut screen, map);
+        self.draw_stops(&mut screen, stops);
+        self.draw_arrivals(&mut screen, arrivals);
+        screen
+    }
+
+    fn draw_routes(&self, screen: &mut Screen, rect: Rect) {
+        let inner = screen.frame(rect, "Routes", self.focus == Pane::Routes);
+        let lines: Vec<String> = self
+            .routes
+            .iter()
+            .map(|r| {
+                let current = if self.route.as_ref() == Some(&r.tag) {
+                    "*"
+                } else {
+                    " "
+                };
+                format!("{}{}", current, r.title)
+            })
+            .collect();
+        screen.list(inner, &lines, self.route_cursor, self.focus == Pane::Routes);
+    }
+
+    fn draw_vehicles(&self, screen: &mut Screen, rect: Rect, now: u64) {
+        let inner = screen.frame(rect, "Vehicles", false);
+        if self.route.is_none() {
+            return;
+        }
+        let mut lines = vec![format!(
+            "{:<6} {:<14} {:>4} {:>4} {:>6}",
+            "ID", "DIRECTION", "KM/H", "HDG", "AGE"
+        )];
+        let mut vehicles: Vec<&VehicleTime> = self.vehicles.values().collect();
+        vehicles.sort_by(|a, b| a.dir_tag.cmp(&b.dir_tag).then_with(|| a.id.cmp(&b.id)));
+        lines.extend(vehicles.into_iter().map(|v| {
+            format!(
+                "{:<6} {:<14} {:>4} {:>4} {:>6}",
+                v.id,
+                self.direction_name(&v.dir_tag),
+                v.speed_km_hr,
+                v.heading,
+                staleness(now.saturating_sub(v.epoch)),
+            )
+        }));
+        screen.lines(inner, &lines);
+    }
+
+    // Stops and vehicles plotted within the route's bounding box, with vehicles drawn as
+    // arrows pointing in their heading and the selected stop as @
+    fn draw_map(&self, screen: &mut Screen, rect: Rect) {
+        let inner = screen.frame(rect, "Map", false);
+        let config = match self.config {
+            Some(ref c) => c,
+            None => return,
+        };
+        let project = |lat: f64, lon: f64| -> Option<(usize, usize)> {
+            let lat_span = config.lat_max.0 - config.lat_min.0;
+            let lon_span = config.lon_max.0 - config.lon_min.0;
+            if inner.width == 0 || inner.height == 0 || lat_span <= 0.0 || lon_span <= 0.0 {
+                return None;
+            }
+            let x = (lon - config.lon_min.0) / lon_span;
+            let y = (config.lat_max.0 - lat) / lat_span;
+            if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+                return None;
+            }
+            Some((
+                inner.x + (x * (inner.width - 1) as f64).round() as usize,
+                inner.y + (y * (inner.height - 1) as f64).round() as usize,
+            ))
+        };
+
+        for entry in &self.stops {
+            if let Some((x, y)) = project(entry.stop.lat.0, entry.stop.lon.0) {
+                screen.put(x, y, '.');
+            }
+        }
+        for v in self.vehicles.values() {
+            if let Some((x, y)) = project(v.lat.0, v.lon.0) {
+                screen.put(x, y, heading_arrow(v.heading));
+            }
+        }
+        let selected = self
+            .stops
+            .iter()
+            .find(|e| self.stop.as_ref() == Some(&e.stop.tag));
+        if let Some(entry) = selected {
+            if let Some((x, y)) = project(entry.stop.lat.0, entry.stop.lon.0) {
+                screen.put(x, y, '@');
+            }
+        }
+    }
+
+    fn draw_stops(&self, screen: &mut Screen, rect: Rect) {
+        let inner = screen.frame(rect, "Stops", self.focus == Pane::Stops);
+        let lines: Vec<String> = self
+            .stops
+            .iter()
+            .map(|e| format!("{:<8} {}", e.direction, e.stop.title))
+            .collect();
+        screen.list(inner, &lines, self.stop_cursor, self.focus == Pane::Stops);
+    }
+
+    fn draw_arrivals(&self, screen: &mut Screen, rect: Rect) {
+        let inner = screen.frame(rect, "Arrivals", false);
+        let text = match (&self.stop, &self.arrivals) {
+            (None, _) => String::from("Select a stop"),
+            (Some(_), None) => String::from("No predictions"),
+            (Some(_), Some(board)) => board.clone(),
+        };
+        let lines: Vec<String> = text.lines().map(String::from).collect();
+        screen.lines(inner, &lines);
+    }
+
+    fn direction_name(&self, dir_tag: &str) -> String {
+        self.config
+            .as_ref()
+            .and_then(|c| c.directions.iter().find(|d| d.tag == dir_tag))
+            .map(|d| d.name.clone())
+            .unwrap_or_else(|| String::from(dir_tag))
+    }
+}
+
+// An arrow for a heading in degrees clockwise from north, or o if the heading is unknown
+fn heading_arrow(heading: i16) -> char {
+    if heading < 0 {
+        return 'o';
+    }
+    match ((heading as i32 + 45) % 360) / 90 {
+        0 => '^',
+        1 => '>',
+        2 => 'v',
+        _ => '<',
+    }
+}
+
+// Time since a vehicle last reported, e.g. 45s or 3m05s
+fn staleness(ms: u64) -> String {
+    let seconds = ms / 1000;
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        _ => format!("{}m{:02}s", seconds / 60, seconds % 60),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Rect {
+    fn new(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+// A frame of characters drawn off screen, then written out in one go to avoid flicker
+struct Screen {
+    width: usize,
+    cells: Vec<Vec<char>>,
+}
+
+impl Screen {
+    fn new(width: usize, height: usize) -> Screen {
+        Screen {
+            width,
+            cells: vec![vec![' '; width]; height],
+        }
+    }
+
+    fn put(&mut self, x: usize, y: usize, c: char) {
+        if let Some(cell) = self.cells.get_mut(y).and_then(|row| row.get_mut(x)) {
+            *cell = c;
+        }
+    }
+
+    // Write text starting at x, cut off after `width` characters
+    fn text(&mut self, x: usize, y: usize, width: usize, text: &str) {
+        for (i, c) in text.chars().take(width).enumerate() {
+            self.put(x + i, y, c);
+        }
+    }
+
+    // Draw a border around `rect` with its title, and return the area inside it
+    fn frame(&mut self, rect: Rect, title: &str, focused: bool) -> Rect {
+        if rect.width < 2 || rect.height < 2 {
+            return Rect::new(rect.x, rect.y, 0, 0);
+        }
+        let (right, bottom) = (rect.x + rect.width - 1, rect.y + rect.height - 1);
+        for x in rect.x..=right {
+            self.put(x, rect.y, '-');
+            self.put(x, bottom, '-');
+        }
+        for y in rect.y..=bottom {
+            self.put(rect.x, y, '|');
+            self.put(right, y, '|');
+        }
+        for &(x, y) in &[
+            (rect.x, rect.y),
+            (right, rect.y),
+            (rect.x, bottom),
+            (right, bottom),
+        ] {
+            self.put(x, y, '+');
+        }
+        let title = match focused {
+            true => format!("[{}]", title),
+            false => format!(" {} ", title),
+        };
+        self.text(rect.x + 1, rect.y, rect.width - 2, &title);
+        Rect::new(rect.x + 1, rect.y + 1, rect.width - 2, rect.height - 2)
+    }
+
+    fn lines(&mut self, rect: Rect, lines: &[String]) {
+        for (i, line) in lines.iter().take(rect.height).enumerate() {
+            self.text(rect.x, rect.y + i, rect.width, line);
+        }
+    }
+
+    // Lines scrolled so that the line at `cursor` is visible, marked with > if `focused`
+    fn list(&mut self, rect: Rect, lines: &[String], cursor: usize, focused: bool) {
+        if rect.height == 0 {
+            return;
+        }
+        let offset = (cursor + 1).saturating_sub(rect.height);
+        for (i, line) in lines.iter().skip(offset).take(rect.height).enumerate() {
+            let marker = if focused && offset + i == cursor {
+                '>'
+            } else {
+                ' '
+            };
+            self.put(rect.x, rect.y + i, marker);
+            self.text(rect.x + 1, rect.y + i, rect.width.saturating_sub(1), line);
+        }
+    }
+
+    fn flush<W: Write>(&self, out: &mut W) -> Result<()> {
+        for (y, row) in self.cells.iter().enumerate() {
+            let line: String = row.iter().take(self.width).collect();
+            queue!(out, MoveTo(0, y as u16), Print(line))?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::ClientConfig;
+    use geo::Coordinate;
+    use recording::Recording;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    fn app() -> App {
+        let route = |tag: &str, title: &str| Route {
+            tag: String::from(tag),
+            title: String::from(title),
+        };
+        App::new(
+            String::from("unitrans"),
+            vec![
+                route("A", "A Line"),
+                route("B", "B Line"),
+                route("C", "C Line"),
+            ],
+        )
+    }
+
+    fn stop(tag: &str) -> Stop {
+        Stop {
+            tag: String::from(tag),
+            title: format!("Stop {}", tag),
+            lat: Coordinate(38.54),
+            lon: Coordinate(-121.75),
+            stop_id: None,
+        }
+    }
+
+    fn config(use_for_ui: bool) -> FlatRoute {
+        let direction = |tag: &str, name: &str, stops: Vec<Stop>| stops::FlatDirection {
+            tag: String::from(tag),
+            title: String::new(),
+            name: String::from(name),
+            use_for_ui,
+            stops,
+        };
+        FlatRoute {
+            tag: String::from("A"),
+            title: String::from("A Line"),
+            lat_min: Coordinate(38.53),
+            lat_max: Coordinate(38.56),
+            lon_min: Coordinate(-121.77),
+            lon_max: Coordinate(-121.73),
+            directions: vec![
+                direction("A_out", "Outbound", vec![stop("1"), stop("2")]),
+                direction("A_in", "Inbound", vec![stop("3")]),
+            ],
+            paths: Vec::new(),
+        }
+    }
+
+    fn vehicle(id: &str, epoch: u64) -> VehicleTime {
+        VehicleTime {
+            id: String::from(id),
+            route_tag: String::from("A"),
+            dir_tag: String::from("A_out"),
+            lat: Coordinate(38.54),
+            lon: Coordinate(-121.75),
+            epoch,
+            predictable: true,
+            heading: 90,
+            speed_km_hr: 20,
+            leading_vehicle_id: String::new(),
+            removed: false,
+            progress: None,
+            heading_source: None,
+            speed_source: None,
+        }
+    }
+
+    fn vehicles(route_tag: &str, vehicles: Vec<VehicleTime>, epoch: u64) -> Update {
+        Update::Vehicles {
+            route_tag: String::from(route_tag),
+            vehicles,
+            epoch,
+        }
+    }
+
+    #[test]
+    fn points_arrows_in_the_heading() {
+        assert_eq!(heading_arrow(0), '^');
+        assert_eq!(heading_arrow(44), '^');
+        assert_eq!(heading_arrow(45), '>');
+        assert_eq!(heading_arrow(90), '>');
+        assert_eq!(heading_arrow(180), 'v');
+        assert_eq!(heading_arrow(270), '<');
+        assert_eq!(heading_arrow(314), '<');
+        assert_eq!(heading_arrow(315), '^');
+        assert_eq!(heading_arrow(359), '^');
+        assert_eq!(heading_arrow(-1), 'o');
+    }
+
+    #[test]
+    fn shows_how_long_ago_a_vehicle_reported() {
+        assert_eq!(staleness(0), "0s");
+        assert_eq!(staleness(45_900), "45s");
+        assert_eq!(staleness(60_000), "1m00s");
+        assert_eq!(staleness(185_000), "3m05s");
+        assert_eq!(staleness(3_600_000), "60m00s");
+    }
+
+    #[test]
+    fn keeps_the_cursor_within_the_focused_list() {
+        let mut app = app();
+        app.move_cursor(1);
+        assert_eq!(app.route_cursor, 1);
+        app.move_cursor(10);
+        assert_eq!(app.route_cursor, 2);
+        app.move_cursor(-10);
+        assert_eq!(app.route_cursor, 0);
+
+        // the stops list is empty until a route's config arrives
+        app.focus = Pane::Stops;
+        app.move_cursor(1);
+        assert_eq!(app.stop_cursor, 0);
+        app.route = Some(String::from("A"));
+        app.apply(Update::Config(config(true)));
+        app.move_cursor(1);
+        assert_eq!((app.route_cursor, app.stop_cursor), (0, 1));
+    }
+
+    #[test]
+    fn lists_stops_of_the_directions_for_display() {
+        let mut app = app();
+        app.route = Some(String::from("A"));
+        let mut route = config(true);
+        route.directions[1].use_for_ui = false;
+        app.apply(Update::Config(route));
+        let tags: Vec<&str> = app.stops.iter().map(|e| &e.stop.tag[..]).collect();
+        assert_eq!(tags, vec!["1", "2"]);
+        assert_eq!(app.status, "Route A Line");
+
+        // or of all of them, when none are flagged
+        app.apply(Update::Config(config(false)));
+        let stops: Vec<(&str, &str)> = app
+            .stops
+            .iter()
+            .map(|e| (&e.direction[..], &e.stop.tag[..]))
+            .collect();
+        assert_eq!(
+            stops,
+            vec![("Outbound", "1"), ("Outbound", "2"), ("Inbound", "3")]
+        );
+
+        // a config for a route that is no longer selected is ignored
+        let mut other = config(true);
+        other.tag = String::from("B");
+        other.title = String::from("B Line");
+        app.apply(Update::Config(other));
+        assert_eq!(app.config.as_ref().unwrap().tag, "A");
+    }
+
+    #[test]
+    fn removes_vehicles_that_stopped_reporting() {
+        let mut app = app();
+        app.route = Some(String::from("A"));
+        let start = 1792414800000;
+        app.apply(vehicles(
+            "A",
+            vec![vehicle("1", start), vehicle("2", start)],
+            start,
+        ));
+        assert_eq!(app.vehicles.len(), 2);
+
+        // only vehicle 2 keeps reporting
+        app.apply(vehicles(
+            "A",
+            vec![vehicle("2", start + 240_000)],
+            start + 240_000,
+        ));
+        assert_eq!(app.vehicles.len(), 2);
+        app.apply(vehicles(
+            "A",
+            vec![vehicle("2", start + 360_000)],
+            start + 360_000,
+        ));
+        let ids: Vec<&String> = app.vehicles.keys().collect();
+        assert_eq!(ids, vec!["2"]);
+        assert_eq!(app.vehicles["2"].epoch, start + 360_000);
+
+        // vehicles of another route are ignored
+        app.apply(vehicles("B", vec![vehicle("3", start)], start + 360_000));
+        assert_eq!(app.vehicles.len(), 1);
+    }
+
+    #[test]
+    fn shows_arrivals_and_errors() {
+        let mut app = app();
+        app.stop = Some(String::from("1"));
+        app.apply(Update::Arrivals {
+            stop_tag: String::from("2"),
+            predictions: Some(PredictionsList {
+                predictions: Vec::new(),
+            }),
+        });
+        assert!(app.arrivals.is_none());
+        app.apply(Update::Arrivals {
+            stop_tag: String::from("1"),
+            predictions: Some(PredictionsList {
+                predictions: Vec::new(),
+            }),
+        });
+        assert!(app.arrivals.is_some());
+
+        app.apply(Update::Error(String::from("timed out\nCaused by: ...")));
+        assert_eq!(app.status, "Error: timed out");
+    }
+
+    // Draws the N with its vehicles and the arrivals at 28th Ave, fetched by the poller from
+    // the recorded responses, and compares the frame with tests/golden/tui.txt. Run with
+    // UPDATE_GOLDEN=1 to rewrite it after an intended change.
+    #[test]
+    fn draws_a_route_from_recorded_responses() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let client = client::Client::new(ClientConfig {
+            recording: Some(Recording::Replay(root.join("fixtures/recorded/responses"))),
+            ..ClientConfig::default()
+        })
+        .unwrap();
+        let agency = String::from("sf-muni");
+        let routes = routes::_get_routes(&client, &agency).unwrap().routes;
+        let mut app = App::new(agency.clone(), routes);
+        let (requests, _) = mpsc::channel();
+        let (updates, received) = mpsc::channel();
+
+        app.route_cursor = app.routes.iter().position(|r| r.tag == "N").unwrap();
+        app.select_route(&requests);
+        let config = client
+            .block_on(stops::get_stops_for_routes_async(
+                &client,
+                &agency,
+                &[String::from("N")],
+            ))
+            .unwrap();
+        app.apply(Update::Config(config.into_iter().next().unwrap()));
+        let mut epoch = 0;
+        fetch_vehicles(&client, &agency, &app.route, &mut epoch, &updates).unwrap();
+        app.focus = Pane::Stops;
+        app.stop_cursor = app.stops.iter().position(|e| e.stop.tag == "5186").unwrap();
+        app.select_stop(&requests);
+        fetch_arrivals(&client, &agency, &app.route, &app.stop, &updates).unwrap();
+        while let Ok(update) = received.try_recv() {
+            app.apply(update);
+        }
+
+        let screen = app.render(120, 30, epoch + 30_000);
+        let frame: String = screen
+            .cells
+            .iter()
+            .map(|row| row.iter().collect::<String>().trim_end().to_owned() + "\n")
+            .collect();
+        let path = root.join("tests/golden/tui.txt");
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &frame).unwrap();
+            return;
+        }
+        let expected = fs::read_to_string(&path).unwrap();
+        assert!(
+            frame == expected,
+            "drew:\n{}\nexpected ({}):\n{}",
+            frame,
+            path.display(),
+            expected
+        );
+    }
+}
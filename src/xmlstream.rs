@@ -0,0 +1,132 @@
+use serde::de::value::{Error as ValueError, MapDeserializer, StringDeserializer};
+use serde::de::{self, DeserializeOwned, Deserializer, IntoDeserializer, Visitor};
+use std::io::Read;
+use std::result::Result as StdResult;
+use xml::reader::{EventReader, Events, XmlEvent};
+
+use errors::*;
+
+// A start element and its attributes, without any of its children
+#[derive(Debug)]
+pub struct Element {
+    pub name: String,
+    // depth of the element in the document, where the root element has depth 1
+    pub depth: usize,
+    pub attributes: Vec<(String, String)>,
+}
+
+impl Element {
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|&(k, _)| k == name)
+            .map(|(_, v)| &v[..])
+    }
+
+    // Deserialize the element's attributes into a struct. Every attribute value is a string,
+    // so numeric and boolean fields must use `client::from_string`, as they do for
+    // serde_xml_rs.
+    pub fn deserialize<T: DeserializeOwned>(self) -> Result<T> {
+        let name = self.name;
+        let attributes = MapDeserializer::<_, ValueError>::new(
+            self.attributes
+                .into_iter()
+                .map(|(k, v)| (k, AttributeValue(v))),
+        );
+        T::deserialize(attributes)
+            .chain_err(|| format!("Deserialization failed for element '{}'.", name))
+    }
+}
+
+// An attribute's value, which deserializes as a string, or as `Some` of one for optional
+// fields, as serde_xml_rs does for attributes that are there
+pub struct AttributeValue(pub String);
+
+impl<'de> IntoDeserializer<'de, ValueError> for AttributeValue {
+    type Deserializer = AttributeValue;
+
+    fn into_deserializer(self) -> AttributeValue {
+        self
+    }
+}
+
+impl<'de> Deserializer<'de> for AttributeValue {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> StdResult<V::Value, ValueError> {
+        let value: StringDeserializer<ValueError> = self.0.into_deserializer();
+        value.deserialize_any(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> StdResult<V::Value, ValueError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> StdResult<V::Value, ValueError> {
+        let value: StringDeserializer<ValueError> = self.0.into_deserializer();
+        value.deserialize_enum(name, variants, visitor)
+    }
+
+    // serde_xml_rs takes any attribute that isn't empty as true, even "false"
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> StdResult<V::Value, ValueError> {
+        visitor.visit_bool(!self.0.is_empty())
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> StdResult<V::Value, ValueError> {
+        visitor.visit_u8(self.0.parse().map_err(de::Error::custom)?)
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
+
+// Iterate over the start elements of an XML document as they are parsed, so that large
+// responses never have to be held in memory all at once
+pub struct Elements<R: Read> {
+    events: Events<R>,
+    depth: usize,
+}
+
+impl<R: Read> Elements<R> {
+    pub fn new(source: R) -> Elements<R> {
+        Elements {
+            events: EventReader::new(source).into_iter(),
+            depth: 0,
+        }
+    }
+}
+
+impl<R: Read> Iterator for Elements<R> {
+    type Item = Result<Element>;
+
+    fn next(&mut self) -> Option<Result<Element>> {
+        loop {
+            match self.events.next()? {
+                Ok(XmlEvent::StartElement {
+                    name, attributes, ..
+                }) => {
+                    self.depth += 1;
+                    return Some(Ok(Element {
+                        name: name.local_name,
+                        depth: self.depth,
+                        attributes: attributes
+                            .into_iter()
+                            .map(|a| (a.name.local_name, a.value))
+                            .collect(),
+                    }));
+                }
+                Ok(XmlEvent::EndElement { .. }) => self.depth -= 1,
+                Ok(_) => (),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+// Runs nextbus against fake-feed serving the recorded responses, with its simulated vehicles
+// and with the failures it can inject.
+
+extern crate serde_json;
+
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+fn manifest_dir() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+// A running fake-feed, which is stopped when dropped
+struct FakeFeed {
+    child: Child,
+    url: String,
+}
+
+impl FakeFeed {
+    fn start(args: &[&str]) -> FakeFeed {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let child = Command::new(env!("CARGO_BIN_EXE_fake-feed"))
+            .arg("fixtures/recorded/responses")
+            .arg("--port")
+            .arg(port.to_string())
+            .args(args)
+            .current_dir(manifest_dir())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "fake-feed didn't start"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+        FakeFeed {
+            child,
+            url: format!("http://127.0.0.1:{}/service/publicXMLFeed", port),
+        }
+    }
+
+    fn nextbus(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_nextbus"))
+            .arg("--feed-url")
+            .arg(&self.url)
+            .args(args)
+            .env("RUST_LOG", "warn")
+            .output()
+            .unwrap()
+    }
+}
+
+impl Drop for FakeFeed {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// The simulated vehicles drive along the route's path, which bends away from the line
+// between the N's last two stops
+#[test]
+fn vehicles_drive_along_the_route_path() {
+    let feed = FakeFeed::start(&["--vehicles", "10"]);
+    let output = feed.nextbus(&["locations", "sf-muni", "N", "--match-routes"]);
+    assert!(output.status.success());
+
+    let vehicles: Vec<Value> = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(vehicles.len(), 20);
+    for vehicle in &vehicles {
+        let off_path = vehicle["distance_from_route_meters"].as_f64().unwrap();
+        assert!(off_path < 1.0, "{} is off the path", vehicle);
+        assert!(vehicle["next_stop_tag"].is_string() || vehicle["previous_stop_tag"].is_string());
+    }
+}
+
+// Failed requests are warned about, and don't stop nextbus from printing the ones that
+// succeed. The seed fails the same requests every run.
+#[test]
+fn survives_injected_faults() {
+    let feed = FakeFeed::start(&[
+        "--feed-error-percent",
+        "30",
+        "--server-error-percent",
+        "30",
+        "--seed",
+        "7",
+    ]);
+    let (mut answered, mut failed, mut unavailable) = (0, 0, 0);
+    for _ in 0..10 {
+        let output = feed.nextbus(&["predictions", "sf-muni", "N", "--", "5186"]);
+        assert!(output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match output.stdout.is_empty() {
+            true => {
+                assert!(stderr.contains("Download error"), "{}", stderr);
+                failed += 1;
+            }
+            false => {
+                let predictions: Value = serde_json::from_slice(&output.stdout).unwrap();
+                assert_eq!(predictions["predictions"][0]["stopTag"], "5186");
+                answered += 1;
+            }
+        }
+        if stderr.contains("503 Service Unavailable") {
+            unavailable += 1;
+        }
+    }
+    assert!(answered > 0 && failed > 0, "{} answered", answered);
+    assert!(unavailable > 0 && unavailable < failed);
+}
@@ -0,0 +1,408 @@
+// Runs each subcommand against the feed's responses recorded in fixtures/recorded/responses,
+// replayed with --replay-dir, and compares what it prints with the golden output in
+// tests/golden. Run with UPDATE_GOLDEN=1 to rewrite the golden output after an intended change.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output};
+
+fn manifest_dir() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+// nextbus with the arguments, replaying the recorded responses
+fn nextbus(args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_nextbus"));
+    command
+        .arg("--replay-dir")
+        .arg("fixtures/recorded/responses")
+        .args(args)
+        .current_dir(manifest_dir())
+        .env_remove("RUST_LOG");
+    command
+}
+
+// Run nextbus with the arguments, and check that it succeeds
+fn run(name: &str, args: &[&str]) -> Output {
+    // a cache of its own, so stop indexes are built from the recorded route configs
+    let cache: PathBuf = env::temp_dir().join(format!("nextbus-golden-{}-{}", process::id(), name));
+    let output = nextbus(args)
+        .env("XDG_CACHE_HOME", &cache)
+        .output()
+        .unwrap();
+    let _ = fs::remove_dir_all(&cache);
+    assert!(
+        output.status.success(),
+        "nextbus {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+// Run nextbus with the arguments, and check its output against tests/golden/<name>
+fn golden(name: &str, args: &[&str]) {
+    golden_masked(name, args, &[]);
+}
+
+// Like `golden`, with the numbers in the given JSON fields replaced, for fields that depend on
+// when nextbus is run
+fn golden_masked(name: &str, args: &[&str], masked: &[&str]) {
+    let output = run(name, args);
+    let mut actual = String::from_utf8(output.stdout).unwrap();
+    for field in masked {
+        actual = mask(&actual, field);
+    }
+    assert!(!actual.is_empty(), "nextbus {:?} printed nothing", args);
+
+    let path = manifest_dir().join("tests/golden").join(name);
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{}: {} (run with UPDATE_GOLDEN=1)", path.display(), e));
+    assert!(
+        actual == expected,
+        "nextbus {:?} printed:\n{}\nexpected ({}):\n{}",
+        args,
+        actual,
+        path.display(),
+        expected
+    );
+}
+
+// Replace the number after each "<field>": with 0
+fn mask(json: &str, field: &str) -> String {
+    let key = format!("\"{}\":", field);
+    let mut masked = String::new();
+    let mut rest = json;
+    while let Some(i) = rest.find(&key) {
+        let value = &rest[i + key.len()..];
+        let end = value
+            .find(|c: char| !c.is_ascii_digit() && c != '.' && c != '-')
+            .unwrap_or(value.len());
+        masked.push_str(&rest[..i + key.len()]);
+        masked.push('0');
+        rest = &value[end..];
+    }
+    masked.push_str(rest);
+    masked
+}
+
+#[test]
+fn routes() {
+    golden("routes.json", &["routes", "sf-muni"]);
+    golden("routes-ttc.json", &["routes", "ttc"]);
+    golden("routes-unitrans.json", &["routes", "unitrans"]);
+}
+
+#[test]
+fn stops() {
+    golden("stops-sf-muni.json", &["stops", "sf-muni", "N"]);
+    golden("stops-ttc.json", &["stops", "ttc", "504"]);
+    golden("stops-unitrans.json", &["stops", "unitrans", "A"]);
+    golden("stops-stream.json", &["stops", "--stream", "ttc", "504"]);
+    // a route without a config is null
+    golden("stops-missing.json", &["stops", "sf-muni", "J", "X"]);
+    golden("stops-all.json", &["stops", "sf-muni", "--all"]);
+}
+
+#[test]
+fn stops_search() {
+    golden("stops-search.json", &["stops", "search", "sf-muni", "28th"]);
+}
+
+#[test]
+fn nearby() {
+    golden(
+        "nearby.json",
+        &["nearby", "sf-muni", "37.7612,-122.4856", "--radius", "1000"],
+    );
+    golden(
+        "nearby-predictions.json",
+        &[
+            "nearby",
+            "sf-muni",
+            "37.7612,-122.4856",
+            "--radius",
+            "1000",
+            "--predictions",
+        ],
+    );
+}
+
+#[test]
+fn locations() {
+    golden("locations-sf-muni.json", &["locations", "sf-muni", "N"]);
+    golden(
+        "locations-stream.json",
+        &["locations", "--stream", "sf-muni", "N"],
+    );
+    golden(
+        "locations-ttc.json",
+        &["locations", "ttc", "504", "--match-routes"],
+    );
+    // a vehicle without a dirTag
+    golden("locations-unitrans.json", &["locations", "unitrans", "A"]);
+}
+
+#[test]
+fn predictions() {
+    golden(
+        "predictions-sf-muni.json",
+        &["predictions", "sf-muni", "N", "--", "5186", "5178"],
+    );
+    golden(
+        "predictions-ttc.json",
+        &["predictions", "ttc", "504", "--", "15637"],
+    );
+    golden(
+        "predictions-ttc.txt",
+        &[
+            "predictions",
+            "ttc",
+            "504",
+            "--format",
+            "table",
+            "--",
+            "15637",
+        ],
+    );
+}
+
+#[test]
+fn schedule() {
+    let day = ["--date", "2026-10-19", "--timezone", "America/Los_Angeles"];
+    let with_day = |args: &[&'static str]| -> Vec<&'static str> {
+        args.iter().chain(day.iter()).cloned().collect()
+    };
+    golden("schedule.json", &with_day(&["schedule", "sf-muni", "N"]));
+    golden(
+        "schedule.csv",
+        &with_day(&["schedule", "sf-muni", "N", "--format", "csv"]),
+    );
+    golden(
+        "schedule.txt",
+        &with_day(&["schedule", "sf-muni", "N", "--format", "table"]),
+    );
+    golden(
+        "schedule-departures.json",
+        &with_day(&["schedule", "sf-muni", "N", "--view", "departures"]),
+    );
+    golden(
+        "schedule-departures.txt",
+        &with_day(&[
+            "schedule",
+            "sf-muni",
+            "N",
+            "--view",
+            "departures",
+            "--format",
+            "table",
+        ]),
+    );
+    golden(
+        "schedule-routes.json",
+        &with_day(&["schedule", "sf-muni", "N", "J"]),
+    );
+    // service after midnight, and service classes that aren't named by day
+    golden(
+        "schedule-ttc.txt",
+        &[
+            "schedule",
+            "ttc",
+            "504",
+            "--date",
+            "2026-10-19",
+            "--timezone",
+            "America/Toronto",
+            "--format",
+            "table",
+        ],
+    );
+    // stops without titles, in tables without headers
+    golden(
+        "schedule-unitrans.txt",
+        &with_day(&["schedule", "unitrans", "A", "--format", "table"]),
+    );
+    // a route without a schedule is null
+    golden(
+        "schedule-missing.json",
+        &with_day(&["schedule", "sf-muni", "N", "X"]),
+    );
+    golden(
+        "next-departures.json",
+        &with_day(&["next-departures", "sf-muni", "N", "5205", "--at", "06:05"]),
+    );
+}
+
+#[test]
+fn adherence() {
+    golden(
+        "adherence.json",
+        &[
+            "adherence",
+            "sf-muni",
+            "N",
+            "--timezone",
+            "America/Los_Angeles",
+        ],
+    );
+}
+
+// The expected headway is given, since the schedule's depends on the time of day
+#[test]
+fn headways() {
+    golden_masked(
+        "headways.json",
+        &[
+            "headways",
+            "sf-muni",
+            "N",
+            "--scheduled-headway",
+            "600",
+            "--gap-percent",
+            "130",
+        ],
+        &["epoch"],
+    );
+}
+
+#[test]
+fn accuracy_collect() {
+    golden_masked(
+        "accuracy-collect.json",
+        &[
+            "--locations-interval",
+            "0",
+            "accuracy",
+            "collect",
+            "sf-muni",
+            "N",
+            "--polls",
+            "2",
+            "--interval",
+            "5",
+        ],
+        &["prediction_epoch"],
+    );
+}
+
+#[test]
+fn accuracy_report() {
+    golden(
+        "accuracy-report.json",
+        &["accuracy", "report", "fixtures/recorded/accuracy.ndjson"],
+    );
+}
+
+#[test]
+fn replay() {
+    golden(
+        "replay.json",
+        &[
+            "replay",
+            "fixtures/recorded/locations.ndjson",
+            "--speed",
+            "1000000",
+        ],
+    );
+}
+
+// Lenient parsing prints the same as strict parsing for responses that parse
+#[test]
+fn lenient() {
+    golden("routes.json", &["--lenient", "routes", "sf-muni"]);
+    golden(
+        "stops-sf-muni.json",
+        &["--lenient", "stops", "sf-muni", "N"],
+    );
+    golden("stops-ttc.json", &["--lenient", "stops", "ttc", "504"]);
+    golden(
+        "stops-unitrans.json",
+        &["--lenient", "stops", "unitrans", "A"],
+    );
+    golden(
+        "stops-stream.json",
+        &["--lenient", "stops", "--stream", "ttc", "504"],
+    );
+    golden(
+        "locations-sf-muni.json",
+        &["--lenient", "locations", "sf-muni", "N"],
+    );
+    golden(
+        "locations-ttc.json",
+        &["--lenient", "locations", "ttc", "504", "--match-routes"],
+    );
+    golden(
+        "predictions-sf-muni.json",
+        &[
+            "--lenient",
+            "predictions",
+            "sf-muni",
+            "N",
+            "--",
+            "5186",
+            "5178",
+        ],
+    );
+    golden(
+        "predictions-ttc.json",
+        &["--lenient", "predictions", "ttc", "504", "--", "15637"],
+    );
+    golden(
+        "schedule.json",
+        &[
+            "--lenient",
+            "schedule",
+            "sf-muni",
+            "N",
+            "--date",
+            "2026-10-19",
+            "--timezone",
+            "America/Los_Angeles",
+        ],
+    );
+}
+
+// A vehicle and predictions that fail to parse are skipped when parsing leniently, and
+// summarized on stderr, and lose the whole response otherwise
+#[test]
+fn schema_drift() {
+    golden(
+        "locations-actransit.json",
+        &["--lenient", "locations", "actransit", "51B"],
+    );
+    // what was skipped and ignored is summarized without RUST_LOG
+    let lenient = run(
+        "locations-actransit-drift",
+        &["--lenient", "locations", "actransit", "51B"],
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&lenient.stderr),
+        "Schema drift: skipped 1 <vehicle> that failed to parse, the last with lat: cannot \
+         parse float from empty string\n\
+         Schema drift: ignored unknown attribute 'occupancy' on <vehicle>\n"
+    );
+    let strict = nextbus(&["locations", "actransit", "51B"])
+        .env("RUST_LOG", "warn")
+        .output()
+        .unwrap();
+    assert!(strict.status.success());
+    assert!(strict.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&strict.stderr).contains("Download error"));
+    golden(
+        "predictions-actransit.json",
+        &[
+            "--lenient",
+            "predictions",
+            "actransit",
+            "51B",
+            "--",
+            "0305520",
+            "0305530",
+        ],
+    );
+}